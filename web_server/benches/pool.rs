@@ -0,0 +1,103 @@
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use web_server::{Scheduler, ThreadPool};
+
+const RUNS: usize = 10;
+const TINY_JOBS: usize = 100_000;
+// 2^17 - 1 jobs.
+const DEPTH: u32 = 16;
+
+fn main() {
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("{workers} workers, best and median of {RUNS} runs\n");
+
+    for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+        let pool = Arc::new(
+            ThreadPool::builder()
+                .workers(workers)
+                .scheduler(scheduler)
+                // A shared queue has to hold every job of the fork/join run at once: a worker
+                // waiting for room to submit a job isn't running any.
+                .queue_capacity(1 << (DEPTH + 1))
+                .build()
+                .unwrap(),
+        );
+
+        report(scheduler, "tiny jobs", TINY_JOBS, || tiny_jobs(&pool));
+        report(scheduler, "fork/join", (1 << (DEPTH + 1)) - 1, || {
+            fork_join(&pool)
+        });
+    }
+}
+
+fn report(scheduler: Scheduler, workload: &str, jobs: usize, mut run: impl FnMut()) {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    let per_job = |time: Duration| time.as_nanos() / jobs as u128;
+    println!(
+        "{:<13} {workload:<10} {:>10.2?} {:>10.2?}   ({} / {} ns per job)",
+        format!("{scheduler:?}"),
+        times[0],
+        times[RUNS / 2],
+        per_job(times[0]),
+        per_job(times[RUNS / 2]),
+    );
+}
+
+// Many jobs that hardly do anything, all submitted from outside the pool. This is mostly the
+// cost of getting a job to a worker.
+fn tiny_jobs(pool: &ThreadPool) {
+    let remaining = Arc::new(AtomicUsize::new(TINY_JOBS));
+    let (done, is_done) = mpsc::channel();
+    for n in 0..TINY_JOBS {
+        let (remaining, done) = (Arc::clone(&remaining), done.clone());
+        pool.execute(move || {
+            black_box(n);
+            if remaining.fetch_sub(1, Ordering::Relaxed) == 1 {
+                done.send(()).unwrap();
+            }
+        });
+    }
+    is_done.recv().unwrap();
+}
+
+// One job that splits into two, which split into two, and so on: the jobs are submitted by
+// the workers themselves.
+fn fork_join(pool: &Arc<ThreadPool>) {
+    let remaining = Arc::new(AtomicUsize::new(1));
+    let (done, is_done) = mpsc::channel();
+    let job_pool = Arc::clone(pool);
+    pool.execute(move || split(job_pool, DEPTH, remaining, done));
+    is_done.recv().unwrap();
+}
+
+fn split(pool: Arc<ThreadPool>, depth: u32, remaining: Arc<AtomicUsize>, done: mpsc::Sender<()>) {
+    if depth > 0 {
+        for _ in 0..2 {
+            remaining.fetch_add(1, Ordering::Relaxed);
+            let (job_pool, remaining, done) =
+                (Arc::clone(&pool), Arc::clone(&remaining), done.clone());
+            pool.execute(move || split(job_pool, depth - 1, remaining, done));
+        }
+    }
+    // Before saying we're done, or the last reference to the pool could end up on a worker,
+    // which would have to wait for itself to finish.
+    drop(pool);
+    if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+        done.send(()).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+#!/bin/sh
+# A tiny CGI script: the request arrives in environment variables and on stdin, and the response
+# goes to stdout as headers, a blank line and the body.
+echo "Content-Type: text/plain"
+echo
+echo "Hello from $SCRIPT_NAME!"
+echo "You sent a $REQUEST_METHOD for ${PATH_INFO:-/} with query ${QUERY_STRING:-(none)}."
+if [ "${CONTENT_LENGTH:-0}" -gt 0 ]; then
+    echo "Your request body was:"
+    head -c "$CONTENT_LENGTH"
+fi
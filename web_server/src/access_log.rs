@@ -0,0 +1,229 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+// Spelled in lower case in config files, e.g. `format = "json"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // host ident authuser [date] "request" status bytes
+    Common,
+    // Common, plus "referer" "user-agent" and (our addition) the latency in microseconds
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+// Everything we know about a request once its response has been written.
+#[derive(Debug)]
+pub struct LogEntry {
+    pub peer_addr: Option<SocketAddr>,
+    pub time: SystemTime,
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub status: u16,
+    pub bytes: usize,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub latency: Duration,
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    // Entries are written whole under the lock so that lines from different threads don't mix.
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat, out: impl Write + Send + 'static) -> AccessLog {
+        AccessLog {
+            format,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog::new(format, io::stdout())
+    }
+
+    pub fn log(&self, entry: &LogEntry) {
+        let line = self.format(entry);
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+            eprintln!("Failed to write access log: {err}");
+        }
+    }
+
+    pub fn format(&self, entry: &LogEntry) -> String {
+        let host = entry
+            .peer_addr
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| String::from("-"));
+        let request_line = format!("{} {} {}", entry.method, entry.target, entry.version);
+
+        match self.format {
+            LogFormat::Common => format!(
+                "{host} - - [{}] \"{}\" {} {}",
+                clf_time(entry.time),
+                escape_quoted(&request_line),
+                entry.status,
+                entry.bytes
+            ),
+            LogFormat::Combined => format!(
+                "{host} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {}",
+                clf_time(entry.time),
+                escape_quoted(&request_line),
+                entry.status,
+                entry.bytes,
+                escape_quoted(entry.referer.as_deref().unwrap_or("-")),
+                escape_quoted(entry.user_agent.as_deref().unwrap_or("-")),
+                entry.latency.as_micros()
+            ),
+            LogFormat::Json => format!(
+                "{{\"time\":\"{}\",\"remote_addr\":\"{}\",\"method\":\"{}\",\"target\":\"{}\",\
+                 \"version\":\"{}\",\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\
+                 \"latency_ms\":{:.3}}}",
+                rfc3339_time(entry.time),
+                escape_json(&host),
+                escape_json(&entry.method),
+                escape_json(&entry.target),
+                escape_json(&entry.version),
+                entry.status,
+                entry.bytes,
+                json_option(entry.referer.as_deref()),
+                json_option(entry.user_agent.as_deref()),
+                entry.latency.as_secs_f64() * 1000.0
+            ),
+        }
+    }
+}
+
+// Quotes and backslashes would otherwise let a client forge extra fields in the line.
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_option(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", escape_json(value)),
+        None => String::from("null"),
+    }
+}
+
+// Splits a time into UTC (year, month, day, hour, minute, second).
+pub(crate) fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        (secs_of_day / 3600) as u32,
+        (secs_of_day / 60 % 60) as u32,
+        (secs_of_day % 60) as u32,
+    )
+}
+
+// 10/Oct/2000:13:55:36 +0000
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+// 2000-10-10T13:55:36Z
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            peer_addr: Some("127.0.0.1:50000".parse().unwrap()),
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: String::from("GET"),
+            target: String::from("/apache_pb.gif"),
+            version: String::from("HTTP/1.0"),
+            status: 200,
+            bytes: 2326,
+            referer: Some(String::from("http://www.example.com/start.html")),
+            user_agent: Some(String::from("Mozilla/4.08 \"quoted\"")),
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn common_log_format() {
+        let log = AccessLog::new(LogFormat::Common, io::sink());
+        assert_eq!(
+            log.format(&entry()),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326"
+        );
+    }
+
+    #[test]
+    fn combined_log_format_escapes_quotes() {
+        let log = AccessLog::new(LogFormat::Combined, io::sink());
+        assert!(log.format(&entry()).ends_with(
+            "\"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\" 1500"
+        ));
+    }
+
+    #[test]
+    fn json_format() {
+        let log = AccessLog::new(LogFormat::Json, io::sink());
+        let line = log.format(&entry());
+        assert!(
+            line.starts_with("{\"time\":\"2000-10-10T13:55:36Z\",\"remote_addr\":\"127.0.0.1\"")
+        );
+        assert!(line.contains("\"user_agent\":\"Mozilla/4.08 \\\"quoted\\\"\""));
+        assert!(line.ends_with("\"latency_ms\":1.500}"));
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    http::{Headers, Request, Response},
+    router::{BoxFuture, Handler},
+    ThreadPool,
+};
+
+// Scripts that print more than this are cut off with a 502 rather than buffered.
+const MAX_OUTPUT: u64 = 16 * 1024 * 1024;
+
+// Runs an executable for every request, the CGI/1.1 way (RFC 3875): the request is described by
+// environment variables, its body is piped to stdin, and the script prints the response to stdout
+// as headers, a blank line and the body.
+//
+// Running a process means blocking on pipes, so the work is handed to a `ThreadPool` instead of
+// tying up the executor. When its workers are all busy and its queue is full, requests are
+// answered with a 503 rather than waiting. Scripts that take longer than the timeout are killed
+// and answered with a 504.
+pub struct Cgi {
+    program: PathBuf,
+    pool: Arc<ThreadPool>,
+    timeout: Duration,
+    script_name: String,
+    env: Vec<(String, String)>,
+}
+
+enum CgiError {
+    Spawn(io::Error),
+    Io(io::Error),
+    Timeout,
+    BadOutput(String),
+}
+
+impl Cgi {
+    pub fn new(program: impl Into<PathBuf>, pool: Arc<ThreadPool>) -> Cgi {
+        Cgi {
+            program: program.into(),
+            pool,
+            timeout: Duration::from_secs(30),
+            script_name: String::new(),
+            env: Vec::new(),
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    // The path the script is mounted at, e.g. `/cgi-bin/report`. Whatever follows it in the
+    // request path is passed on as `PATH_INFO`.
+    pub fn script_name(mut self, script_name: &str) -> Cgi {
+        self.script_name = script_name.trim_end_matches('/').to_string();
+        self
+    }
+
+    // Extra environment variable for the script. It doesn't inherit the server's environment.
+    pub fn env(mut self, name: &str, value: &str) -> Cgi {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn environment(&self, request: &Request) -> Vec<(String, String)> {
+        let path_info = request
+            .path
+            .strip_prefix(self.script_name.as_str())
+            .unwrap_or(&request.path);
+        let host = request.header("Host").unwrap_or("localhost");
+        let (server_name, server_port) = match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => (name, port),
+            _ => (host, if request.secure { "443" } else { "80" }),
+        };
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+            ("SERVER_SOFTWARE", String::from("web_server")),
+            ("SERVER_PROTOCOL", request.version.clone()),
+            ("SERVER_NAME", server_name.to_string()),
+            ("SERVER_PORT", server_port.to_string()),
+            ("REQUEST_METHOD", request.method.to_string()),
+            ("SCRIPT_NAME", self.script_name.clone()),
+            ("PATH_INFO", path_info.to_string()),
+            ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+            ("CONTENT_LENGTH", request.body.len().to_string()),
+        ];
+        if let Some(content_type) = request.header("Content-Type") {
+            env.push(("CONTENT_TYPE", content_type.to_string()));
+        }
+        if let Some(peer_addr) = request.peer_addr {
+            env.push(("REMOTE_ADDR", peer_addr.ip().to_string()));
+            env.push(("REMOTE_PORT", peer_addr.port().to_string()));
+        }
+        if request.secure {
+            env.push(("HTTPS", String::from("on")));
+        }
+
+        let mut env: Vec<(String, String)> = env
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        for (name, value) in request.headers.iter() {
+            // These two already have their own variables. `Proxy` would become `HTTP_PROXY`,
+            // which many HTTP clients in the script would take as their proxy ("httpoxy").
+            if ["Content-Type", "Content-Length", "Proxy"]
+                .iter()
+                .any(|skip| name.eq_ignore_ascii_case(skip))
+            {
+                continue;
+            }
+            let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            env.push((name, value.to_string()));
+        }
+        env.extend(self.env.iter().cloned());
+        env
+    }
+}
+
+impl Handler for Cgi {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        // Only a body we have in full can be passed on.
+        if request.body_reader.is_some() {
+            return Box::pin(async { Response::new(413).with_body("Payload Too Large") });
+        }
+        let mut command = Command::new(&self.program);
+        command
+            .env_clear()
+            .envs(self.environment(&request))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        let (program, timeout) = (self.program.display().to_string(), self.timeout);
+        let pool = Arc::clone(&self.pool);
+
+        Box::pin(async move {
+            // Waiting for room in the pool would block the thread running this future, and the
+            // other tasks on it, so a full pool turns the request away instead.
+            let (sender, receiver) = smol::channel::bounded(1);
+            let job = move || {
+                let _ = sender.try_send(run(command, request.body, timeout));
+            };
+            if let Err(err) = pool.try_execute(job) {
+                eprintln!("Can't run {program}: {err}");
+                return Response::new(503)
+                    .with_header("Retry-After", "1")
+                    .with_body("Service Unavailable");
+            }
+
+            let result = receiver
+                .recv()
+                .await
+                .unwrap_or_else(|_| Err(CgiError::Io(io::ErrorKind::Interrupted.into())));
+            match result.and_then(|output| parse_output(&output)) {
+                Ok(response) => response,
+                Err(CgiError::Spawn(err)) => {
+                    eprintln!("Failed to run {program}: {err}");
+                    Response::new(500).with_body("Internal Server Error")
+                }
+                Err(CgiError::Timeout) => {
+                    eprintln!("{program} timed out after {timeout:?}");
+                    Response::new(504).with_body("Gateway Timeout")
+                }
+                Err(CgiError::Io(err)) => {
+                    eprintln!("{program} failed: {err}");
+                    Response::new(502).with_body("Bad Gateway")
+                }
+                Err(CgiError::BadOutput(message)) => {
+                    eprintln!("{program} printed a bad response: {message}");
+                    Response::new(502).with_body("Bad Gateway")
+                }
+            }
+        })
+    }
+}
+
+// Runs on a pool worker. Writing stdin and reading stdout each get a helper thread so that a
+// script that writes before it has read all of its input can't deadlock with us, and so that
+// the worker is free to kill the script when it runs out of time.
+fn run(mut command: Command, body: Vec<u8>, timeout: Duration) -> Result<Vec<u8>, CgiError> {
+    let mut child = command.spawn().map_err(CgiError::Spawn)?;
+    let (mut stdin, stdout) = (child.stdin.take(), child.stdout.take());
+
+    if !body.is_empty() {
+        if let Some(mut stdin) = stdin.take() {
+            // The script may exit without reading everything, so a broken pipe is fine.
+            thread::spawn(move || stdin.write_all(&body));
+        }
+    }
+    // Closes stdin right away if there was no body.
+    drop(stdin);
+
+    let (sender, receiver) = mpsc::channel();
+    if let Some(stdout) = stdout {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let read = stdout
+                .take(MAX_OUTPUT + 1)
+                .read_to_end(&mut output)
+                .map(|_| output);
+            let _ = sender.send(read);
+        });
+    }
+
+    let result = match receiver.recv_timeout(timeout) {
+        Ok(Ok(output)) if output.len() as u64 > MAX_OUTPUT => {
+            Err(CgiError::BadOutput(format!("more than {MAX_OUTPUT} bytes")))
+        }
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(CgiError::Io(err)),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(CgiError::Timeout),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(CgiError::Io(io::ErrorKind::BrokenPipe.into()))
+        }
+    };
+
+    // Whatever happened, don't leave the process behind: once its output is in, a script that's
+    // still running would keep the worker waiting for it. Killing one that already exited is a
+    // no-op, and waiting reaps it.
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
+
+// Headers up to a blank line, then the body. `Status: 404 Not Found` sets the status; otherwise
+// it's 302 if the script sent a `Location`, and 200 if not.
+fn parse_output(output: &[u8]) -> Result<Response, CgiError> {
+    let (head, body) = match find(output, b"\r\n\r\n") {
+        Some(end) => (&output[..end], &output[end + 4..]),
+        None => match find(output, b"\n\n") {
+            Some(end) => (&output[..end], &output[end + 2..]),
+            None => return Err(CgiError::BadOutput(String::from("no end of headers"))),
+        },
+    };
+    let head = std::str::from_utf8(head)
+        .map_err(|_| CgiError::BadOutput(String::from("headers are not UTF-8")))?;
+
+    let mut headers = Headers::new();
+    let mut status = None;
+    for line in head.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(CgiError::BadOutput(format!("malformed header: {line:?}")));
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split_whitespace().next().unwrap_or("");
+            match code.parse::<u16>() {
+                Ok(code) if (100..600).contains(&code) => status = Some(code),
+                _ => return Err(CgiError::BadOutput(format!("bad status: {value:?}"))),
+            }
+        } else {
+            headers.append(name, value);
+        }
+    }
+    if headers.iter().next().is_none() && status.is_none() {
+        return Err(CgiError::BadOutput(String::from("no headers")));
+    }
+
+    let status = status.unwrap_or(if headers.get("Location").is_some() {
+        302
+    } else {
+        200
+    });
+    // The response is framed by us, whatever the script claims.
+    headers.remove("Content-Length");
+    headers.remove("Transfer-Encoding");
+
+    let mut response = Response::new(status).with_body(body.to_vec());
+    response.headers = headers;
+    Ok(response)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn parse(output: &str) -> Response {
+        match parse_output(output.as_bytes()) {
+            Ok(response) => response,
+            Err(_) => panic!("failed to parse {output:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_status_headers_and_body() {
+        let response = parse("Status: 404 Not Found\nContent-Type: text/plain\n\nnope");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(response.body, b"nope");
+
+        let response = parse("Location: /elsewhere\r\nContent-Length: 99\r\n\r\n");
+        assert_eq!(response.status, 302);
+        assert_eq!(response.headers.get("Content-Length"), None);
+
+        assert!(parse_output(b"just some text").is_err());
+        assert!(parse_output(b"Status: nope\n\n").is_err());
+    }
+
+    #[test]
+    fn describes_the_request_in_the_environment() {
+        let cgi = Cgi::new("/bin/true", Arc::new(ThreadPool::new(1)))
+            .script_name("/cgi-bin/report/")
+            .env("APP_ENV", "test");
+        let mut request = Request::new(Method::Post, "/cgi-bin/report/2024/q1?format=csv");
+        request.headers.insert("Host", "example.com:8080");
+        request.headers.insert("Content-Type", "text/plain");
+        request.headers.insert("X-Custom-Header", "yes");
+        request.headers.insert("Proxy", "http://evil.example");
+        request.body = b"hello".to_vec();
+
+        let env = cgi.environment(&request);
+        let get = |name: &str| {
+            env.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(get("SCRIPT_NAME"), Some("/cgi-bin/report"));
+        assert_eq!(get("PATH_INFO"), Some("/2024/q1"));
+        assert_eq!(get("QUERY_STRING"), Some("format=csv"));
+        assert_eq!(get("SERVER_NAME"), Some("example.com"));
+        assert_eq!(get("SERVER_PORT"), Some("8080"));
+        assert_eq!(get("CONTENT_LENGTH"), Some("5"));
+        assert_eq!(get("CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(get("HTTP_X_CUSTOM_HEADER"), Some("yes"));
+        assert_eq!(get("HTTP_CONTENT_TYPE"), None);
+        assert_eq!(get("HTTP_PROXY"), None);
+        assert_eq!(get("APP_ENV"), Some("test"));
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::{self, Write};
+
+use flate2::{write::GzEncoder, Compression as GzLevel};
+
+use crate::{
+    http::{Request, Response},
+    router::{BoxFuture, Handler},
+};
+
+// Bodies smaller than this are sent as-is: the headers would eat most of the savings.
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    // File extension used for precompressed siblings, e.g. `app.js.br`.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Identity => None,
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    // Quality 5 is a reasonable trade-off for compressing on the fly.
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(output)
+            }
+            Encoding::Gzip => {
+                let mut writer = GzEncoder::new(Vec::new(), GzLevel::default());
+                writer.write_all(data)?;
+                writer.finish()
+            }
+            Encoding::Identity => Ok(data.to_vec()),
+        }
+    }
+}
+
+// Picks the best of `available` (listed in our order of preference) for an `Accept-Encoding`
+// header. Falls back to identity when the client accepts none of them.
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Encoding {
+    let Some(accept_encoding) = accept_encoding else {
+        return Encoding::Identity;
+    };
+
+    // (coding, q-value) pairs, e.g. "gzip;q=0.5, br" => [("gzip", 0.5), ("br", 1.0)]
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, quality))
+        })
+        .collect();
+
+    let quality_of = |encoding: Encoding| {
+        let find = |coding: &str| accepted.iter().find(|(c, _)| c == coding).map(|(_, q)| *q);
+        find(encoding.token()).or_else(|| find("*")).unwrap_or(0.0)
+    };
+
+    let mut best = (Encoding::Identity, 0.0);
+    for &encoding in available {
+        let quality = quality_of(encoding);
+        // Strictly greater, so that ties go to the encoding we prefer.
+        if quality > best.1 {
+            best = (encoding, quality);
+        }
+    }
+    best.0
+}
+
+// Only text-like content is worth compressing; images and archives already are.
+pub fn is_compressible(content_type: Option<&str>) -> bool {
+    let Some(content_type) = content_type else {
+        return false;
+    };
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+// Wraps a handler and compresses its responses according to the request's `Accept-Encoding`.
+pub struct Compress<H> {
+    inner: H,
+    threshold: usize,
+}
+
+impl<H: Handler> Compress<H> {
+    pub fn new(inner: H) -> Compress<H> {
+        Compress {
+            inner,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    pub fn threshold(mut self, bytes: usize) -> Compress<H> {
+        self.threshold = bytes;
+        self
+    }
+}
+
+impl<H: Handler> Handler for Compress<H> {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let encoding = negotiate(
+            request.header("Accept-Encoding"),
+            &[Encoding::Brotli, Encoding::Gzip],
+        );
+        let threshold = self.threshold;
+        let response = self.inner.call(request);
+
+        Box::pin(async move { compress_response(response.await, encoding, threshold) })
+    }
+}
+
+fn compress_response(mut response: Response, encoding: Encoding, threshold: usize) -> Response {
+    // Handlers such as the static file one may already have picked an encoding. Streamed bodies
+    // have to go out as they are produced, so there's nothing to compress all at once.
+    if response.headers.get("Content-Encoding").is_some()
+        || response.stream.is_some()
+        || !is_compressible(response.headers.get("Content-Type"))
+    {
+        return response;
+    }
+
+    // Whether we compress or not, the representation depends on Accept-Encoding.
+    response.add_vary("Accept-Encoding");
+    if encoding == Encoding::Identity || response.body.len() < threshold {
+        return response;
+    }
+
+    match encoding.encode(&response.body) {
+        Ok(body) => {
+            response.body = body;
+            response
+                .headers
+                .insert("Content-Encoding", encoding.token());
+        }
+        Err(err) => eprintln!("Failed to {} encode response: {err}", encoding.token()),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use std::io::Read;
+
+    const BOTH: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    #[test]
+    fn negotiation_honours_quality_values() {
+        assert_eq!(negotiate(None, &BOTH), Encoding::Identity);
+        assert_eq!(
+            negotiate(Some("gzip, deflate, br"), &BOTH),
+            Encoding::Brotli
+        );
+        assert_eq!(negotiate(Some("br;q=0.5, gzip"), &BOTH), Encoding::Gzip);
+        assert_eq!(negotiate(Some("br;q=0, *"), &BOTH), Encoding::Gzip);
+        assert_eq!(negotiate(Some("deflate"), &BOTH), Encoding::Identity);
+        assert_eq!(negotiate(Some("*;q=0"), &BOTH), Encoding::Identity);
+    }
+
+    fn call(accept_encoding: &str, body: &str) -> Response {
+        let body = body.to_string();
+        let handler = Compress::new(move |_| {
+            let body = body.clone();
+            async move { Response::html(200, body) }
+        })
+        .threshold(16);
+
+        let mut request = Request::new(Method::Get, "/");
+        request.headers.insert("Accept-Encoding", accept_encoding);
+        smol::block_on(handler.call(request))
+    }
+
+    #[test]
+    fn compresses_large_bodies_only() {
+        let large = "<p>hello</p>".repeat(100);
+
+        let response = call("gzip", &large);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, large);
+
+        let response = call("gzip", "tiny");
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, b"tiny");
+    }
+
+    #[test]
+    fn brotli_round_trips() {
+        let large = "<p>hello</p>".repeat(100);
+
+        let response = call("br", &large);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&response.body[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, large);
+    }
+}
@@ -0,0 +1,981 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    access_log::{AccessLog, LogFormat},
+    cgi::Cgi,
+    http::{Method, Request, Response},
+    limits::{ConnectionLimit, RateLimit},
+    proxy::Proxy,
+    router::Handler,
+    static_files::StaticFiles,
+    template::{Context, Template, Templates},
+    tls::Certificates,
+    PoolCreationError, Router, Server, ThreadPool, VirtualHosts,
+};
+
+// Everything about the server that doesn't need code, loaded from a TOML file:
+//
+//     not_found = "404.html"
+//
+//     [server]
+//     bind = ["127.0.0.1:7878"]
+//     workers = 4
+//
+//     [[route]]
+//     path = "/"
+//     file = "hello.html"
+//
+//     [[host]]
+//     names = ["example.com", "www.example.com"]
+//     root = "sites/example"
+//
+// Every key is optional. Relative paths are resolved against the working directory, just like the
+// hard-coded file names used to be.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // HTML page served with a 404 for requests that no route matched.
+    pub not_found: Option<PathBuf>,
+    // Name of the `[[host]]` that serves requests for names no host lists. The top-level routes
+    // serve them if this isn't set.
+    pub default_host: Option<String>,
+    pub server: ServerConfig,
+    pub timeouts: TimeoutConfig,
+    pub tls: Option<TlsConfig>,
+    pub log: LogConfig,
+    pub limits: LimitConfig,
+    pub templates: TemplateConfig,
+    pub cgi: CgiConfig,
+    #[serde(rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    #[serde(rename = "host")]
+    pub hosts: Vec<HostConfig>,
+}
+
+// How the server listens. Unlike the rest of the config, this can't change without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: Vec<SocketAddr>,
+    // Threads in the pool, or executor threads for the async backend.
+    pub workers: usize,
+    pub backend: Backend,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            workers: 4,
+            backend: Backend::Threaded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Threaded,
+    Async,
+}
+
+// In seconds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub read: u64,
+    pub write: u64,
+    // How long a new connection may wait for a free worker before it gets a 503. Only for the
+    // threaded backend; 0 doesn't wait at all. Waits as long as it takes if left out.
+    pub queue: Option<u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> TimeoutConfig {
+        TimeoutConfig {
+            read: 30,
+            write: 30,
+            queue: None,
+        }
+    }
+}
+
+// Everything is unlimited unless set. A reload starts counting afresh.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    // Requests per second that each client IP may make on average...
+    pub rate: Option<f64>,
+    // ...and at once. Defaults to `rate`.
+    pub burst: Option<u32>,
+    // Open connections from each client IP.
+    pub connections_per_ip: Option<usize>,
+    // Open connections in total.
+    pub connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Plain HTTP address that redirects everything to the first `server.bind` address.
+    pub redirect_from: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Whether to write an access log at all.
+    pub access: bool,
+    pub format: LogFormat,
+    // Appended to if set, otherwise the log goes to stdout.
+    pub path: Option<PathBuf>,
+    // Route to serve Prometheus metrics on, if any.
+    pub metrics: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            access: true,
+            format: LogFormat::Combined,
+            path: None,
+            metrics: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateConfig {
+    // Template names are relative to this.
+    pub dir: PathBuf,
+    // Notice changes to templates instead of reading each one only once.
+    pub dev: bool,
+    // Rendered with a 404 for requests that no route matched. Takes the place of `not_found`.
+    pub not_found: Option<String>,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> TemplateConfig {
+        TemplateConfig {
+            dir: PathBuf::from("templates"),
+            dev: false,
+            not_found: None,
+        }
+    }
+}
+
+// CGI scripts get a thread pool of their own, so that slow scripts can't starve the server's
+// workers. It's only started if some route uses `cgi`; the `[[host]]` routes share a second one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgiConfig {
+    // Scripts that can run at once.
+    pub workers: usize,
+    // Scripts that can wait for a worker. Requests beyond that get a 503.
+    pub queue: usize,
+    // In seconds. Scripts still running after this are killed.
+    pub timeout: u64,
+}
+
+impl Default for CgiConfig {
+    fn default() -> CgiConfig {
+        CgiConfig {
+            workers: 4,
+            queue: 64,
+            timeout: 30,
+        }
+    }
+}
+
+// A route serves exactly one of `file`, `template`, `dir`, `proxy`, `cgi` or `redirect`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    // May end in `/*` to match everything below it, see `Router`.
+    pub path: String,
+    // Defaults to GET, to GET and POST for `cgi`, or to every method for `proxy`. GET routes
+    // answer HEAD too.
+    pub method: Option<String>,
+    // Defaults to 200 for `file` and `template`, and 301 for `redirect`.
+    pub status: Option<u16>,
+    // An HTML page.
+    pub file: Option<PathBuf>,
+    // A template, rendered with the request's details (see `Context::for_request`).
+    pub template: Option<String>,
+    // The files below this directory, with the path's `/*` prefix stripped.
+    pub dir: Option<PathBuf>,
+    // Backend addresses to forward requests to.
+    pub proxy: Option<Vec<String>>,
+    // Executable to run for each request. Whatever follows the path's `/*` prefix is passed on
+    // as `PATH_INFO`.
+    pub cgi: Option<PathBuf>,
+    // URL to redirect to.
+    pub redirect: Option<String>,
+}
+
+// A site of its own on the same listeners, served for requests to any of `names` (see
+// `VirtualHosts`). Only the top-level routes are extended in code.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    // May start with `*.` to match every subdomain.
+    pub names: Vec<String>,
+    // Files served for requests that none of the host's routes matched.
+    pub root: Option<PathBuf>,
+    // HTML page served with a 404 for requests that nothing else matched.
+    pub not_found: Option<PathBuf>,
+    // Certificate for clients that ask for one of `names`, in place of `tls.cert` and `tls.key`.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    // Not TOML, or a key is unknown or has the wrong type. The message points at the line.
+    Parse(toml::de::Error),
+    // Well-formed, but some settings don't make sense. Every problem found is listed.
+    Invalid(Vec<String>),
+    Tls(io::Error),
+    // The pool for CGI scripts couldn't be started.
+    Cgi(PoolCreationError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+            ConfigError::Parse(err) => write!(f, "{err}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+            ConfigError::Tls(err) => write!(f, "tls: {err}"),
+            ConfigError::Cgi(err) => write!(f, "cgi: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?
+            .parse()
+    }
+
+    // Whether going from `self` to `new` changes anything that only takes effect on restart.
+    pub fn needs_restart(&self, new: &Config) -> bool {
+        let redirect_from = |config: &Config| config.tls.as_ref().and_then(|tls| tls.redirect_from);
+        self.server != new.server || redirect_from(self) != redirect_from(new)
+    }
+
+    // Checks everything that can be checked without starting the server, so that a bad reload is
+    // rejected up front instead of replacing a working server.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.server.bind.is_empty() {
+            problems.push(String::from("server.bind: needs at least one address"));
+        }
+        if self.server.workers == 0 {
+            problems.push(String::from("server.workers: must be at least 1"));
+        }
+        if self.timeouts.read == 0 {
+            problems.push(String::from("timeouts.read: must be at least 1 second"));
+        }
+        if self.timeouts.write == 0 {
+            problems.push(String::from("timeouts.write: must be at least 1 second"));
+        }
+        match (self.limits.rate, self.limits.burst) {
+            (Some(rate), _) if !(rate > 0.0 && rate.is_finite()) => {
+                problems.push(format!("limits.rate: {rate} must be a positive number"));
+            }
+            (None, Some(_)) => problems.push(String::from("limits.burst: needs limits.rate")),
+            (_, Some(0)) => problems.push(String::from("limits.burst: must be at least 1")),
+            _ => {}
+        }
+        if self.limits.connections_per_ip == Some(0) {
+            problems.push(String::from(
+                "limits.connections_per_ip: must be at least 1",
+            ));
+        }
+        if self.limits.connections == Some(0) {
+            problems.push(String::from("limits.connections: must be at least 1"));
+        }
+        if let Some(page) = &self.not_found {
+            check_file("not_found", page, &mut problems);
+        }
+        if let Some(name) = &self.templates.not_found {
+            if self.not_found.is_some() {
+                problems.push(String::from(
+                    "templates.not_found: can't be used together with not_found",
+                ));
+            }
+            check_template("templates.not_found", &self.templates, name, &mut problems);
+        }
+        if let Some(tls) = &self.tls {
+            check_file("tls.cert", &tls.cert, &mut problems);
+            check_file("tls.key", &tls.key, &mut problems);
+        }
+        if self.cgi.workers == 0 {
+            problems.push(String::from("cgi.workers: must be at least 1"));
+        }
+        if self.cgi.timeout == 0 {
+            problems.push(String::from("cgi.timeout: must be at least 1 second"));
+        }
+        if let Some(path) = &self.log.metrics {
+            if !path.starts_with('/') {
+                problems.push(format!("log.metrics: {path:?} must start with '/'"));
+            }
+        }
+
+        for (ind, route) in self.routes.iter().enumerate() {
+            let name = format!("route[{ind}] ({})", route.path);
+            route.validate(&name, &self.templates, &mut problems);
+        }
+
+        let mut names = Vec::new();
+        for (ind, host) in self.hosts.iter().enumerate() {
+            let name = format!("host[{ind}]");
+            host.validate(&name, self, &mut problems);
+            for host_name in &host.names {
+                let host_name = host_name.to_ascii_lowercase();
+                if names.contains(&host_name) {
+                    problems.push(format!("{name}.names: {host_name:?} is listed twice"));
+                }
+                names.push(host_name);
+            }
+        }
+        if let Some(host_name) = &self.default_host {
+            if !names.contains(&host_name.to_ascii_lowercase()) {
+                problems.push(format!("default_host: no [[host]] lists {host_name:?}"));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    // A router for the configured routes and 404 page. More routes can be added to it in code.
+    pub fn router(&self) -> Result<Router, ConfigError> {
+        let (templates, cgi_pool) = self.resources(&self.routes)?;
+        let mut router = self.routes(&self.routes, &templates, cgi_pool.as_ref());
+
+        if let Some(page) = &self.not_found {
+            let page = page.clone();
+            router = router.fallback(move |_| {
+                let page = page.clone();
+                async move { Response::html_file(404, page) }
+            });
+        }
+        if let Some(name) = &self.templates.not_found {
+            let name = name.clone();
+            router = router.fallback(move |request: Request| {
+                let response = templates.response(404, &name, &Context::for_request(&request));
+                async move { response }
+            });
+        }
+        Ok(router)
+    }
+
+    // Puts the configured `[[host]]` sites in front of `handler`, which serves every other name
+    // unless `default_host` picks one of the hosts instead. With no hosts configured, everything
+    // goes to `handler`.
+    pub fn virtual_hosts(&self, handler: impl Handler) -> Result<VirtualHosts, ConfigError> {
+        let (templates, cgi_pool) =
+            self.resources(self.hosts.iter().flat_map(|host| &host.routes))?;
+
+        let mut hosts = VirtualHosts::new();
+        let mut default = None;
+        for host in &self.hosts {
+            let mut router = self.routes(&host.routes, &templates, cgi_pool.as_ref());
+            let page = host.not_found.clone();
+            if let Some(root) = &host.root {
+                let files = StaticFiles::new(root);
+                router = router.fallback(move |request| {
+                    let (response, page) = (files.call(request), page.clone());
+                    async move {
+                        match (response.await, page) {
+                            (response, Some(page)) if response.status == 404 => {
+                                Response::html_file(404, page)
+                            }
+                            (response, _) => response,
+                        }
+                    }
+                });
+            } else if let Some(page) = page {
+                router = router.fallback(move |_| {
+                    let page = page.clone();
+                    async move { Response::html_file(404, page) }
+                });
+            }
+
+            let site: Arc<dyn Handler> = Arc::new(router);
+            let is_default = |name: &String| {
+                self.default_host
+                    .as_ref()
+                    .is_some_and(|default| default.eq_ignore_ascii_case(name))
+            };
+            if host.names.iter().any(is_default) {
+                default = Some(Arc::clone(&site));
+            }
+            let names: Vec<&str> = host.names.iter().map(String::as_str).collect();
+            hosts = hosts.host(&names, move |request| site.call(request));
+        }
+
+        Ok(match default {
+            Some(site) => hosts.default_host(move |request| site.call(request)),
+            None => hosts.default_host(handler),
+        })
+    }
+
+    // What the handlers for `routes` share: the templates and, if any of them runs a CGI
+    // script, a pool to run the scripts on.
+    fn resources<'a>(
+        &self,
+        routes: impl IntoIterator<Item = &'a RouteConfig>,
+    ) -> Result<(Arc<Templates>, Option<Arc<ThreadPool>>), ConfigError> {
+        let templates = Arc::new(Templates::new(&self.templates.dir).dev_mode(self.templates.dev));
+        let mut cgi_pool = None;
+        if routes.into_iter().any(|route| route.cgi.is_some()) {
+            let pool = ThreadPool::builder()
+                .workers(self.cgi.workers)
+                .queue_capacity(self.cgi.queue)
+                .build()
+                .map_err(ConfigError::Cgi)?;
+            cgi_pool = Some(Arc::new(pool));
+        }
+        Ok((templates, cgi_pool))
+    }
+
+    fn routes(
+        &self,
+        routes: &[RouteConfig],
+        templates: &Arc<Templates>,
+        cgi_pool: Option<&Arc<ThreadPool>>,
+    ) -> Router {
+        let mut router = Router::new();
+        for route in routes {
+            let handler = route.handler(templates, cgi_pool, &self.cgi);
+            for method in route.methods() {
+                let handler = Arc::clone(&handler);
+                router = router.route(method, &route.path, move |request| handler.call(request));
+            }
+        }
+        router
+    }
+
+    // A server for `handler` with the configured timeouts, limits, TLS and access log.
+    pub fn server(&self, handler: impl Handler) -> Result<Server, ConfigError> {
+        let mut server = Server::new(handler).with_timeouts(
+            Duration::from_secs(self.timeouts.read),
+            Duration::from_secs(self.timeouts.write),
+        );
+        if let Some(queue) = self.timeouts.queue {
+            server = server.with_queue_timeout(Duration::from_secs(queue));
+        }
+
+        let limits = &self.limits;
+        if let Some(rate) = limits.rate {
+            let burst = limits.burst.unwrap_or(rate.ceil() as u32);
+            server = server.with_middleware(RateLimit::new(rate, burst));
+        }
+        if limits.connections.is_some() || limits.connections_per_ip.is_some() {
+            server = server.with_connection_limit(ConnectionLimit::new(
+                limits.connections.unwrap_or(usize::MAX),
+                limits.connections_per_ip.unwrap_or(usize::MAX),
+            ));
+        }
+
+        if let Some(config) = &self.tls {
+            let mut certificates =
+                Certificates::load(&config.cert, &config.key).map_err(ConfigError::Tls)?;
+            for host in &self.hosts {
+                if let (Some(cert), Some(key)) = (&host.cert, &host.key) {
+                    let names: Vec<&str> = host.names.iter().map(String::as_str).collect();
+                    certificates = certificates
+                        .with_host(&names, cert, key)
+                        .map_err(ConfigError::Tls)?;
+                }
+            }
+            server = server.with_tls(certificates.acceptor().map_err(ConfigError::Tls)?);
+        }
+
+        if self.log.access {
+            let access_log = match &self.log.path {
+                Some(path) => {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|err| ConfigError::Io(path.clone(), err))?;
+                    AccessLog::new(self.log.format, file)
+                }
+                None => AccessLog::stdout(self.log.format),
+            };
+            server = server.with_access_log(access_log);
+        }
+
+        Ok(server)
+    }
+}
+
+impl RouteConfig {
+    fn validate(&self, name: &str, templates: &TemplateConfig, problems: &mut Vec<String>) {
+        if !self.path.starts_with('/') {
+            problems.push(format!("{name}: path must start with '/'"));
+        }
+
+        let kinds = [
+            self.file.is_some(),
+            self.template.is_some(),
+            self.dir.is_some(),
+            self.proxy.is_some(),
+            self.cgi.is_some(),
+            self.redirect.is_some(),
+        ];
+        if kinds.iter().filter(|&&kind| kind).count() != 1 {
+            problems.push(format!(
+                "{name}: needs exactly one of `file`, `template`, `dir`, `proxy`, `cgi` or `redirect`"
+            ));
+            return;
+        }
+
+        if let Some(file) = &self.file {
+            check_file(&format!("{name}.file"), file, problems);
+        }
+        if let Some(template) = &self.template {
+            check_template(&format!("{name}.template"), templates, template, problems);
+        }
+        if let Some(dir) = &self.dir {
+            if !dir.is_dir() {
+                problems.push(format!("{name}.dir: {} is not a directory", dir.display()));
+            }
+        }
+        if let Some(backends) = &self.proxy {
+            if backends.is_empty() {
+                problems.push(format!("{name}.proxy: needs at least one backend"));
+            }
+            for backend in backends {
+                let port = backend
+                    .rsplit_once(':')
+                    .map(|(_, port)| port.parse::<u16>());
+                if !matches!(port, Some(Ok(_))) {
+                    problems.push(format!(
+                        "{name}.proxy: {backend:?} is not a host:port address"
+                    ));
+                }
+            }
+        }
+
+        if let Some(program) = &self.cgi {
+            check_file(&format!("{name}.cgi"), program, problems);
+        }
+
+        match (self.status, self.redirect.is_some()) {
+            (Some(status), true) if !(300..400).contains(&status) => {
+                problems.push(format!("{name}.status: {status} is not a redirect status"));
+            }
+            (Some(status), _) if !(100..600).contains(&status) => {
+                problems.push(format!("{name}.status: {status} is not an HTTP status"));
+            }
+            _ => {}
+        }
+        if self.status.is_some()
+            && (self.dir.is_some() || self.proxy.is_some() || self.cgi.is_some())
+        {
+            problems.push(format!(
+                "{name}.status: only applies to `file`, `template` and `redirect`"
+            ));
+        }
+    }
+
+    fn methods(&self) -> Vec<Method> {
+        match &self.method {
+            Some(method) => vec![Method::parse(&method.to_ascii_uppercase())],
+            None if self.proxy.is_some() => vec![
+                Method::Get,
+                Method::Head,
+                Method::Post,
+                Method::Put,
+                Method::Delete,
+                Method::Patch,
+                Method::Options,
+            ],
+            None if self.cgi.is_some() => vec![Method::Get, Method::Post],
+            None => vec![Method::Get],
+        }
+    }
+
+    // Shared by every method the route is registered for. `validate` made sure exactly one kind
+    // is set.
+    fn handler(
+        &self,
+        templates: &Arc<Templates>,
+        cgi_pool: Option<&Arc<ThreadPool>>,
+        cgi: &CgiConfig,
+    ) -> Arc<dyn Handler> {
+        if let Some(file) = &self.file {
+            let (file, status) = (file.clone(), self.status.unwrap_or(200));
+            Arc::new(move |_| {
+                let file = file.clone();
+                async move { Response::html_file(status, file) }
+            })
+        } else if let Some(name) = &self.template {
+            let (templates, name) = (Arc::clone(templates), name.clone());
+            let status = self.status.unwrap_or(200);
+            Arc::new(move |request: Request| {
+                let response = templates.response(status, &name, &Context::for_request(&request));
+                async move { response }
+            })
+        } else if let Some(dir) = &self.dir {
+            let prefix = self.path.strip_suffix("/*").unwrap_or(&self.path);
+            Arc::new(StaticFiles::new(dir).strip_prefix(prefix))
+        } else if let Some(backends) = &self.proxy {
+            let backends: Vec<&str> = backends.iter().map(String::as_str).collect();
+            Arc::new(Proxy::new(&backends))
+        } else if let (Some(program), Some(pool)) = (&self.cgi, cgi_pool) {
+            let script_name = self.path.strip_suffix("/*").unwrap_or(&self.path);
+            Arc::new(
+                Cgi::new(program, Arc::clone(pool))
+                    .timeout(Duration::from_secs(cgi.timeout))
+                    .script_name(script_name),
+            )
+        } else {
+            let (location, status) = (self.redirect.clone().unwrap_or_default(), self.status);
+            Arc::new(move |_| {
+                let response =
+                    Response::new(status.unwrap_or(301)).with_header("Location", location.as_str());
+                async move { response }
+            })
+        }
+    }
+}
+
+impl HostConfig {
+    fn validate(&self, name: &str, config: &Config, problems: &mut Vec<String>) {
+        if self.names.is_empty() {
+            problems.push(format!("{name}.names: needs at least one name"));
+        }
+        for host_name in &self.names {
+            let wildcard = host_name.strip_prefix("*.");
+            let valid = !host_name.is_empty()
+                && !wildcard.is_some_and(str::is_empty)
+                && wildcard
+                    .unwrap_or(host_name)
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            if !valid {
+                problems.push(format!("{name}.names: {host_name:?} is not a host name"));
+            }
+        }
+
+        if let Some(root) = &self.root {
+            if !root.is_dir() {
+                problems.push(format!(
+                    "{name}.root: {} is not a directory",
+                    root.display()
+                ));
+            }
+        }
+        if let Some(page) = &self.not_found {
+            check_file(&format!("{name}.not_found"), page, problems);
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                if config.tls.is_none() {
+                    problems.push(format!("{name}.cert: needs [tls]"));
+                }
+                check_file(&format!("{name}.cert"), cert, problems);
+                check_file(&format!("{name}.key"), key, problems);
+            }
+            (Some(_), None) => problems.push(format!("{name}.cert: needs {name}.key")),
+            (None, Some(_)) => problems.push(format!("{name}.key: needs {name}.cert")),
+            (None, None) => {}
+        }
+
+        for (ind, route) in self.routes.iter().enumerate() {
+            let name = format!("{name}.route[{ind}] ({})", route.path);
+            route.validate(&name, &config.templates, problems);
+        }
+    }
+}
+
+// Parsing it catches syntax errors before a reload swaps in a broken template.
+fn check_template(name: &str, config: &TemplateConfig, template: &str, problems: &mut Vec<String>) {
+    let path = config.dir.join(template);
+    match fs::read_to_string(&path) {
+        Ok(source) => {
+            if let Err(err) = Template::parse(template, &source) {
+                problems.push(format!("{name}: {err}"));
+            }
+        }
+        Err(err) => problems.push(format!("{name}: {}: {err}", path.display())),
+    }
+}
+
+fn check_file(name: &str, path: &Path, problems: &mut Vec<String>) {
+    if !path.is_file() {
+        problems.push(format!("{name}: {} is not a file", path.display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.server.bind, ["127.0.0.1:7878".parse().unwrap()]);
+        assert_eq!(config.server.workers, 4);
+        assert!(config.log.access);
+    }
+
+    #[test]
+    fn parses_every_section() {
+        let config: Config = r#"
+            not_found = "404.html"
+
+            [server]
+            bind = ["0.0.0.0:8080", "[::1]:8080"]
+            workers = 8
+            backend = "async"
+
+            [timeouts]
+            read = 5
+            queue = 0
+
+            [log]
+            format = "json"
+            metrics = "/metrics"
+
+            [limits]
+            rate = 2.5
+            connections_per_ip = 8
+
+            [[route]]
+            path = "/"
+            file = "hello.html"
+
+            [[route]]
+            path = "/api/*"
+            proxy = ["127.0.0.1:9000", "127.0.0.1:9001"]
+
+            [[route]]
+            path = "/old"
+            redirect = "/"
+            status = 308
+
+            [cgi]
+            timeout = 5
+            queue = 16
+
+            [[route]]
+            path = "/cgi-bin/hello"
+            cgi = "cgi-bin/hello.sh"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.server.bind.len(), 2);
+        assert_eq!(config.server.backend, Backend::Async);
+        assert_eq!(config.timeouts.read, 5);
+        assert_eq!(config.timeouts.write, 30);
+        assert_eq!(config.timeouts.queue, Some(0));
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.limits.rate, Some(2.5));
+        assert_eq!(config.limits.connections, None);
+        assert_eq!(config.routes.len(), 4);
+        assert_eq!(config.routes[1].methods().len(), 7);
+        assert_eq!(config.routes[2].methods(), [Method::Get]);
+        assert_eq!(config.routes[3].methods(), [Method::Get, Method::Post]);
+        assert_eq!(config.cgi.timeout, 5);
+        assert_eq!(config.cgi.workers, 4);
+        assert_eq!(config.cgi.queue, 16);
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_line() {
+        let err = "[server]\nworkers = \"four\"\n"
+            .parse::<Config>()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2"), "{err}");
+
+        let err = "[server]\nworker = 4\n"
+            .parse::<Config>()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `worker`"), "{err}");
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let err = r#"
+            [server]
+            workers = 0
+
+            [limits]
+            burst = 10
+
+            [[route]]
+            path = "/"
+            file = "missing.html"
+
+            [[route]]
+            path = "nowhere"
+            file = "hello.html"
+            redirect = "/"
+
+            [[route]]
+            path = "/broken"
+            template = "missing.html"
+        "#
+        .parse::<Config>()
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(
+            err,
+            "invalid configuration:\n  \
+             - server.workers: must be at least 1\n  \
+             - limits.burst: needs limits.rate\n  \
+             - route[0] (/).file: missing.html is not a file\n  \
+             - route[1] (nowhere): path must start with '/'\n  \
+             - route[1] (nowhere): needs exactly one of `file`, `template`, `dir`, `proxy`, `cgi` or `redirect`\n  \
+             - route[2] (/broken).template: templates/missing.html: No such file or directory (os error 2)"
+        );
+    }
+
+    #[test]
+    fn hosts_get_sites_of_their_own() {
+        let config: Config = r#"
+            default_host = "docs.example.com"
+
+            [[host]]
+            names = ["example.com", "*.example.com"]
+
+            [[host.route]]
+            path = "/"
+            redirect = "https://example.org/"
+
+            [[host]]
+            names = ["docs.example.com"]
+            root = "templates"
+            not_found = "hello.html"
+        "#
+        .parse()
+        .unwrap();
+        let hosts = config
+            .virtual_hosts(|_| async { Response::new(200) })
+            .unwrap();
+
+        let get = |host: &str, path: &str| {
+            let mut request = Request::new(Method::Get, path);
+            request.headers.insert("Host", host);
+            smol::block_on(hosts.call(request))
+        };
+        assert_eq!(get("www.example.com", "/").status, 301);
+        assert_eq!(get("example.com", "/head.html").status, 404);
+        let response = get("docs.example.com", "/head.html");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, fs::read("templates/head.html").unwrap());
+        // Unknown names go to `default_host`, where missing files get its 404 page.
+        let response = get("example.net", "/missing");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, fs::read("hello.html").unwrap());
+    }
+
+    #[test]
+    fn hosts_are_validated() {
+        let err = r#"
+            default_host = "nowhere.com"
+
+            [[host]]
+            names = ["example.com", "bad name"]
+            cert = "hello.html"
+
+            [[host]]
+            names = ["Example.com"]
+            root = "missing"
+
+            [[host.route]]
+            path = "/"
+        "#
+        .parse::<Config>()
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(
+            err,
+            "invalid configuration:\n  \
+             - host[0].names: \"bad name\" is not a host name\n  \
+             - host[0].cert: needs host[0].key\n  \
+             - host[1].root: missing is not a directory\n  \
+             - host[1].route[0] (/): needs exactly one of `file`, `template`, `dir`, `proxy`, `cgi` or `redirect`\n  \
+             - host[1].names: \"example.com\" is listed twice\n  \
+             - default_host: no [[host]] lists \"nowhere.com\""
+        );
+    }
+
+    #[test]
+    fn only_listener_changes_need_a_restart() {
+        let config = Config::default();
+        let mut new = config.clone();
+        new.log.access = false;
+        new.timeouts.read = 1;
+        assert!(!config.needs_restart(&new));
+
+        new.server.workers = 1;
+        assert!(config.needs_restart(&new));
+    }
+
+    #[test]
+    fn cgi_pool_failures_are_errors() {
+        let mut config: Config = "[[route]]\npath = \"/hello\"\ncgi = \"cgi-bin/hello.sh\"\n"
+            .parse()
+            .unwrap();
+        // Past validation, as if the OS had refused to start the threads.
+        config.cgi.workers = 0;
+        assert!(matches!(
+            config.router(),
+            Err(ConfigError::Cgi(PoolCreationError::NoWorkers))
+        ));
+    }
+}
@@ -0,0 +1,695 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use smol::io::BlockOn;
+
+use crate::http::{Request, Response};
+
+// How much of a multipart body is read at a time.
+const CHUNK_SIZE: usize = 8 * 1024;
+// Longest header line allowed in a multipart part, and how many headers a part may have.
+const MAX_PART_HEADER: usize = 8 * 1024;
+const MAX_PART_HEADERS: usize = 16;
+
+// Numbers the temporary files of uploads, so that parallel requests never share one.
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+// What a form submission may contain. Anything bigger is refused with a 413 rather than
+// buffered or written to disk.
+#[derive(Debug, Clone)]
+pub struct FormLimits {
+    // Fields and files together.
+    pub max_fields: usize,
+    // Bytes in the value of a single non-file field.
+    pub max_field_size: usize,
+    pub max_files: usize,
+    // Bytes in a single uploaded file.
+    pub max_file_size: u64,
+    // Where uploaded files are written to while the request is handled.
+    pub temp_dir: PathBuf,
+}
+
+impl Default for FormLimits {
+    fn default() -> FormLimits {
+        FormLimits {
+            max_fields: 100,
+            max_field_size: 64 * 1024,
+            max_files: 10,
+            max_file_size: 10 * 1024 * 1024,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+// A parsed form submission: its text fields in the order they were sent, and the files that
+// came with it.
+#[derive(Debug, Default)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+    files: Vec<FormFile>,
+}
+
+// An uploaded file, written to a temporary file as it was read. The temporary file is deleted
+// when this is dropped, unless it was `persist`ed somewhere first.
+#[derive(Debug)]
+pub struct FormFile {
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub enum FormError {
+    // The body isn't a form at all.
+    UnsupportedMediaType(Option<String>),
+    Malformed(String),
+    TooLarge(String),
+    Missing(String),
+    // The field is there, but doesn't parse as the type asked for.
+    Invalid { name: String, value: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormError::UnsupportedMediaType(Some(content_type)) => {
+                write!(f, "{content_type:?} is not a form")
+            }
+            FormError::UnsupportedMediaType(None) => f.write_str("no Content-Type"),
+            FormError::Malformed(message) => write!(f, "malformed form: {message}"),
+            FormError::TooLarge(message) => write!(f, "form is too large: {message}"),
+            FormError::Missing(name) => write!(f, "missing field {name:?}"),
+            FormError::Invalid { name, value } => write!(f, "invalid {name:?}: {value:?}"),
+            FormError::Io(err) => write!(f, "failed to store upload: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<io::Error> for FormError {
+    fn from(err: io::Error) -> FormError {
+        FormError::Io(err)
+    }
+}
+
+impl FormError {
+    // What to tell the client, so that handlers can simply `return err.response()`.
+    pub fn response(&self) -> Response {
+        let status = match self {
+            FormError::UnsupportedMediaType(_) => 415,
+            FormError::TooLarge(_) => 413,
+            FormError::Io(_) => 500,
+            _ => 400,
+        };
+        let body = match self {
+            FormError::Io(_) => String::from("Internal Server Error"),
+            err => err.to_string(),
+        };
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+}
+
+impl Request {
+    // The submitted form, for `application/x-www-form-urlencoded` and `multipart/form-data`
+    // bodies, within the default `FormLimits`.
+    pub fn form(&self) -> Result<Form, FormError> {
+        self.form_with_limits(&FormLimits::default())
+    }
+
+    // Uploads too big for the server to buffer only come with a `body_reader`, so they need
+    // `read_form` instead.
+    pub fn form_with_limits(&self, limits: &FormLimits) -> Result<Form, FormError> {
+        if self.body_reader.is_some() {
+            return Err(FormError::TooLarge(String::from(
+                "body is too big to buffer",
+            )));
+        }
+
+        let content_type = self.header("Content-Type");
+        let (media_type, params) = split_media_type(content_type.unwrap_or(""));
+
+        if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            parse_urlencoded(&self.body, limits)
+        } else if media_type.eq_ignore_ascii_case("multipart/form-data") {
+            parse_multipart(self.body.as_slice(), boundary(&params)?, limits)
+        } else {
+            Err(FormError::UnsupportedMediaType(
+                content_type.map(String::from),
+            ))
+        }
+    }
+
+    // Like `form_with_limits`, but also takes uploads over the server's body limit, which are
+    // parsed as they arrive: files go to disk a chunk at a time, and the body is never held in
+    // memory. Parsing blocks on the connection and on writing files, so it happens on a thread
+    // of its own while the handler waits.
+    pub async fn read_form(&mut self, limits: &FormLimits) -> Result<Form, FormError> {
+        let Some(body) = self.body_reader.take() else {
+            return self.form_with_limits(limits);
+        };
+        let (_, params) = split_media_type(self.header("Content-Type").unwrap_or(""));
+        let boundary = boundary(&params)?.to_string();
+        let limits = limits.clone();
+        smol::unblock(move || parse_multipart(BlockOn::new(body), &boundary, &limits)).await
+    }
+}
+
+// The `boundary` parameter of a `multipart/form-data` media type.
+fn boundary(params: &[(String, String)]) -> Result<&str, FormError> {
+    params
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.as_str())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+        .ok_or_else(|| FormError::Malformed(String::from("no boundary")))
+}
+
+impl Form {
+    // The first value sent for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value sent for `name`, e.g. for checkboxes.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // `name`, parsed as a `T`: `let age: u32 = form.value("age")?;`
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<T, FormError> {
+        let value = self
+            .get(name)
+            .ok_or_else(|| FormError::Missing(name.to_string()))?;
+        value.trim().parse().map_err(|_| FormError::Invalid {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // The first file uploaded as `name`.
+    pub fn file(&self, name: &str) -> Option<&FormFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    pub fn files(&self) -> &[FormFile] {
+        &self.files
+    }
+
+    // Takes the first file uploaded as `name` out of the form, e.g. to `persist` it.
+    pub fn take_file(&mut self, name: &str) -> Option<FormFile> {
+        let ind = self.files.iter().position(|file| file.name == name)?;
+        Some(self.files.remove(ind))
+    }
+}
+
+impl FormFile {
+    // The form field it was uploaded as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The name the client gave it. Never use it as a path as-is: it could be `../../etc/passwd`.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The temporary file holding the upload.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    // Moves the upload to `to`, where it's kept.
+    pub fn persist(mut self, to: impl AsRef<Path>) -> io::Result<()> {
+        let to = to.as_ref();
+        // Renaming fails across file systems, in which case the file has to be copied.
+        if fs::rename(&self.path, to).is_err() {
+            fs::copy(&self.path, to)?;
+            let _ = fs::remove_file(&self.path);
+        }
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for FormFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// `text/html; charset="utf-8"` -> ("text/html", [("charset", "utf-8")]).
+fn split_media_type(value: &str) -> (&str, Vec<(String, String)>) {
+    let (media_type, rest) = value.split_once(';').unwrap_or((value, ""));
+    (media_type.trim(), parse_params(rest))
+}
+
+// `name="a; b"; filename=c.txt` -> [("name", "a; b"), ("filename", "c.txt")]. Quoted values may
+// contain semicolons and backslash-escaped quotes.
+fn parse_params(mut rest: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        let Some((name, after)) = rest.split_once('=') else {
+            return params;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let mut value = String::new();
+        if let Some(quoted) = after.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            rest = "";
+            while let Some((ind, ch)) = chars.next() {
+                match ch {
+                    '\\' => value.extend(chars.next().map(|(_, ch)| ch)),
+                    '"' => {
+                        rest = &quoted[ind + 1..];
+                        break;
+                    }
+                    ch => value.push(ch),
+                }
+            }
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            value.push_str(after[..end].trim());
+            rest = &after[end..];
+        }
+        params.push((name, value));
+    }
+}
+
+fn parse_urlencoded(body: &[u8], limits: &FormLimits) -> Result<Form, FormError> {
+    let mut form = Form::default();
+    for pair in body.split(|&byte| byte == b'&') {
+        if pair.is_empty() {
+            continue;
+        }
+        if form.fields.len() >= limits.max_fields {
+            return Err(FormError::TooLarge(format!(
+                "more than {} fields",
+                limits.max_fields
+            )));
+        }
+
+        let (name, value) = match pair.iter().position(|&byte| byte == b'=') {
+            Some(ind) => (&pair[..ind], &pair[ind + 1..]),
+            None => (pair, &[][..]),
+        };
+        let name = url_decode(name)?;
+        if value.len() > limits.max_field_size {
+            return Err(FormError::TooLarge(format!(
+                "{name:?} is over {} bytes",
+                limits.max_field_size
+            )));
+        }
+        form.fields.push((name, url_decode(value)?));
+    }
+    Ok(form)
+}
+
+// `a+b%21` -> "a b!".
+fn url_decode(encoded: &[u8]) -> Result<String, FormError> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [*bytes.next().unwrap_or(&0), *bytes.next().unwrap_or(&0)];
+                let byte = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| FormError::Malformed(String::from("bad percent-encoding")))?;
+                decoded.push(byte);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| FormError::Malformed(String::from("not UTF-8")))
+}
+
+// Reads `multipart/form-data` (RFC 7578) a chunk at a time, so that file parts go straight to
+// disk and memory use stays the same however big the upload is.
+//
+//     --boundary\r\n
+//     Content-Disposition: form-data; name="avatar"; filename="me.png"\r\n
+//     Content-Type: image/png\r\n
+//     \r\n
+//     ...bytes...\r\n
+//     --boundary--\r\n
+pub fn parse_multipart<R: Read>(
+    reader: R,
+    boundary: &str,
+    limits: &FormLimits,
+) -> Result<Form, FormError> {
+    let mut parts = Parts {
+        reader,
+        buffer: Vec::new(),
+    };
+    let delimiter = format!("\r\n--{boundary}").into_bytes();
+    let mut form = Form::default();
+
+    // Anything before the first boundary is a preamble, to be ignored. The first boundary
+    // doesn't need the CRLF in front of it.
+    parts.buffer.extend_from_slice(b"\r\n");
+    parts.read_until(&delimiter, &mut |_| Ok(()))?;
+
+    loop {
+        // Right after a boundary comes `--` for the last one, or the end of its line.
+        let line = parts.read_line()?;
+        if line.starts_with(b"--") {
+            return Ok(form);
+        }
+        if !line.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Err(FormError::Malformed(String::from("junk after boundary")));
+        }
+
+        if form.fields.len() + form.files.len() >= limits.max_fields {
+            return Err(FormError::TooLarge(format!(
+                "more than {} fields",
+                limits.max_fields
+            )));
+        }
+
+        let (name, file_name, content_type) = parts.read_part_headers()?;
+        match file_name {
+            Some(file_name) => {
+                if form.files.len() >= limits.max_files {
+                    return Err(FormError::TooLarge(format!(
+                        "more than {} files",
+                        limits.max_files
+                    )));
+                }
+                let file = parts.read_file(&delimiter, limits, name, file_name, content_type)?;
+                form.files.push(file);
+            }
+            None => {
+                let mut value = Vec::new();
+                parts.read_until(&delimiter, &mut |bytes| {
+                    if value.len() + bytes.len() > limits.max_field_size {
+                        return Err(FormError::TooLarge(format!(
+                            "{name:?} is over {} bytes",
+                            limits.max_field_size
+                        )));
+                    }
+                    value.extend_from_slice(bytes);
+                    Ok(())
+                })?;
+                let value = String::from_utf8(value)
+                    .map_err(|_| FormError::Malformed(format!("{name:?} is not UTF-8")))?;
+                form.fields.push((name, value));
+            }
+        }
+    }
+}
+
+struct Parts<R> {
+    reader: R,
+    // Read from `reader` but not looked at yet.
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> Parts<R> {
+    // Passes everything up to `delimiter` to `sink` and skips the delimiter itself.
+    // Only the last few bytes are held back in case they are the start of the delimiter.
+    fn read_until(
+        &mut self,
+        delimiter: &[u8],
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), FormError>,
+    ) -> Result<(), FormError> {
+        loop {
+            if let Some(end) = find(&self.buffer, delimiter) {
+                sink(&self.buffer[..end])?;
+                self.buffer.drain(..end + delimiter.len());
+                return Ok(());
+            }
+
+            let keep = delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let ready = self.buffer.len() - keep;
+                sink(&self.buffer[..ready])?;
+                self.buffer.drain(..ready);
+            }
+
+            let start = self.buffer.len();
+            self.buffer.resize(start + CHUNK_SIZE, 0);
+            let read = self.reader.read(&mut self.buffer[start..])?;
+            self.buffer.truncate(start + read);
+            if read == 0 {
+                return Err(FormError::Malformed(String::from("unexpected end of body")));
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>, FormError> {
+        let mut line = Vec::new();
+        self.read_until(b"\r\n", &mut |bytes| {
+            if line.len() + bytes.len() > MAX_PART_HEADER {
+                return Err(FormError::Malformed(String::from("header line too long")));
+            }
+            line.extend_from_slice(bytes);
+            Ok(())
+        })?;
+        Ok(line)
+    }
+
+    // The field name, file name and content type of the part that's next.
+    fn read_part_headers(&mut self) -> Result<(String, Option<String>, Option<String>), FormError> {
+        let (mut name, mut file_name, mut content_type) = (None, None, None);
+        for _ in 0..=MAX_PART_HEADERS {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                let name = name.ok_or_else(|| {
+                    FormError::Malformed(String::from("part without a field name"))
+                })?;
+                return Ok((name, file_name, content_type));
+            }
+
+            let line = String::from_utf8(line)
+                .map_err(|_| FormError::Malformed(String::from("part header is not UTF-8")))?;
+            let Some((header, value)) = line.split_once(':') else {
+                return Err(FormError::Malformed(format!("bad part header: {line:?}")));
+            };
+            if header.trim().eq_ignore_ascii_case("Content-Disposition") {
+                let (disposition, params) = split_media_type(value.trim());
+                if !disposition.eq_ignore_ascii_case("form-data") {
+                    return Err(FormError::Malformed(format!(
+                        "part is {disposition:?}, not form-data"
+                    )));
+                }
+                for (param, value) in params {
+                    match param.as_str() {
+                        "name" => name = Some(value),
+                        "filename" => file_name = Some(value),
+                        _ => {}
+                    }
+                }
+            } else if header.trim().eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+        Err(FormError::Malformed(String::from("too many part headers")))
+    }
+
+    fn read_file(
+        &mut self,
+        delimiter: &[u8],
+        limits: &FormLimits,
+        name: String,
+        file_name: String,
+        content_type: Option<String>,
+    ) -> Result<FormFile, FormError> {
+        let path = limits.temp_dir.join(format!(
+            "web_server_upload_{}_{}",
+            std::process::id(),
+            NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Created up front so that the temporary file is cleaned up if anything below fails.
+        let mut upload = FormFile {
+            name,
+            file_name: Some(file_name).filter(|file_name| !file_name.is_empty()),
+            content_type,
+            path,
+            size: 0,
+        };
+
+        let mut writer = BufWriter::new(file);
+        self.read_until(delimiter, &mut |bytes| {
+            upload.size += bytes.len() as u64;
+            if upload.size > limits.max_file_size {
+                return Err(FormError::TooLarge(format!(
+                    "{:?} is over {} bytes",
+                    upload.name, limits.max_file_size
+                )));
+            }
+            writer.write_all(bytes).map_err(FormError::Io)
+        })?;
+        writer.flush()?;
+        Ok(upload)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn request(content_type: &str, body: &str) -> Request {
+        let mut request = Request::new(Method::Post, "/submit");
+        request.headers.insert("Content-Type", content_type);
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn parses_urlencoded_fields() {
+        let request = request(
+            "application/x-www-form-urlencoded",
+            "name=Ferris+the+crab&age=7&tag=a&tag=b%26c&empty=&flag",
+        );
+        let form = request.form().unwrap();
+
+        assert_eq!(form.get("name"), Some("Ferris the crab"));
+        assert_eq!(form.value::<u32>("age").unwrap(), 7);
+        assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["a", "b&c"]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert!(matches!(
+            form.value::<u32>("name"),
+            Err(FormError::Invalid { .. })
+        ));
+        assert!(matches!(
+            form.value::<u32>("nope"),
+            Err(FormError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn streams_multipart_files_to_disk() {
+        let body = "preamble\r\n\
+             --XyZ\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\
+             \r\n\
+             Holiday; \"summer\"\r\n\
+             --XyZ\r\n\
+             Content-Disposition: form-data; name=\"photo\"; filename=\"beach.txt\"\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             sand\r\n--Xy and sea\r\n\
+             --XyZ--\r\n";
+        let request = request("multipart/form-data; boundary=XyZ", body);
+
+        // Small chunks, so that the boundary is split across reads.
+        let mut form = parse_multipart(
+            ChunkedReader(request.body.as_slice()),
+            "XyZ",
+            &FormLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(form.get("title"), Some("Holiday; \"summer\""));
+
+        let photo = form.file("photo").unwrap();
+        assert_eq!(photo.file_name(), Some("beach.txt"));
+        assert_eq!(photo.content_type(), Some("text/plain"));
+        assert_eq!(photo.size(), 18);
+        let path = photo.path().to_path_buf();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "sand\r\n--Xy and sea");
+
+        drop(form.take_file("photo"));
+        assert!(!path.exists());
+        assert_eq!(request.form().unwrap().files().len(), 1);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let body = "--b\r\n\
+             Content-Disposition: form-data; name=\"f\"; filename=\"big\"\r\n\
+             \r\n\
+             0123456789\r\n\
+             --b--\r\n";
+        let request = request("multipart/form-data; boundary=b", body);
+        let limits = FormLimits {
+            max_file_size: 5,
+            ..FormLimits::default()
+        };
+        let err = request.form_with_limits(&limits).unwrap_err();
+        assert_eq!(err.response().status, 413);
+
+        let request = self::request("application/x-www-form-urlencoded", "a=1&b=2&c=3");
+        let limits = FormLimits {
+            max_fields: 2,
+            ..FormLimits::default()
+        };
+        assert!(matches!(
+            request.form_with_limits(&limits),
+            Err(FormError::TooLarge(_))
+        ));
+
+        let err = self::request("application/json", "{}").form().unwrap_err();
+        assert_eq!(err.response().status, 415);
+        let err = self::request("multipart/form-data; boundary=b", "--b\r\nno end")
+            .form()
+            .unwrap_err();
+        assert_eq!(err.response().status, 400);
+    }
+
+    // Hands out at most 3 bytes per read.
+    struct ChunkedReader<'a>(&'a [u8]);
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3).min(self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+}
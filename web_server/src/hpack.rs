@@ -0,0 +1,544 @@
+use std::{collections::VecDeque, fmt, sync::OnceLock};
+
+// HPACK (RFC 7541), the header compression of HTTP/2.
+//
+// Header blocks are a sequence of instructions: "header #N from the tables", "this name and
+// value, and add them to the table", "this name from the tables with this value", or "resize
+// the table". Decoding has to follow along with every instruction, since the dynamic table it
+// builds up is shared by all the header blocks of a connection.
+//
+// Our encoder never adds to the dynamic table, which HPACK allows: it refers to the static
+// table where it can and sends everything else as plain literals. Responses are small enough
+// that the saving wouldn't be worth keeping the state.
+
+// The dynamic table of a connection starts out this big (SETTINGS_HEADER_TABLE_SIZE).
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// Every entry in the dynamic table, and every field of a header list, counts for this much on
+// top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HpackError(&'static str);
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad header block: {}", self.0)
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+pub struct Decoder {
+    // Newest entry first, which is also the order they are numbered in.
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // The most the peer may resize the table to, i.e. what we allowed in our settings.
+    limit: usize,
+}
+
+impl Decoder {
+    pub fn new(limit: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    // The header fields in `block`, in order. After an error the decoder is out of step with
+    // the peer's encoder, so the connection can't be used any more.
+    //
+    // A small block can stand for a huge list, by naming the same big table entry over and
+    // over, so the list is refused once it gets bigger than `max_list_size`. Like
+    // SETTINGS_MAX_HEADER_LIST_SIZE, that counts names and values plus 32 bytes per field.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<(String, String)>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                // Indexed field.
+                let index = decode_int(&mut block, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                // Literal that goes into the table.
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                let size = decode_int(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(HpackError("table size over the limit"));
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // Literal that doesn't go into the table, or must never go into one. We don't
+                // pass the difference on since we don't forward headers over HTTP/2.
+                self.decode_literal(&mut block, 4)?
+            };
+
+            list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            if list_size > max_list_size {
+                return Err(HpackError("header list over the limit"));
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix: u8,
+    ) -> Result<(String, String), HpackError> {
+        let name = match decode_int(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block)?))
+    }
+
+    // Index 1 to 61 is the static table, the dynamic table comes after that.
+    fn get(&self, index: usize) -> Result<(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError("index 0")),
+            index if index <= STATIC_TABLE.len() => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            index => self
+                .table
+                .get(index - STATIC_TABLE.len() - 1)
+                .cloned()
+                .ok_or(HpackError("index out of range")),
+        }
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        self.size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.table.push_front(field);
+        // An entry bigger than the whole table just empties it.
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+// Encodes `fields` into a header block that any decoder can read, whatever its table holds.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(ind) = STATIC_TABLE
+            .iter()
+            .position(|&field| field == (name, value))
+        {
+            encode_int(&mut block, 0x80, 7, ind + 1);
+            continue;
+        }
+
+        // Literal without indexing, with the name from the static table if it's there.
+        let name_index = STATIC_TABLE
+            .iter()
+            .position(|&(static_name, _)| static_name == name)
+            .map_or(0, |ind| ind + 1);
+        encode_int(&mut block, 0x00, 4, name_index);
+        if name_index == 0 {
+            encode_string(&mut block, name);
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+// Integers fill up the low `prefix` bits of the first byte; bigger ones continue 7 bits at a
+// time, least significant first, for as long as the top bit is set.
+fn decode_int(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, mut rest) = block.split_first().ok_or(HpackError("truncated"))?;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, next) = rest.split_first().ok_or(HpackError("truncated"))?;
+            rest = next;
+            if shift > 28 {
+                return Err(HpackError("integer too large"));
+            }
+            value += usize::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+fn encode_int(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+// A length with a flag for Huffman coding, then the bytes.
+fn decode_string(block: &mut &[u8]) -> Result<String, HpackError> {
+    let huffman = block.first().is_some_and(|&byte| byte & 0x80 != 0);
+    let len = decode_int(block, 7)?;
+    if len > block.len() {
+        return Err(HpackError("truncated"));
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| HpackError("not UTF-8"))
+}
+
+// We never Huffman-code: it saves little on the headers we send.
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_int(block, 0x00, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+// Walks the code tree bit by bit. Each node is a pair of children, either another node or a
+// decoded byte.
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut node, mut depth, mut all_ones) = (0, 0, true);
+
+    for &byte in bytes {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            all_ones &= bit == 1;
+            depth += 1;
+            match tree[node][usize::from(bit)] {
+                Child::Node(next) => node = next,
+                Child::Symbol(256) => return Err(HpackError("EOS in string")),
+                Child::Symbol(symbol) => {
+                    decoded.push(symbol as u8);
+                    (node, depth, all_ones) = (0, 0, true);
+                }
+                Child::Empty => return Err(HpackError("invalid Huffman code")),
+            }
+        }
+    }
+    // Whatever is left over must be the start of EOS (all ones), and shorter than a byte.
+    if depth > 7 || !all_ones {
+        return Err(HpackError("invalid Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+#[derive(Clone, Copy)]
+enum Child {
+    Empty,
+    Node(usize),
+    Symbol(u16),
+}
+
+fn huffman_tree() -> &'static [[Child; 2]] {
+    static TREE: OnceLock<Vec<[Child; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[Child::Empty; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for shift in (0..len).rev() {
+                let bit = ((code >> shift) & 1) as usize;
+                if shift == 0 {
+                    tree[node][bit] = Child::Symbol(symbol as u16);
+                } else if let Child::Node(next) = tree[node][bit] {
+                    node = next;
+                } else {
+                    tree.push([Child::Empty; 2]);
+                    tree[node][bit] = Child::Node(tree.len() - 1);
+                    node = tree.len() - 1;
+                }
+            }
+        }
+        tree
+    })
+}
+
+// RFC 7541 Appendix A. Index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// RFC 7541 Appendix B: the code for every byte value, and for EOS (256), with its length in bits.
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // The requests from RFC 7541 C.4, which share a dynamic table and use Huffman coding.
+    #[test]
+    fn decodes_the_rfc_examples() {
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+
+        let first = decoder
+            .decode(
+                &hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+                usize::MAX,
+            )
+            .unwrap();
+        assert_eq!(
+            first,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let second = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), usize::MAX)
+            .unwrap();
+        assert_eq!(second[3], first[3]);
+        assert_eq!(second[4], fields(&[("cache-control", "no-cache")])[0]);
+
+        let third = decoder
+            .decode(
+                &hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                usize::MAX,
+            )
+            .unwrap();
+        assert_eq!(
+            third,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table.len(), 3);
+    }
+
+    #[test]
+    fn evicts_and_rejects_bad_blocks() {
+        let mut decoder = Decoder::new(64);
+        let block = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        decoder.decode(&block, usize::MAX).unwrap();
+        assert_eq!(decoder.size, 55);
+        decoder.decode(&block, usize::MAX).unwrap();
+        assert_eq!(decoder.table.len(), 1);
+
+        // Resizing past what we allowed, an index past the tables, and a cut-off string.
+        assert!(decoder.decode(&hex("3f e1 1f"), usize::MAX).is_err());
+        assert!(decoder.decode(&hex("bf"), usize::MAX).is_err());
+        assert!(decoder.decode(&hex("0085 6162"), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn encoded_blocks_decode_to_the_same_fields() {
+        let sent = [
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/html; charset=utf-8"),
+            ("x-long", "x".repeat(300).leak() as &str),
+        ];
+        let block = encode(sent);
+        // `:status: 200` is static entry 8.
+        assert_eq!(block[0], 0x88);
+
+        let decoded = Decoder::new(DEFAULT_TABLE_SIZE)
+            .decode(&block, usize::MAX)
+            .unwrap();
+        assert_eq!(decoded, fields(&sent));
+    }
+
+    #[test]
+    fn refuses_header_lists_over_the_limit() {
+        // One 1000-byte value that goes into the table, then 100 references to it: a block of
+        // about 1 KB for a list of about 100 KB.
+        let mut block = vec![0x40, 0x01, b'x', 0x7f, 0xe9, 0x06];
+        block.extend_from_slice(&[b'y'; 1000]);
+        block.extend_from_slice(&[0xbe; 100]);
+
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert!(decoder.decode(&block, 16 * 1024).is_err());
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert_eq!(decoder.decode(&block, usize::MAX).unwrap().len(), 101);
+
+        // The limit counts 32 bytes per field on top of the name and value.
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert!(decoder.decode(&[0x82], 42).is_ok());
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert!(decoder.decode(&[0x82], 41).is_err());
+    }
+}
@@ -0,0 +1,698 @@
+use std::{
+    fmt, fs,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use smol::{
+    channel,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite},
+    stream::Stream,
+};
+
+use crate::router::{timeout, BoxFuture};
+
+// Bodies bigger than this are rejected before we buffer them, unless the server allows more for
+// requests (`Server::with_max_body_size`). Uploads are the exception, see `BodyReader`.
+pub(crate) const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// How much of a streamed body is read at a time, and how many chunks may be read ahead of the
+// handler.
+const BODY_CHUNK_SIZE: usize = 16 * 1024;
+const BODY_CHUNKS_AHEAD: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Other(String),
+}
+
+impl Method {
+    pub(crate) fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Header names are case-insensitive, so a plain HashMap keyed by name won't do.
+// A Vec also keeps the order the headers were sent in and allows duplicates.
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replaces every existing value of `name`.
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.0.push((name.to_string(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // Takes the place of `body` for uploads too big to buffer; see `BodyReader`.
+    pub body_reader: Option<BodyReader>,
+    pub peer_addr: Option<SocketAddr>,
+    // Whether the request arrived over TLS.
+    pub secure: bool,
+    // The host name the client asked for in the TLS handshake (SNI), if it sent one.
+    pub server_name: Option<String>,
+}
+
+impl Request {
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+
+        Request {
+            method,
+            path,
+            query,
+            version: String::from("HTTP/1.1"),
+            headers: Headers::new(),
+            body: Vec::new(),
+            body_reader: None,
+            peer_addr: None,
+            secure: false,
+            server_name: None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    // Reads a single request off the connection.
+    // Returns `Ok(None)` if the client hung up before sending anything, and an error of kind
+    // `FileTooLarge` if the body is over `max_body` bytes, before reading any of it.
+    pub async fn read_from<R>(reader: &mut R, max_body: usize) -> io::Result<Option<Request>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let Some(mut request) = Request::read_head(reader).await? else {
+            return Ok(None);
+        };
+        request.read_body(reader, max_body).await?;
+        Ok(Some(request))
+    }
+
+    // The request line and headers, leaving the body on the connection.
+    pub(crate) async fn read_head<R>(reader: &mut R) -> io::Result<Option<Request>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid_data(format!("malformed request line: {line:?}")));
+        };
+
+        let mut request = Request::new(Method::parse(method), target);
+        request.version = version.to_string();
+        request.headers = read_headers(reader).await?;
+        Ok(Some(request))
+    }
+
+    pub(crate) async fn read_body<R>(&mut self, reader: &mut R, max_body: usize) -> io::Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let length = content_length(&self.headers, max_body)?.unwrap_or(0);
+        self.body = vec![0; length];
+        reader.read_exact(&mut self.body).await
+    }
+
+    // The length of a body over `max_body` bytes that goes to the handler as it arrives instead
+    // of being refused. Only `multipart/form-data` bodies do, since `Request::read_form` can parse
+    // them on the fly; anything else would have to be buffered after all.
+    pub(crate) fn streamed_length(&self, max_body: usize) -> Option<u64> {
+        let length = self.header("Content-Length")?.parse::<u64>().ok()?;
+        let media_type = self.header("Content-Type")?.split(';').next()?.trim();
+        (length > max_body as u64 && media_type.eq_ignore_ascii_case("multipart/form-data"))
+            .then_some(length)
+    }
+}
+
+// Reads header lines up to and including the blank line that ends them.
+async fn read_headers<R>(reader: &mut R) -> io::Result<Headers>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut headers = Headers::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(headers);
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid_data(format!("malformed header: {line:?}")));
+        };
+        headers.append(name.trim(), value.trim());
+    }
+}
+
+fn content_length(headers: &Headers, limit: usize) -> io::Result<Option<usize>> {
+    let Some(length) = headers.get("Content-Length") else {
+        return Ok(None);
+    };
+    let length = length
+        .parse::<usize>()
+        .map_err(|_| invalid_data(format!("bad Content-Length: {length:?}")))?;
+    if length > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("body of {length} bytes is over the limit of {limit}"),
+        ));
+    }
+    Ok(Some(length))
+}
+
+// Decodes a `Transfer-Encoding: chunked` body, ignoring chunk extensions and trailers.
+async fn read_chunked<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let size = line.trim_end().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size.trim(), 16)
+            .map_err(|_| invalid_data(format!("bad chunk size: {line:?}")))?;
+        // A size near `usize::MAX` would overflow the sum.
+        if body
+            .len()
+            .checked_add(size)
+            .is_none_or(|len| len > MAX_BODY_SIZE)
+        {
+            return Err(invalid_data(String::from("chunked body is too large")));
+        }
+
+        if size == 0 {
+            // Trailers, if any, end with a blank line just like headers.
+            read_headers(reader).await?;
+            return Ok(body);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        // Every chunk is followed by CRLF.
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+    }
+}
+
+// Any connection the server can hand over to a protocol other than HTTP/1.1, e.g. WebSocket.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+// Takes over the connection once the response head has been written.
+pub struct Upgrade(Box<dyn FnOnce(Box<dyn Io>) -> BoxFuture<()> + Send>);
+
+impl Upgrade {
+    pub fn new<F>(on_upgrade: F) -> Upgrade
+    where
+        F: FnOnce(Box<dyn Io>) -> BoxFuture<()> + Send + 'static,
+    {
+        Upgrade(Box::new(on_upgrade))
+    }
+
+    pub fn run(self, io: Box<dyn Io>) -> BoxFuture<()> {
+        (self.0)(io)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+// A body that is sent while it is being produced, e.g. server-sent events. Every chunk goes out as
+// soon as it's ready, instead of once the whole body is, which may be never. The server stops
+// polling (and drops the stream) once the client has gone away.
+pub struct BodyStream(Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>);
+
+impl BodyStream {
+    pub fn new(stream: impl Stream<Item = Vec<u8>> + Send + 'static) -> BodyStream {
+        BodyStream(Box::pin(stream))
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+// The body of an upload too big to buffer, read off the connection as the handler asks for it
+// (see `Request::read_form`). Only a few chunks are read ahead, so memory use stays the same
+// however big the upload is. Reading it fails if the client stops sending for the server's read
+// timeout, or hangs up early. Only HTTP/1.1 requests get one; over HTTP/2, bodies are buffered
+// and limited like any other.
+pub struct BodyReader {
+    chunks: Pin<Box<channel::Receiver<io::Result<Vec<u8>>>>>,
+    chunk: Vec<u8>,
+    // How much of `chunk` has been read.
+    pos: usize,
+}
+
+impl BodyReader {
+    // A reader for the next `length` bytes of `reader`, and the future that feeds it, which has to
+    // run alongside the handler. The future stops early once the reader is dropped.
+    pub(crate) fn new<'a, R>(
+        reader: &'a mut R,
+        length: u64,
+        read_timeout: Option<Duration>,
+    ) -> (BodyReader, impl Future<Output = ()> + 'a)
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (sender, receiver) = channel::bounded(BODY_CHUNKS_AHEAD);
+        let feed = async move {
+            let mut left = length;
+            while left > 0 {
+                let mut chunk = vec![0; left.min(BODY_CHUNK_SIZE as u64) as usize];
+                let read = reader.read(&mut chunk);
+                let read = match read_timeout {
+                    Some(duration) => timeout(duration, read)
+                        .await
+                        .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into())),
+                    None => read.await,
+                };
+                let chunk = match read {
+                    Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(len) => {
+                        chunk.truncate(len);
+                        left -= len as u64;
+                        Ok(chunk)
+                    }
+                    Err(err) => Err(err),
+                };
+                let failed = chunk.is_err();
+                if sender.send(chunk).await.is_err() || failed {
+                    return;
+                }
+            }
+        };
+        let body = BodyReader {
+            chunks: Box::pin(receiver),
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        (body, feed)
+    }
+}
+
+impl AsyncRead for BodyReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pos == self.chunk.len() {
+            // The channel closes after the last chunk, or after an error.
+            match ready!(self.chunks.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodyReader")
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // Set on `101 Switching Protocols` responses; see `Upgrade`.
+    pub upgrade: Option<Upgrade>,
+    // Takes the place of `body` when set; see `BodyStream`.
+    pub stream: Option<BodyStream>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+            upgrade: None,
+            stream: None,
+        }
+    }
+
+    pub fn html(status: u16, contents: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(contents.into())
+    }
+
+    // Serves an HTML file from disk, or a plain 500 if we can't read it.
+    pub fn html_file(status: u16, path: impl AsRef<Path>) -> Response {
+        match fs::read_to_string(path.as_ref()) {
+            Ok(contents) => Response::html(status, contents),
+            Err(err) => {
+                eprintln!("Failed to read {}: {err}", path.as_ref().display());
+                Response::new(500).with_body("Internal Server Error")
+            }
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    pub fn with_upgrade(mut self, upgrade: Upgrade) -> Response {
+        self.upgrade = Some(upgrade);
+        self
+    }
+
+    pub fn with_stream(mut self, stream: BodyStream) -> Response {
+        self.stream = Some(stream);
+        self
+    }
+
+    // The answer to a request with a body over `limit` bytes.
+    pub(crate) fn too_large(limit: usize) -> Response {
+        Response::new(413).with_body(format!(
+            "Payload Too Large: bodies are limited to {limit} bytes"
+        ))
+    }
+
+    // Reads a response off a connection to another server, e.g. a proxied backend. A response to
+    // HEAD (`head`) has no body, but keeps its `Content-Length`: the length a GET would get.
+    pub async fn read_from<R>(reader: &mut R, head: bool) -> io::Result<Response>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| invalid_data(format!("malformed status line: {line:?}")))?;
+
+        let mut response = Response::new(status);
+        response.headers = read_headers(reader).await?;
+
+        let chunked = response
+            .headers
+            .get("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+        if status < 200 || status == 204 || status == 304 {
+            // No body, whatever the headers say.
+        } else if head {
+            return Ok(response);
+        } else if chunked {
+            response.body = read_chunked(reader).await?;
+            response.headers.remove("Transfer-Encoding");
+        } else if let Some(length) = content_length(&response.headers, MAX_BODY_SIZE)? {
+            response.body = vec![0; length];
+            reader.read_exact(&mut response.body).await?;
+        } else {
+            // Without a length the body runs until the server closes the connection.
+            reader
+                .take(MAX_BODY_SIZE as u64)
+                .read_to_end(&mut response.body)
+                .await?;
+        }
+        // We always send our own length.
+        response.headers.remove("Content-Length");
+
+        Ok(response)
+    }
+
+    // Adds `name` to the `Vary` header without clobbering what other handlers put there.
+    pub fn add_vary(&mut self, name: &str) {
+        let vary = match self.headers.get("Vary") {
+            Some(vary)
+                if vary
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(name) || v.trim() == "*") =>
+            {
+                return
+            }
+            Some(vary) => format!("{vary}, {name}"),
+            None => name.to_string(),
+        };
+        self.headers.insert("Vary", vary);
+    }
+
+    // Serializes the status line and headers, including the blank line that ends them.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // Informational responses and 204 must not carry a length, and neither do chunked bodies.
+        // A length that's set already is the one for a HEAD response, which has no body.
+        if self.status >= 200
+            && self.status != 204
+            && self.headers.get("Transfer-Encoding").is_none()
+            && self.headers.get("Content-Length").is_none()
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        404 => "NOT FOUND",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> io::Result<Option<Request>> {
+        let mut reader = smol::io::BufReader::new(raw.as_bytes());
+        smol::block_on(Request::read_from(&mut reader, MAX_BODY_SIZE))
+    }
+
+    #[test]
+    fn parses_request_line_headers_and_body() {
+        let request =
+            parse("POST /submit?x=1 HTTP/1.1\r\nHost: example\r\ncontent-length: 5\r\n\r\nhello")
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/submit");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.header("Content-Length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn parses_chunked_responses() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut reader = smol::io::BufReader::new(raw.as_bytes());
+        let response = smol::block_on(Response::read_from(&mut reader, false)).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello, world");
+        assert_eq!(response.headers.get("Transfer-Encoding"), None);
+    }
+
+    #[test]
+    fn rejects_oversized_chunks() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\nffffffffffffffff\r\n";
+        let mut reader = smol::io::BufReader::new(raw.as_bytes());
+        let err = smol::block_on(Response::read_from(&mut reader, false)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn vary_is_appended_once() {
+        let mut response = Response::new(200).with_header("Vary", "Origin");
+        response.add_vary("Accept-Encoding");
+        response.add_vary("accept-encoding");
+        assert_eq!(
+            response.headers.get("Vary"),
+            Some("Origin, Accept-Encoding")
+        );
+    }
+
+    #[test]
+    fn streams_big_uploads() {
+        let raw = "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+                   Content-Length: 10\r\n\r\n0123456789";
+        let mut reader = smol::io::BufReader::new(raw.as_bytes());
+        smol::block_on(async {
+            let request = Request::read_head(&mut reader).await.unwrap().unwrap();
+            assert_eq!(request.streamed_length(10), None);
+            assert_eq!(request.streamed_length(5), Some(10));
+
+            // The client hangs up 2 bytes short.
+            let (mut body, feed) = BodyReader::new(&mut reader, 12, None);
+            let mut read = Vec::new();
+            let (_, result) = smol::future::zip(feed, body.read_to_end(&mut read)).await;
+            assert_eq!(read, b"0123456789");
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn empty_connection_is_not_an_error() {
+        assert!(parse("").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_request_line() {
+        assert!(parse("GARBAGE\r\n\r\n").is_err());
+    }
+}
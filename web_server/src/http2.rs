@@ -0,0 +1,948 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use smol::{
+    channel::{self, Receiver, Sender},
+    future,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::StreamExt,
+    Executor, Task,
+};
+
+use crate::{
+    hpack::{self, Decoder},
+    http::{Headers, Method, Request, Response},
+    router::{timeout, BoxFuture},
+};
+
+// What an HTTP/2 client sends before anything else. The first line looks like a request to
+// HTTP/1.1 servers: `PRI * HTTP/2.0`, with no headers. See `is_preface`.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types (RFC 9113 section 6).
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags. ACK shares its bit with END_STREAM, on frame types that have no streams.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings we care about.
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes, for RST_STREAM and GOAWAY.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Until the peer says otherwise, frames are at most this big and each side may send this much
+// before the other has to grant more with a WINDOW_UPDATE.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+// Requests a client may have going at once on one connection.
+const MAX_STREAMS: usize = 100;
+// Header blocks bigger than this, spread over CONTINUATION frames or not, are refused.
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+// And so are header lists bigger than this once decoded, which we tell the peer in our settings.
+const MAX_HEADER_LIST: usize = 64 * 1024;
+
+// Whether an HTTP/1.1 request is really the start of an HTTP/2 connection from a client that
+// knew in advance that we speak it ("prior knowledge", no TLS). The rest of the preface,
+// `SM\r\n\r\n`, is still to be read.
+pub fn is_preface(request: &Request) -> bool {
+    request.method == Method::Other(String::from("PRI"))
+        && request.path == "*"
+        && request.version == "HTTP/2.0"
+}
+
+// Serves an HTTP/2 connection whose preface has been read, until the client goes away or stops
+// sending anything for `idle_timeout`. Every request is handed to `handle` as it comes in, so
+// a slow response doesn't hold up the others on the same connection. Requests with bodies over
+// `max_body` bytes get a 413 instead.
+//
+// Reading and writing are separate tasks: the reader parses frames and starts handlers, and
+// tells the writer what to send through a channel. The writer is the only one touching the
+// connection's output and the flow-control windows, which keeps the frames in order.
+pub async fn serve<S, F>(
+    io: S,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_body: usize,
+    handle: F,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: Fn(Request) -> BoxFuture<Response> + Send + Sync,
+{
+    let (reader, writer) = smol::io::split(io);
+    let (commands, queue) = channel::unbounded();
+    let open = Arc::new(AtomicUsize::new(0));
+    // Handlers run on an executor of the connection's own, which makes progress whenever the
+    // connection does, on whichever thread that is.
+    let executor: Executor<'static> = Executor::new();
+
+    let reading = async {
+        let mut connection = Connection {
+            reader,
+            decoder: Decoder::new(hpack::DEFAULT_TABLE_SIZE),
+            commands: commands.clone(),
+            executor: &executor,
+            handle: &handle,
+            incoming: HashMap::new(),
+            tasks: HashMap::new(),
+            last_stream: 0,
+            open: Arc::clone(&open),
+            idle_timeout,
+            max_body,
+        };
+        let end = connection.run().await;
+
+        // Requests that were still arriving won't be completed now.
+        for &stream in connection.incoming.keys() {
+            let _ = commands.send(Command::Reset(stream, CANCEL)).await;
+        }
+        let last_stream = connection.last_stream;
+        let command = match end {
+            Ok(End::Finish) => Command::Finish(last_stream),
+            Ok(End::Closed) => Command::Abort,
+            Err(Error::Protocol(code, message)) => {
+                eprintln!("HTTP/2 protocol error: {message}");
+                Command::GoAway(last_stream, code)
+            }
+            Err(Error::Io(err)) => {
+                if err.kind() != io::ErrorKind::UnexpectedEof {
+                    eprintln!("HTTP/2 connection failed: {err}");
+                }
+                Command::Abort
+            }
+        };
+        let _ = commands.send(command).await;
+        // The writer decides when the connection is done.
+        future::pending::<io::Result<()>>().await
+    };
+
+    let writing = write_loop(writer, queue, Arc::clone(&open), write_timeout);
+    executor.run(future::or(writing, reading)).await
+}
+
+// What the reader and the handlers ask the writer to do.
+enum Command {
+    // A new stream, whose response will come later.
+    Open(u32),
+    // The response to a stream, and whether it answers a HEAD request.
+    Respond(u32, Response, bool),
+    // Like `Respond`, but the body follows in `Data` commands. The writer signals on the sender
+    // each time it has sent all it was given, and the handler waits for that before producing
+    // more, so a peer that doesn't read can't make us buffer the whole body.
+    Stream(u32, Response, bool, Sender<()>),
+    // A chunk of a streamed body, or `None` once it has ended.
+    Data(u32, Option<Vec<u8>>),
+    // The peer sent this many bytes of DATA, which we give back to its window once read.
+    Received(Option<u32>, usize),
+    WindowUpdate(u32, u32),
+    Settings(Vec<(u16, u32)>),
+    Ping([u8; 8]),
+    // We give up on a stream, with an error code.
+    Reset(u32, u32),
+    // The peer gave up on a stream.
+    Cancelled(u32),
+    // Finish the open streams, then say goodbye.
+    Finish(u32),
+    // Say goodbye with an error, right away.
+    GoAway(u32, u32),
+    // The peer is gone.
+    Abort,
+}
+
+enum End {
+    // The peer said goodbye, or was idle for too long.
+    Finish,
+    Closed,
+}
+
+enum Error {
+    Io(io::Error),
+    // Means the whole connection has to go, with this error code.
+    Protocol(u32, String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+fn protocol_error(message: impl Into<String>) -> Error {
+    Error::Protocol(PROTOCOL_ERROR, message.into())
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// A header block that continues in CONTINUATION frames.
+struct PartialHeaders {
+    stream: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+struct Connection<'a, R, F> {
+    reader: R,
+    decoder: Decoder,
+    commands: Sender<Command>,
+    executor: &'a Executor<'static>,
+    handle: &'a F,
+    // Requests whose body is still arriving.
+    incoming: HashMap<u32, Request>,
+    // Handlers that may still be running or streaming a body. Dropping one cancels it, which is
+    // what happens when the peer resets its stream.
+    tasks: HashMap<u32, Task<()>>,
+    last_stream: u32,
+    // Streams that are open, until the writer has sent all of their response.
+    open: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    max_body: usize,
+}
+
+impl<'a, R, F> Connection<'a, R, F>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(Request) -> BoxFuture<Response> + Send + Sync,
+{
+    async fn run(&mut self) -> Result<End, Error> {
+        let mut partial: Option<PartialHeaders> = None;
+        loop {
+            // Only a connection with nothing going on can be idle.
+            let read = read_frame(&mut self.reader);
+            let frame = match self.idle_timeout {
+                Some(duration) if self.open.load(Ordering::SeqCst) == 0 => {
+                    match timeout(duration, read).await {
+                        Some(frame) => frame?,
+                        None => return Ok(End::Finish),
+                    }
+                }
+                _ => read.await?,
+            };
+            let Some(frame) = frame else {
+                return Ok(End::Closed);
+            };
+
+            // Nothing may come between a HEADERS frame and its CONTINUATION frames.
+            if let Some(mut headers) = partial.take() {
+                if frame.kind != CONTINUATION || frame.stream != headers.stream {
+                    return Err(protocol_error("expected CONTINUATION"));
+                }
+                headers.block.extend_from_slice(&frame.payload);
+                if headers.block.len() > MAX_HEADER_BLOCK {
+                    return Err(Error::Protocol(
+                        ENHANCE_YOUR_CALM,
+                        String::from("header block too large"),
+                    ));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.headers(headers).await?;
+                } else {
+                    partial = Some(headers);
+                }
+                continue;
+            }
+
+            match frame.kind {
+                DATA => self.data(frame).await?,
+                HEADERS => {
+                    if frame.stream == 0 || frame.stream.is_multiple_of(2) {
+                        return Err(protocol_error("HEADERS on a server stream"));
+                    }
+                    let mut payload = unpad(&frame)?;
+                    if frame.flags & PRIORITY_FLAG != 0 {
+                        payload = payload
+                            .get(5..)
+                            .ok_or_else(|| protocol_error("short HEADERS frame"))?;
+                    }
+                    let headers = PartialHeaders {
+                        stream: frame.stream,
+                        block: payload.to_vec(),
+                        end_stream: frame.flags & END_STREAM != 0,
+                    };
+                    if frame.flags & END_HEADERS != 0 {
+                        self.headers(headers).await?;
+                    } else {
+                        partial = Some(headers);
+                    }
+                }
+                // We answer requests in the order they finish, whatever their priority.
+                PRIORITY => {}
+                RST_STREAM => {
+                    if frame.stream == 0 || frame.payload.len() != 4 {
+                        return Err(protocol_error("bad RST_STREAM"));
+                    }
+                    self.incoming.remove(&frame.stream);
+                    self.tasks.remove(&frame.stream);
+                    self.send(Command::Cancelled(frame.stream)).await;
+                }
+                SETTINGS => {
+                    if frame.stream != 0 {
+                        return Err(protocol_error("SETTINGS on a stream"));
+                    }
+                    if frame.flags & ACK == 0 {
+                        let settings = parse_settings(&frame.payload)?;
+                        self.send(Command::Settings(settings)).await;
+                    }
+                }
+                PUSH_PROMISE => return Err(protocol_error("clients can't push")),
+                PING => {
+                    let data = <[u8; 8]>::try_from(frame.payload.as_slice())
+                        .map_err(|_| Error::Protocol(FRAME_SIZE_ERROR, String::from("bad PING")))?;
+                    if frame.flags & ACK == 0 {
+                        self.send(Command::Ping(data)).await;
+                    }
+                }
+                GOAWAY => return Ok(End::Finish),
+                WINDOW_UPDATE => {
+                    let increment = <[u8; 4]>::try_from(frame.payload.as_slice())
+                        .map(|bytes| u32::from_be_bytes(bytes) & 0x7fff_ffff)
+                        .map_err(|_| {
+                            Error::Protocol(FRAME_SIZE_ERROR, String::from("bad WINDOW_UPDATE"))
+                        })?;
+                    match (increment, frame.stream) {
+                        (0, 0) => return Err(protocol_error("empty WINDOW_UPDATE")),
+                        (0, stream) => self.send(Command::Reset(stream, PROTOCOL_ERROR)).await,
+                        (increment, stream) => {
+                            self.send(Command::WindowUpdate(stream, increment)).await
+                        }
+                    }
+                }
+                CONTINUATION => return Err(protocol_error("unexpected CONTINUATION")),
+                // Unknown frame types must be ignored.
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&self, command: Command) {
+        // Only fails once the writer is gone, and then the connection is over anyway.
+        let _ = self.commands.send(command).await;
+    }
+
+    async fn data(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(protocol_error("DATA on stream 0"));
+        }
+        let data = unpad(&frame)?;
+        let end_stream = frame.flags & END_STREAM != 0;
+
+        let Some(request) = self.incoming.get_mut(&frame.stream) else {
+            if frame.stream > self.last_stream {
+                return Err(protocol_error("DATA on an idle stream"));
+            }
+            // A stream we already reset or answered. Frames the peer sent before it heard
+            // about that are fine, but still count against the connection's window.
+            self.send(Command::Received(None, frame.payload.len()))
+                .await;
+            return Ok(());
+        };
+
+        if request.body.len() + data.len() > self.max_body {
+            // Answer right away, then tell the client to stop sending the rest.
+            self.incoming.remove(&frame.stream);
+            let response = Response::too_large(self.max_body);
+            self.send(Command::Respond(frame.stream, response, false))
+                .await;
+            self.send(Command::Reset(frame.stream, NO_ERROR)).await;
+            self.send(Command::Received(None, frame.payload.len()))
+                .await;
+            return Ok(());
+        }
+        request.body.extend_from_slice(data);
+
+        // The stream's window doesn't matter any more once it's done sending.
+        let stream = (!end_stream).then_some(frame.stream);
+        self.send(Command::Received(stream, frame.payload.len()))
+            .await;
+        if end_stream {
+            if let Some(request) = self.incoming.remove(&frame.stream) {
+                self.dispatch(frame.stream, request);
+            }
+        }
+        Ok(())
+    }
+
+    async fn headers(&mut self, headers: PartialHeaders) -> Result<(), Error> {
+        // Decoding has to happen even for streams we refuse, to keep the table in step.
+        let fields = self
+            .decoder
+            .decode(&headers.block, MAX_HEADER_LIST)
+            .map_err(|err| Error::Protocol(COMPRESSION_ERROR, err.to_string()))?;
+        let stream = headers.stream;
+
+        if self.incoming.contains_key(&stream) {
+            // Trailers. We have nowhere to put them, but they have to end the request.
+            if !headers.end_stream {
+                self.incoming.remove(&stream);
+                self.send(Command::Reset(stream, PROTOCOL_ERROR)).await;
+            } else if let Some(request) = self.incoming.remove(&stream) {
+                self.dispatch(stream, request);
+            }
+            return Ok(());
+        }
+        if stream <= self.last_stream {
+            return Err(Error::Protocol(
+                STREAM_CLOSED,
+                format!("HEADERS on closed stream {stream}"),
+            ));
+        }
+        self.last_stream = stream;
+
+        if self.open.load(Ordering::SeqCst) >= MAX_STREAMS {
+            self.send(Command::Reset(stream, REFUSED_STREAM)).await;
+            return Ok(());
+        }
+        let request = match build_request(fields) {
+            Ok(request) => request,
+            Err(message) => {
+                eprintln!("Bad HTTP/2 request: {message}");
+                self.send(Command::Reset(stream, PROTOCOL_ERROR)).await;
+                return Ok(());
+            }
+        };
+
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.send(Command::Open(stream)).await;
+        if headers.end_stream {
+            self.dispatch(stream, request);
+        } else {
+            self.incoming.insert(stream, request);
+        }
+        Ok(())
+    }
+
+    // Runs the handler alongside everything else on the connection.
+    fn dispatch(&mut self, stream: u32, request: Request) {
+        let head = request.method == Method::Head;
+        let response = (self.handle)(request);
+        let commands = self.commands.clone();
+        let task = self.executor.spawn(async move {
+            let mut response = response.await;
+            let Some(mut body) = response.stream.take() else {
+                let _ = commands
+                    .send(Command::Respond(stream, response, head))
+                    .await;
+                return;
+            };
+
+            let (drained, sent) = channel::bounded(1);
+            let _ = commands
+                .send(Command::Stream(stream, response, head, drained))
+                .await;
+            if head {
+                return;
+            }
+            while let Some(chunk) = body.next().await {
+                if commands
+                    .send(Command::Data(stream, Some(chunk)))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                // Fails once the stream is gone from the writer.
+                if sent.recv().await.is_err() {
+                    return;
+                }
+            }
+            let _ = commands.send(Command::Data(stream, None)).await;
+        });
+
+        self.tasks.retain(|_, task| !task.is_finished());
+        self.tasks.insert(stream, task);
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, Error> {
+    let mut head = [0; 9];
+    // A clean end of the connection is only expected between frames.
+    match reader.read(&mut head[..1]).await? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut head[1..]).await?,
+    }
+
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(Error::Protocol(
+            FRAME_SIZE_ERROR,
+            format!("frame of {len} bytes"),
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+// DATA and HEADERS frames may be padded to hide their size.
+fn unpad(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&pad, rest) = frame
+        .payload
+        .split_first()
+        .ok_or_else(|| protocol_error("missing pad length"))?;
+    rest.len()
+        .checked_sub(usize::from(pad))
+        .map(|len| &rest[..len])
+        .ok_or_else(|| protocol_error("too much padding"))
+}
+
+fn parse_settings(payload: &[u8]) -> Result<Vec<(u16, u32)>, Error> {
+    if !payload.len().is_multiple_of(6) {
+        return Err(Error::Protocol(
+            FRAME_SIZE_ERROR,
+            String::from("bad SETTINGS"),
+        ));
+    }
+    let mut settings = Vec::new();
+    for setting in payload.chunks(6) {
+        let id = u16::from_be_bytes([setting[0], setting[1]]);
+        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+        match id {
+            SETTINGS_INITIAL_WINDOW_SIZE if i64::from(value) > MAX_WINDOW => {
+                return Err(Error::Protocol(
+                    FLOW_CONTROL_ERROR,
+                    String::from("initial window too large"),
+                ));
+            }
+            SETTINGS_MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => {
+                return Err(protocol_error("bad max frame size"));
+            }
+            _ => settings.push((id, value)),
+        }
+    }
+    Ok(settings)
+}
+
+// Turns the decoded header fields into the same `Request` an HTTP/1.1 client would have given
+// us, so that handlers don't need to know which version they are serving.
+fn build_request(fields: Vec<(String, String)>) -> Result<Request, String> {
+    let (mut method, mut path, mut authority) = (None, None, None);
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    let mut regular = false;
+
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if regular {
+                return Err(format!("{name} after regular headers"));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                // Whether it's http or https is known from the connection.
+                "scheme" => continue,
+                _ => return Err(format!("unknown pseudo-header {name}")),
+            };
+            if slot.replace(value).is_some() {
+                return Err(format!("duplicate {name}"));
+            }
+            continue;
+        }
+
+        regular = true;
+        if name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return Err(format!("uppercase header name {name:?}"));
+        }
+        match name.as_str() {
+            // HTTP/2 has its own ways of doing what these do in HTTP/1.1.
+            "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" => {
+                return Err(format!("connection-specific header {name}"));
+            }
+            "te" if value != "trailers" => return Err(format!("te: {value}")),
+            // May be split up to compress better, but handlers expect it in one piece.
+            "cookie" => cookies.push(value),
+            _ => headers.append(&name, value),
+        }
+    }
+
+    let (Some(method), Some(path)) = (method, path) else {
+        return Err(String::from("missing :method or :path"));
+    };
+    let mut request = Request::new(Method::parse(&method), &path);
+    request.version = String::from("HTTP/2.0");
+    if !cookies.is_empty() {
+        headers.append("cookie", cookies.join("; "));
+    }
+    if let Some(authority) = authority {
+        if headers.get("Host").is_none() {
+            headers.append("host", authority);
+        }
+    }
+    request.headers = headers;
+    Ok(request)
+}
+
+// A response being sent, and how much the peer lets us send on its stream.
+struct Outgoing {
+    // Set once the handler is done and the headers have been sent.
+    body: Option<Vec<u8>>,
+    sent: usize,
+    // False while a streamed body may still grow.
+    complete: bool,
+    window: i64,
+    // Tells the handler streaming the body that everything so far has been sent.
+    drained: Option<Sender<()>>,
+}
+
+impl Outgoing {
+    fn signal_drained(&self) {
+        if let Some(drained) = &self.drained {
+            // Full if the handler hasn't picked up the last signal yet, which says the same.
+            let _ = drained.try_send(());
+        }
+    }
+}
+
+async fn write_loop<W>(
+    mut writer: W,
+    queue: Receiver<Command>,
+    open: Arc<AtomicUsize>,
+    write_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut state = WriteState {
+        out: Vec::new(),
+        streams: HashMap::new(),
+        window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame: DEFAULT_MAX_FRAME_SIZE,
+        open,
+    };
+    // Our settings have to come first. The defaults suit us apart from the limits.
+    let mut settings = Vec::new();
+    settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+    settings.extend_from_slice(&(MAX_STREAMS as u32).to_be_bytes());
+    settings.extend_from_slice(&SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+    settings.extend_from_slice(&(MAX_HEADER_LIST as u32).to_be_bytes());
+    state.frame(SETTINGS, 0, 0, &settings);
+
+    let mut finishing = None;
+    loop {
+        state.write_data();
+        if let Some(last_stream) = finishing {
+            if state.streams.is_empty() {
+                // If the peer is the one that said goodbye, it may not wait for ours.
+                state.go_away(last_stream, NO_ERROR);
+                let _ = writer.write_all(&state.out).await;
+                let _ = writer.close().await;
+                return Ok(());
+            }
+        }
+        if !state.out.is_empty() {
+            let write = async {
+                writer.write_all(&state.out).await?;
+                writer.flush().await
+            };
+            match write_timeout {
+                Some(duration) => timeout(duration, write)
+                    .await
+                    .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into()))?,
+                None => write.await?,
+            }
+            state.out.clear();
+        }
+        let Ok(command) = queue.recv().await else {
+            return Ok(());
+        };
+        match command {
+            Command::Open(stream) => {
+                let window = state.initial_window;
+                state.streams.insert(
+                    stream,
+                    Outgoing {
+                        body: None,
+                        sent: 0,
+                        complete: true,
+                        window,
+                        drained: None,
+                    },
+                );
+            }
+            Command::Respond(stream, response, head) => {
+                state.respond(stream, response, head, false)
+            }
+            Command::Stream(stream, response, head, drained) => {
+                state.respond(stream, response, head, true);
+                if let Some(outgoing) = state.streams.get_mut(&stream) {
+                    outgoing.drained = Some(drained);
+                }
+            }
+            Command::Data(stream, chunk) => {
+                if let Some(outgoing) = state.streams.get_mut(&stream) {
+                    match (&mut outgoing.body, chunk) {
+                        // There's nothing to wait for in an empty chunk.
+                        (Some(_), Some(chunk)) if chunk.is_empty() => outgoing.signal_drained(),
+                        (Some(body), Some(chunk)) => body.extend_from_slice(&chunk),
+                        (_, None) => outgoing.complete = true,
+                        (None, Some(_)) => {}
+                    }
+                }
+            }
+            Command::Received(stream, len) => {
+                if len > 0 {
+                    let increment = (len as u32).to_be_bytes();
+                    state.frame(WINDOW_UPDATE, 0, 0, &increment);
+                    if let Some(stream) = stream {
+                        state.frame(WINDOW_UPDATE, 0, stream, &increment);
+                    }
+                }
+            }
+            Command::WindowUpdate(0, increment) => {
+                state.window += i64::from(increment);
+                if state.window > MAX_WINDOW {
+                    state.go_away(0, FLOW_CONTROL_ERROR);
+                    writer.write_all(&state.out).await?;
+                    return writer.close().await;
+                }
+            }
+            Command::WindowUpdate(stream, increment) => {
+                if let Some(outgoing) = state.streams.get_mut(&stream) {
+                    outgoing.window += i64::from(increment);
+                    if outgoing.window > MAX_WINDOW {
+                        state.reset(stream, FLOW_CONTROL_ERROR);
+                    }
+                }
+            }
+            Command::Settings(settings) => {
+                for (id, value) in settings {
+                    match id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            // Applies to the streams already open too.
+                            let delta = i64::from(value) - state.initial_window;
+                            for outgoing in state.streams.values_mut() {
+                                outgoing.window += delta;
+                            }
+                            state.initial_window = i64::from(value);
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => state.max_frame = value as usize,
+                        _ => {}
+                    }
+                }
+                state.frame(SETTINGS, ACK, 0, &[]);
+            }
+            Command::Ping(data) => state.frame(PING, ACK, 0, &data),
+            Command::Reset(stream, code) => state.reset(stream, code),
+            Command::Cancelled(stream) => {
+                if state.streams.remove(&stream).is_some() {
+                    state.open.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+            Command::Finish(last_stream) => finishing = Some(last_stream),
+            Command::GoAway(last_stream, code) => {
+                state.go_away(last_stream, code);
+                writer.write_all(&state.out).await?;
+                return writer.close().await;
+            }
+            Command::Abort => return Ok(()),
+        }
+    }
+}
+
+struct WriteState {
+    // Frames to be written.
+    out: Vec<u8>,
+    streams: HashMap<u32, Outgoing>,
+    // The connection's send window, shared by all streams.
+    window: i64,
+    initial_window: i64,
+    max_frame: usize,
+    open: Arc<AtomicUsize>,
+}
+
+impl WriteState {
+    fn frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        self.out
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.out.extend_from_slice(&[kind, flags]);
+        self.out.extend_from_slice(&stream.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+
+    fn go_away(&mut self, last_stream: u32, code: u32) {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+    }
+
+    fn reset(&mut self, stream: u32, code: u32) {
+        if self.streams.remove(&stream).is_some() {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.frame(RST_STREAM, 0, stream, &code.to_be_bytes());
+    }
+
+    fn finish(&mut self, stream: u32) {
+        if self.streams.remove(&stream).is_some() {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Sends the headers right away; the body follows as the windows allow. A streamed body has no
+    // length, and the stream stays open until it ends.
+    fn respond(&mut self, stream: u32, mut response: Response, head: bool, streaming: bool) {
+        // Reset while the handler was running.
+        if !self.streams.contains_key(&stream) {
+            return;
+        }
+        // An HTTP/2 stream can't be handed over to another protocol the way an HTTP/1.1
+        // connection can, so the client has to ask again over HTTP/1.1.
+        if response.upgrade.is_some() {
+            response = Response::new(505).with_body("HTTP Version Not Supported");
+        }
+
+        let body = std::mem::take(&mut response.body);
+        let status = response.status.to_string();
+        // A proxied response to HEAD brings the length of the body it doesn't have.
+        let length = match response.headers.get("Content-Length") {
+            Some(length) if head => length.to_string(),
+            _ => body.len().to_string(),
+        };
+        // Names must be lowercase in HTTP/2, and connection-specific headers are not allowed.
+        let headers: Vec<(String, &str)> = response
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, _)| {
+                !matches!(
+                    name.as_str(),
+                    "connection"
+                        | "keep-alive"
+                        | "transfer-encoding"
+                        | "upgrade"
+                        | "content-length"
+                )
+            })
+            .collect();
+        let mut fields = vec![(":status", status.as_str())];
+        fields.extend(headers.iter().map(|(name, value)| (name.as_str(), *value)));
+        if response.status >= 200 && response.status != 204 && !streaming {
+            fields.push(("content-length", &length));
+        }
+        let block = hpack::encode(fields);
+
+        // Blocks that don't fit in one frame continue in CONTINUATION frames.
+        let end_stream = (body.is_empty() && !streaming) || head;
+        let chunks: Vec<&[u8]> = block.chunks(self.max_frame).collect();
+        for (ind, chunk) in chunks.iter().enumerate() {
+            let (kind, mut flags) = match ind {
+                0 if end_stream => (HEADERS, END_STREAM),
+                0 => (HEADERS, 0),
+                _ => (CONTINUATION, 0),
+            };
+            if ind == chunks.len() - 1 {
+                flags |= END_HEADERS;
+            }
+            self.frame(kind, flags, stream, chunk);
+        }
+
+        if end_stream {
+            self.finish(stream);
+        } else if let Some(outgoing) = self.streams.get_mut(&stream) {
+            outgoing.body = Some(body);
+            outgoing.complete = !streaming;
+        }
+    }
+
+    // Sends as much of the bodies as the windows allow, a frame per stream at a time so that
+    // one big response doesn't hold up the rest.
+    fn write_data(&mut self) {
+        let mut ready: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, outgoing)| outgoing.body.is_some())
+            .map(|(&stream, _)| stream)
+            .collect();
+        ready.sort_unstable();
+
+        while self.window > 0 && !ready.is_empty() {
+            let mut progress = false;
+            for &stream in &ready {
+                let Some(outgoing) = self.streams.get_mut(&stream) else {
+                    continue;
+                };
+                let Some(body) = &mut outgoing.body else {
+                    continue;
+                };
+                let remaining = body.len() - outgoing.sent;
+                let len = remaining
+                    .min(self.max_frame)
+                    .min(self.window.max(0) as usize)
+                    .min(outgoing.window.max(0) as usize);
+                let done = len == remaining && outgoing.complete;
+                // A streamed body may end with nothing left to send but END_STREAM.
+                if len == 0 && !done {
+                    continue;
+                }
+
+                let chunk = body[outgoing.sent..outgoing.sent + len].to_vec();
+                outgoing.sent += len;
+                outgoing.window -= len as i64;
+                // Streamed bodies would otherwise keep everything ever sent.
+                if outgoing.sent == body.len() {
+                    body.clear();
+                    outgoing.sent = 0;
+                    outgoing.signal_drained();
+                }
+                self.window -= len as i64;
+                self.frame(DATA, if done { END_STREAM } else { 0 }, stream, &chunk);
+                if done {
+                    self.finish(stream);
+                }
+                progress = true;
+            }
+            ready.retain(|stream| self.streams.contains_key(stream));
+            if !progress {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    http::{Request, Response},
+    middleware::{Middleware, Next},
+    router::BoxFuture,
+};
+
+// How often buckets that have filled up again are forgotten, so that the map doesn't keep an
+// entry for every client ever seen.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Token-bucket rate limiting per client IP: every IP starts with `burst` tokens, each request
+// takes one and they refill at `per_second`. Requests with no tokens left get a 429 with a
+// `Retry-After` saying when the next token will be there.
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> RateLimit {
+        assert!(per_second > 0.0, "rate must be positive");
+        RateLimit {
+            per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes a token for `ip`, or says how long until one is available.
+    fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            // A full bucket behaves exactly like a missing one.
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.by_ip.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * per_second < burst
+            });
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        // Requests that didn't come from a socket (e.g. in tests) aren't limited.
+        let Some(peer_addr) = request.peer_addr else {
+            return next.run(request);
+        };
+
+        match self.check(peer_addr.ip(), Instant::now()) {
+            Ok(()) => next.run(request),
+            Err(wait) => {
+                let response = too_many(wait);
+                Box::pin(async move { response })
+            }
+        }
+    }
+}
+
+// `Retry-After` is in whole seconds, so round up: retrying early would just be refused again.
+fn too_many(wait: Duration) -> Response {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::new(429)
+        .with_header("Retry-After", seconds.max(1).to_string())
+        .with_body("Too Many Requests")
+}
+
+// Caps the number of open connections, both in total and from any single IP.
+// The server checks it as soon as a connection is accepted and turns the connection away right
+// there, before it gets to take up a worker.
+pub struct ConnectionLimit {
+    total: usize,
+    per_ip: usize,
+    open: Mutex<OpenConnections>,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(total: usize, per_ip: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            total,
+            per_ip,
+            open: Mutex::default(),
+        }
+    }
+
+    // Counts a new connection from `ip` until the returned permit is dropped, or returns the
+    // response to send if that would go over a limit: 429 if this client has too many
+    // connections open, 503 if the server as a whole does.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, Response> {
+        let mut open = self.open.lock().unwrap();
+        let from_ip = open.by_ip.get(&ip).copied().unwrap_or(0);
+
+        if from_ip >= self.per_ip {
+            return Err(too_many(Duration::from_secs(1)));
+        }
+        if open.total >= self.total {
+            return Err(Response::new(503)
+                .with_header("Retry-After", "1")
+                .with_body("Service Unavailable"));
+        }
+
+        open.total += 1;
+        open.by_ip.insert(ip, from_ip + 1);
+        Ok(ConnectionPermit {
+            limit: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+// Counts as an open connection until dropped.
+pub struct ConnectionPermit {
+    limit: Arc<ConnectionLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_bursts_then_refill() {
+        let limit = RateLimit::new(2.0, 3);
+        let (ip, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limit.check(ip, start), Ok(()));
+        }
+        assert_eq!(limit.check(ip, start), Err(Duration::from_millis(500)));
+        // Other clients have their own bucket.
+        assert_eq!(limit.check(other, start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limit.check(ip, later), Ok(()));
+        assert!(limit.check(ip, later).is_err());
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limit = RateLimit::new(1.0, 1);
+        let start = Instant::now();
+        limit.check("10.0.0.1".parse().unwrap(), start).unwrap();
+
+        limit
+            .check("10.0.0.2".parse().unwrap(), start + PRUNE_INTERVAL)
+            .unwrap();
+        assert_eq!(limit.buckets.lock().unwrap().by_ip.len(), 1);
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let response = too_many(Duration::from_millis(1200));
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers.get("Retry-After"), Some("2"));
+    }
+
+    #[test]
+    fn connection_limits_apply_per_ip_and_in_total() {
+        let limit = Arc::new(ConnectionLimit::new(3, 2));
+        let (ip, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let first = limit.acquire(ip).unwrap();
+        let _second = limit.acquire(ip).unwrap();
+        assert_eq!(limit.acquire(ip).err().unwrap().status, 429);
+
+        let _third = limit.acquire(other).unwrap();
+        assert_eq!(limit.acquire(other).err().unwrap().status, 503);
+
+        drop(first);
+        assert!(limit.acquire(ip).is_ok());
+    }
+}
@@ -1,46 +1,169 @@
-use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
-    thread,
-    time::Duration,
+use std::{env, net::TcpListener, path::PathBuf, process, sync::Arc, thread, time::Duration};
+
+use smol::Timer;
+use web_server::{
+    compression::Compress,
+    config::{Backend, Config, ConfigError, ServerConfig},
+    metrics::{Metrics, MetricsEndpoint},
+    middleware::RequestId,
+    server::Reloadable,
+    sse::{self, Event},
+    tls,
+    websocket::{self, Message},
+    Response, Server, ThreadPool,
 };
-use web_server::ThreadPool;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    // `--config <path>` picks the config file, otherwise `server.toml` in the working directory.
+    let args: Vec<String> = env::args().collect();
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(ind) => match args.get(ind + 1) {
+            Some(path) => PathBuf::from(path),
+            None => {
+                eprintln!("Usage: web_server [--config <server.toml>]");
+                process::exit(2);
+            }
+        },
+        None => PathBuf::from("server.toml"),
+    };
+
+    let config = Config::load(&path).unwrap_or_else(|err| {
+        eprintln!("Failed to load {}: {err}", path.display());
+        process::exit(1);
+    });
+    let metrics = Arc::new(Metrics::new());
+    let server = build(&config, &metrics).unwrap_or_else(|err| {
+        eprintln!("Failed to load {}: {err}", path.display());
+        process::exit(1);
+    });
+    let server = Reloadable::new(server);
 
-    // Only take two requests, then quit
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    #[cfg(unix)]
+    reload_on_sighup(path, config.clone(), server.clone(), metrics);
 
-        // TODO: Use async instead of threads
-        pool.execute(|| {
-            handle_connection(stream);
+    if let Some(redirect_from) = config.tls.as_ref().and_then(|tls| tls.redirect_from) {
+        let https_port = config.server.bind[0].port();
+        let redirect = TcpListener::bind(redirect_from).unwrap();
+        thread::spawn(move || {
+            Server::new(tls::HttpsRedirect::new(https_port))
+                .serve_async(redirect, 0)
+                .unwrap();
         });
     }
 
+    let listeners = config
+        .server
+        .bind
+        .iter()
+        .map(|addr| TcpListener::bind(addr).unwrap())
+        .collect();
+    serve(&server, listeners, &config.server);
+
     println!("Shutting down.");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
+// Adds the routes that need code to the ones from the config.
+fn build(config: &Config, metrics: &Arc<Metrics>) -> Result<Server, ConfigError> {
+    let mut router = config
+        .router()?
+        .get("/sleep", |_| async {
+            // Not `thread::sleep`: awaiting a timer lets the async backend serve other
+            // connections in the meantime.
+            Timer::after(Duration::from_secs(5)).await;
+            Response::html(200, "<h1>Slept for 5 seconds</h1>")
+        })
+        .get("/events", |_| async {
+            // A tick a second, for as long as the client listens.
+            sse::stream(|events| async move {
+                let mut tick = 0;
+                while events.send(Event::new(tick.to_string())).await.is_ok() {
+                    tick += 1;
+                    Timer::after(Duration::from_secs(1)).await;
+                }
+            })
+        })
+        .get("/ws/echo", |request| async move {
+            websocket::upgrade(&request, |mut socket| async move {
+                while let Ok(Some(message)) = socket.recv().await {
+                    if let Message::Text(_) | Message::Binary(_) = message {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+        });
+    if let Some(path) = &config.log.metrics {
+        router = router.get(path, MetricsEndpoint::new(Arc::clone(metrics)));
+    }
+
+    // Only the default host gets the routes above; `[[host]]` sites are all config.
+    Ok(config
+        .server(Compress::new(config.virtual_hosts(router)?))?
+        .with_middleware(RequestId::new())
+        .with_metrics(Arc::clone(metrics)))
+}
+
+// `kill -HUP <pid>` reloads the config file. The listeners stay open and connections in flight
+// finish on the old config. A config that doesn't load is reported and the old one kept.
+#[cfg(unix)]
+fn reload_on_sighup(path: PathBuf, started: Config, server: Reloadable, metrics: Arc<Metrics>) {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
 
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            eprintln!("Can't reload on SIGHUP: {err}");
+            return;
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let reloaded = Config::load(&path).and_then(|config| {
+                let new_server = build(&config, &metrics)?;
+                Ok((config, new_server))
+            });
+            match reloaded {
+                Ok((config, new_server)) => {
+                    if started.needs_restart(&config) {
+                        eprintln!("Changes to [server] and tls.redirect_from need a restart");
+                    }
+                    server.replace(new_server);
+                    println!("Reloaded {}", path.display());
+                }
+                Err(err) => eprintln!("Not reloading {}: {err}", path.display()),
+            }
+        }
+    });
+}
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+// Every listener gets its own accept loop. The threaded backend shares one pool between them,
+// while the async backend runs an executor per listener.
+fn serve(server: &Reloadable, listeners: Vec<TcpListener>, config: &ServerConfig) {
+    let pool = (config.backend == Backend::Threaded).then(|| {
+        ThreadPool::builder()
+            .workers(config.workers)
+            .name_prefix("http")
+            .build()
+            .unwrap_or_else(|err| {
+                eprintln!("Cannot start the worker pool: {err}");
+                process::exit(1);
+            })
+    });
 
-    stream.write_all(response.as_bytes()).unwrap();
+    thread::scope(|scope| {
+        for listener in listeners {
+            let pool = pool.as_ref();
+            scope.spawn(move || {
+                let result = match pool {
+                    Some(pool) => server.serve_threaded(listener, pool),
+                    None => server.serve_async(listener, config.workers),
+                };
+                if let Err(err) = result {
+                    eprintln!("Stopped accepting connections: {err}");
+                }
+            });
+        }
+    });
 }
@@ -0,0 +1,175 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    http::{Request, Response},
+    router::{BoxFuture, Handler},
+    QueueDepth,
+};
+
+// Upper bounds (in seconds) of the latency histogram buckets. `+Inf` is implied.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+// Counters shared by every connection, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests_by_status: Mutex<BTreeMap<u16, u64>>,
+    // Not cumulative: each request is counted in the first bucket it fits in.
+    // The running totals Prometheus expects are computed when rendering.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    active_connections: AtomicI64,
+    queue_depth: Mutex<Option<QueueDepth>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_request(&self, status: u16, latency: Duration) {
+        *self
+            .requests_by_status
+            .lock()
+            .unwrap()
+            .entry(status)
+            .or_insert(0) += 1;
+
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Counts the connection as active until the returned guard is dropped.
+    pub fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    // Reports the queue of the pool that the threaded backend submits connections to.
+    pub fn track_queue(&self, queue_depth: QueueDepth) {
+        *self.queue_depth.lock().unwrap() = Some(queue_depth);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests served, by response status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for (status, count) in self.requests_by_status.lock().unwrap().iter() {
+            writeln!(out, "http_requests_total{{status=\"{status}\"}} {count}").unwrap();
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time from reading a request to writing its response.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        cumulative += self.latency_buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {cumulative}"
+        )
+        .unwrap();
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "http_request_duration_seconds_sum {sum}").unwrap();
+        writeln!(out, "http_request_duration_seconds_count {cumulative}").unwrap();
+
+        out.push_str("# HELP http_active_connections Connections currently open.\n");
+        out.push_str("# TYPE http_active_connections gauge\n");
+        writeln!(
+            out,
+            "http_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        if let Some(queue_depth) = self.queue_depth.lock().unwrap().as_ref() {
+            out.push_str("# HELP thread_pool_queue_depth Jobs waiting for a ThreadPool worker.\n");
+            out.push_str("# TYPE thread_pool_queue_depth gauge\n");
+            writeln!(out, "thread_pool_queue_depth {}", queue_depth.get()).unwrap();
+        }
+
+        out
+    }
+}
+
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Handler for the `/metrics` route.
+pub struct MetricsEndpoint(Arc<Metrics>);
+
+impl MetricsEndpoint {
+    pub fn new(metrics: Arc<Metrics>) -> MetricsEndpoint {
+        MetricsEndpoint(metrics)
+    }
+}
+
+impl Handler for MetricsEndpoint {
+    fn call(&self, _request: Request) -> BoxFuture<Response> {
+        let body = self.0.render();
+        Box::pin(async move {
+            Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_body(body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_cumulative_histogram() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.record_request(200, Duration::from_millis(2));
+        metrics.record_request(200, Duration::from_millis(30));
+        metrics.record_request(404, Duration::from_secs(10));
+        let _connection = metrics.track_connection();
+
+        let text = metrics.render();
+
+        assert!(text.contains("http_requests_total{status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("http_request_duration_seconds_count 3\n"));
+        assert!(text.contains("http_active_connections 1\n"));
+    }
+
+    #[test]
+    fn connection_guard_decrements() {
+        let metrics = Arc::new(Metrics::new());
+        drop(metrics.track_connection());
+        assert!(metrics.render().contains("http_active_connections 0\n"));
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    http::{Method, Request, Response},
+    router::{BoxFuture, Handler},
+};
+
+// Wraps every request that goes through a router or server.
+// A middleware either answers the request itself (short-circuiting the rest of the chain), or
+// calls `next.run(request)` and can then inspect or change the response on its way out.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response>;
+}
+
+// Lets plain closures such as `|req, next: Next| async move { next.run(req).await }` be used as
+// middleware.
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        Box::pin(self(request, next))
+    }
+}
+
+pub type Stack = Arc<Vec<Arc<dyn Middleware>>>;
+
+// The rest of the chain: the middleware after the current one, then the endpoint handler.
+// It owns everything it needs so that middleware can move it into their futures.
+pub struct Next {
+    stack: Stack,
+    index: usize,
+    endpoint: Arc<dyn Handler>,
+}
+
+impl Next {
+    pub fn new(stack: Stack, endpoint: Arc<dyn Handler>) -> Next {
+        Next {
+            stack,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    pub fn run(mut self, request: Request) -> BoxFuture<Response> {
+        match self.stack.get(self.index) {
+            Some(middleware) => {
+                let middleware = Arc::clone(middleware);
+                self.index += 1;
+                middleware.handle(request, self)
+            }
+            None => self.endpoint.call(request),
+        }
+    }
+}
+
+// Tags every request with an `X-Request-Id` (keeping one the client or a proxy already set)
+// and echoes it on the response so that both sides can correlate logs.
+pub struct RequestId {
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+
+    pub fn new() -> RequestId {
+        // Different for every run so that ids from restarts don't collide.
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or(0);
+        RequestId {
+            prefix: format!("{started:x}-{:x}", std::process::id()),
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut request: Request, next: Next) -> BoxFuture<Response> {
+        let id = match request.header(Self::HEADER) {
+            Some(id) => id.to_string(),
+            None => {
+                let count = self.counter.fetch_add(1, Ordering::Relaxed);
+                let id = format!("{}-{count}", self.prefix);
+                request.headers.insert(Self::HEADER, id.as_str());
+                id
+            }
+        };
+
+        Box::pin(async move { next.run(request).await.with_header(Self::HEADER, id) })
+    }
+}
+
+// Answers CORS preflight requests and adds `Access-Control-Allow-Origin` to responses for the
+// configured origins. Requests from other origins pass through untouched; the browser blocks them.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    max_age: u32,
+}
+
+impl Cors {
+    // Use "*" to allow any origin.
+    pub fn new(origins: &[&str]) -> Cors {
+        Cors {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: String::from("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"),
+            headers: String::from("Content-Type, Authorization"),
+            max_age: 600,
+        }
+    }
+
+    pub fn allow_methods(mut self, methods: &str) -> Cors {
+        self.methods = methods.to_string();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &str) -> Cors {
+        self.headers = headers.to_string();
+        self
+    }
+
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some(String::from("*"))
+        } else if self.origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: Request, next: Next) -> BoxFuture<Response> {
+        let Some(origin) = request
+            .header("Origin")
+            .and_then(|origin| self.allowed_origin(origin))
+        else {
+            return next.run(request);
+        };
+
+        if request.method == Method::Options
+            && request.header("Access-Control-Request-Method").is_some()
+        {
+            let mut response = Response::new(204)
+                .with_header("Access-Control-Allow-Origin", origin)
+                .with_header("Access-Control-Allow-Methods", self.methods.as_str())
+                .with_header("Access-Control-Allow-Headers", self.headers.as_str())
+                .with_header("Access-Control-Max-Age", self.max_age.to_string());
+            response.add_vary("Origin");
+            return Box::pin(async move { response });
+        }
+
+        Box::pin(async move {
+            let mut response = next
+                .run(request)
+                .await
+                .with_header("Access-Control-Allow-Origin", origin);
+            response.add_vary("Origin");
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn run(stack: Vec<Arc<dyn Middleware>>, request: Request) -> Response {
+        let endpoint: Arc<dyn Handler> = Arc::new(|_| async { Response::new(200) });
+        smol::block_on(Next::new(Arc::new(stack), endpoint).run(request))
+    }
+
+    #[test]
+    fn runs_in_order_and_unwinds_in_reverse() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let tracer = |name: &'static str| -> Arc<dyn Middleware> {
+            let calls = Arc::clone(&calls);
+            Arc::new(move |request, next: Next| {
+                let calls = Arc::clone(&calls);
+                async move {
+                    calls.lock().unwrap().push(format!("{name} in"));
+                    let response = next.run(request).await;
+                    calls.lock().unwrap().push(format!("{name} out"));
+                    response
+                }
+            })
+        };
+
+        run(
+            vec![tracer("a"), tracer("b")],
+            Request::new(Method::Get, "/"),
+        );
+
+        assert_eq!(*calls.lock().unwrap(), ["a in", "b in", "b out", "a out"]);
+    }
+
+    #[test]
+    fn can_short_circuit() {
+        let deny: Arc<dyn Middleware> = Arc::new(|request: Request, next: Next| async move {
+            match request.header("Authorization") {
+                Some("Bearer secret") => next.run(request).await,
+                _ => Response::new(401),
+            }
+        });
+
+        assert_eq!(
+            run(vec![Arc::clone(&deny)], Request::new(Method::Get, "/")).status,
+            401
+        );
+
+        let mut request = Request::new(Method::Get, "/");
+        request.headers.insert("Authorization", "Bearer secret");
+        assert_eq!(run(vec![deny], request).status, 200);
+    }
+
+    #[test]
+    fn request_id_is_generated_or_kept() {
+        let stack: Vec<Arc<dyn Middleware>> = vec![Arc::new(RequestId::new())];
+        let response = run(stack.clone(), Request::new(Method::Get, "/"));
+        assert!(response
+            .headers
+            .get(RequestId::HEADER)
+            .unwrap()
+            .ends_with("-0"));
+
+        let mut request = Request::new(Method::Get, "/");
+        request.headers.insert(RequestId::HEADER, "abc");
+        let response = run(stack, request);
+        assert_eq!(response.headers.get(RequestId::HEADER), Some("abc"));
+    }
+
+    #[test]
+    fn cors_answers_preflight() {
+        let stack: Vec<Arc<dyn Middleware>> = vec![Arc::new(Cors::new(&["https://app.example"]))];
+
+        let mut request = Request::new(Method::Options, "/api");
+        request.headers.insert("Origin", "https://app.example");
+        request
+            .headers
+            .insert("Access-Control-Request-Method", "POST");
+        let response = run(stack.clone(), request);
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+
+        let mut request = Request::new(Method::Get, "/api");
+        request.headers.insert("Origin", "https://evil.example");
+        let response = run(stack, request);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), None);
+    }
+}
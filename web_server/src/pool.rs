@@ -0,0 +1,1658 @@
+use std::{
+    any::Any,
+    cell::Cell,
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{select, Receiver, SendTimeoutError, Sender, TrySendError};
+
+mod cancel;
+mod handle;
+mod lanes;
+mod par;
+mod scope;
+mod stealing;
+
+pub use cancel::CancellationToken;
+pub use handle::{JobHandle, JoinError};
+pub use lanes::Priority;
+pub use par::{ParallelIterator, ParallelSlice};
+pub use scope::Scope;
+
+use handle::{job_handle, panic_message};
+use lanes::Lanes;
+use stealing::Queues;
+
+// Must use Box<dyn ...> to accept any closures
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// What the workers' queues hold. Jobs from outside the pool wait in the lanes, and the queues
+// only get a ticket for whichever of them is most urgent by the time a worker gets to it.
+enum Task {
+    Job(Job),
+    Ticket,
+}
+
+// Where `execute` and `spawn` put their jobs, which is always the first of the lanes.
+const DEFAULT_QUEUE: &str = "default";
+
+type PanicHandler = Box<dyn Fn(Box<dyn Any + Send>) + Send + Sync>;
+
+thread_local! {
+    // The pool and slot of the worker running on this thread, if it's a worker.
+    static WORKER: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    // Need an optional here since we need to explicitly take ownership of it during drop.
+    // Only there with `Scheduler::SharedQueue`.
+    sender: Option<crossbeam_channel::Sender<Task>>,
+}
+
+// How jobs get from `execute` to the workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    // One queue that every worker takes jobs from, in the order they were submitted, unless
+    // their `Priority` says otherwise. A job that a job submits waits behind everything that was
+    // submitted before it.
+    #[default]
+    SharedQueue,
+    // Every worker gets a queue of its own for the jobs it submits, and runs those first, newest
+    // first. That suits jobs that split their work into more jobs: the pieces stay on the worker
+    // (and in its cache) unless another worker runs out of jobs and steals them. Jobs submitted
+    // from outside the pool go to a shared queue, which workers check before stealing.
+    WorkStealing,
+}
+
+// Everything a worker needs, shared so that a dying worker can start its own replacement, and
+// so that workers can be added from wherever jobs are submitted.
+struct Shared {
+    queue: Queue,
+    // Where jobs from outside the pool wait for a worker, in priority order.
+    lanes: Lanes,
+    // Jobs submitted but not yet picked up by a worker, including a submitter blocked on `send`.
+    queued: Arc<AtomicUsize>,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    panic_handler: Option<PanicHandler>,
+    // Set by `shutdown`, after which no more jobs are taken.
+    shutting_down: AtomicBool,
+    // Cancelled by `shutdown_now`, after which queued jobs are dropped instead of run.
+    cancel: CancellationToken,
+    // One slot per worker. A replacement takes over its predecessor's slot, and a retired
+    // worker's slot goes to the next worker that is started.
+    workers: Mutex<Vec<Option<thread::JoinHandle<()>>>>,
+    // Bounds on `threads`, which `resize` may change.
+    min_threads: AtomicUsize,
+    max_threads: AtomicUsize,
+    keep_alive: Duration,
+    // Workers that are running, and how many of them are running a job.
+    threads: AtomicUsize,
+    busy: AtomicUsize,
+    // Asks idle workers of a shared queue to check whether they are surplus, since there's no
+    // other way to wake them up. Work-stealing workers are woken up through their queues.
+    nudges: (Sender<()>, Receiver<()>),
+}
+
+enum Queue {
+    Shared(crossbeam_channel::Receiver<Task>),
+    Stealing(Box<Queues>),
+}
+
+// A cheap, cloneable view of how many jobs are waiting for a worker.
+#[derive(Clone)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// What the workers are up to at the moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub threads: usize,
+    pub idle: usize,
+    pub busy: usize,
+    // The same as `ThreadPool::queue_depth`.
+    pub queued: usize,
+}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    // A pool needs at least one worker to ever run anything.
+    NoWorkers,
+    // `min_threads` has to be at most `max_threads`.
+    MinAboveMax { min: usize, max: usize },
+    // The OS refused to start a worker thread, e.g. because of a thread limit.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::NoWorkers => f.write_str("a thread pool needs at least one worker"),
+            PoolCreationError::MinAboveMax { min, max } => {
+                write!(f, "min_threads ({min}) is above max_threads ({max})")
+            }
+            PoolCreationError::Spawn(err) => write!(f, "failed to spawn a worker thread: {err}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::NoWorkers | PoolCreationError::MinAboveMax { .. } => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+// Why `try_execute` or `execute_timeout` turned a job away. The job is dropped without running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    // Every worker is busy and the queue has no room, and didn't get any in time.
+    Full,
+    // `shutdown` was called.
+    ShuttingDown,
+    // The pool was built without a queue of that name.
+    UnknownQueue,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Full => f.write_str("the thread pool's queue is full"),
+            ExecuteError::ShuttingDown => f.write_str("the thread pool is shutting down"),
+            ExecuteError::UnknownQueue => f.write_str("the thread pool has no such queue"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+// Configures a pool before any of its threads are started:
+//
+//     let pool = ThreadPool::builder()
+//         .workers(8)
+//         .name_prefix("http")
+//         .queue_capacity(64)
+//         .build()?;
+pub struct ThreadPoolBuilder {
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    queue_capacity: usize,
+    // Named queues and their weights, starting with the default one.
+    queues: Vec<(String, u32)>,
+    scheduler: Scheduler,
+    panic_handler: Option<PanicHandler>,
+}
+
+impl ThreadPoolBuilder {
+    // A pool of a fixed size: sets both `min_threads` and `max_threads`. Defaults to the number
+    // of CPUs, or 1 if that's unknown.
+    pub fn workers(mut self, workers: usize) -> ThreadPoolBuilder {
+        self.min_threads = workers;
+        self.max_threads = workers;
+        self
+    }
+
+    // Workers that are started right away and kept even when there's nothing to do.
+    pub fn min_threads(mut self, threads: usize) -> ThreadPoolBuilder {
+        self.min_threads = threads;
+        self
+    }
+
+    // While jobs are waiting and every worker is busy, more workers are started, up to this many.
+    pub fn max_threads(mut self, threads: usize) -> ThreadPoolBuilder {
+        self.max_threads = threads;
+        self
+    }
+
+    // How long a worker above `min_threads` waits for a job before it stops. Defaults to a minute.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Workers are named `{prefix}-0`, `{prefix}-1` and so on, which shows up in panic messages
+    // and debuggers. Defaults to "worker".
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    // In bytes. Defaults to whatever `std::thread` picks, currently 2 MiB.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    // Jobs that can wait for a worker before `execute` blocks. With 0, every `execute` waits
+    // until a worker takes the job. Defaults to 1.
+    //
+    // With `Scheduler::WorkStealing` this only limits jobs submitted from outside the pool, and 0
+    // acts like 1. Jobs that the workers submit themselves never wait.
+    pub fn queue_capacity(mut self, jobs: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = jobs;
+        self
+    }
+
+    // Adds a named queue for `execute_with` and `spawn_with`, or changes the weight of one, such
+    // as the "default" queue that `execute` and `spawn` use, whose weight is 1. While several
+    // queues have jobs waiting, each gets a share of the workers in proportion to its weight, so
+    // that a flood of jobs in one of them doesn't hold up the others. A weight of 0 acts like 1.
+    //
+    //     let pool = ThreadPool::builder()
+    //         .queue("uploads", 1)
+    //         .queue("pages", 4)
+    //         .build()?;
+    pub fn queue(mut self, name: impl Into<String>, weight: u32) -> ThreadPoolBuilder {
+        let name = name.into();
+        match self.queues.iter_mut().find(|(queue, _)| *queue == name) {
+            Some((_, old)) => *old = weight,
+            None => self.queues.push((name, weight)),
+        }
+        self
+    }
+
+    // Defaults to `Scheduler::SharedQueue`.
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    // Called with the payload of every job that panics, on the worker that ran it. The worker
+    // carries on with the next job either way. Without a handler, the panic is only reported by
+    // the standard panic hook, which prints it to stderr.
+    pub fn panic_handler<H>(mut self, handler: H) -> ThreadPoolBuilder
+    where
+        H: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Box::new(handler));
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        check_size(self.min_threads, self.max_threads)?;
+
+        let (sender, queue) = match self.scheduler {
+            Scheduler::SharedQueue => {
+                // Make blocking channel receive with specified number of threads.
+                // Alternatively, use a mpmc so that the rx can be cloned.
+                let (tx, rx) = crossbeam_channel::bounded(self.queue_capacity);
+                (Some(tx), Queue::Shared(rx))
+            }
+            Scheduler::WorkStealing => (
+                None,
+                Queue::Stealing(Box::new(Queues::new(self.queue_capacity))),
+            ),
+        };
+        let shared = Arc::new(Shared {
+            queue,
+            lanes: Lanes::new(&self.queues),
+            queued: Arc::new(AtomicUsize::new(0)),
+            name_prefix: self.name_prefix,
+            stack_size: self.stack_size,
+            panic_handler: self.panic_handler,
+            shutting_down: AtomicBool::new(false),
+            cancel: CancellationToken::new(),
+            workers: Mutex::new(Vec::new()),
+            min_threads: AtomicUsize::new(self.min_threads),
+            max_threads: AtomicUsize::new(self.max_threads),
+            keep_alive: self.keep_alive,
+            threads: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            nudges: crossbeam_channel::unbounded(),
+        });
+        // If a worker can't be started, dropping the pool shuts down the ones that were.
+        let pool = ThreadPool { shared, sender };
+
+        for _ in 0..self.min_threads {
+            pool.shared.add_worker().map_err(PoolCreationError::Spawn)?;
+        }
+
+        Ok(pool)
+    }
+}
+
+fn check_size(min_threads: usize, max_threads: usize) -> Result<(), PoolCreationError> {
+    if max_threads == 0 {
+        Err(PoolCreationError::NoWorkers)
+    } else if min_threads > max_threads {
+        Err(PoolCreationError::MinAboveMax {
+            min: min_threads,
+            max: max_threads,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+impl Shared {
+    // Starts another worker in the first free slot, unless there are `max_threads` already.
+    fn add_worker(self: &Arc<Self>) -> io::Result<bool> {
+        let mut workers = self.workers.lock().unwrap();
+        if self.threads.load(Ordering::SeqCst) >= self.max_threads.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        let ind = match workers.iter().position(Option::is_none) {
+            Some(ind) => ind,
+            None => {
+                workers.push(None);
+                if let Queue::Stealing(queues) = &self.queue {
+                    queues.add_deque();
+                }
+                workers.len() - 1
+            }
+        };
+        workers[ind] = Some(spawn_worker(self, ind)?);
+        self.threads.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    // A job was just submitted. If there are more jobs waiting than idle workers to take them,
+    // the queue is backing up and another worker can help.
+    fn grow(self: &Arc<Self>) {
+        let outstanding = self.queued.load(Ordering::SeqCst) + self.busy.load(Ordering::SeqCst);
+        let threads = self.threads.load(Ordering::SeqCst);
+        if outstanding > threads && threads < self.max_threads.load(Ordering::SeqCst) {
+            if let Err(err) = self.add_worker() {
+                eprintln!("Could not add a worker: {err}");
+            }
+        }
+    }
+
+    fn is_worker(&self) -> bool {
+        matches!(WORKER.get(), Some((shared, _)) if ptr::eq(shared, self))
+    }
+
+    // Runs a queued job, if this thread is one of our workers and there's a job. For a worker
+    // that's waiting on other jobs, which would never run if every worker did the same.
+    fn help(&self) -> bool {
+        let Some((shared, ind)) = WORKER.get() else {
+            return false;
+        };
+        if !ptr::eq(shared, self) {
+            return false;
+        }
+        let task = match &self.queue {
+            Queue::Shared(receiver) => receiver.try_recv().ok(),
+            Queue::Stealing(queues) => queues.find_job(ind),
+        };
+        match task {
+            Some(task) => {
+                run(self, task, ind);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Whether a worker should stop, in which case it's no longer counted. Any worker over
+    // `max_threads` should, and once it has been idle for `keep_alive`, any over `min_threads`.
+    fn retire(&self, idle: bool) -> bool {
+        let keep = match idle {
+            true => self.min_threads.load(Ordering::SeqCst),
+            false => self.max_threads.load(Ordering::SeqCst),
+        };
+        self.threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| {
+                (threads > keep).then(|| threads - 1)
+            })
+            .is_ok()
+    }
+}
+
+fn spawn_worker(shared: &Arc<Shared>, ind: usize) -> io::Result<thread::JoinHandle<()>> {
+    let mut builder = thread::Builder::new().name(format!("{}-{ind}", shared.name_prefix));
+    if let Some(stack_size) = shared.stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+
+    let shared = Arc::clone(shared);
+    builder.spawn(move || {
+        WORKER.set(Some((Arc::as_ptr(&shared), ind)));
+        let _sentinel = Sentinel {
+            shared: &shared,
+            ind,
+        };
+        if work(&shared, ind) {
+            // Frees the slot for the next worker. Dropping our own handle detaches us, as
+            // there's nothing left to wait for.
+            shared.workers.lock().unwrap()[ind] = None;
+            println!("Thread {ind} is no longer needed; shutting down.");
+        }
+    })
+}
+
+// A worker's main loop. Returns whether the worker retired, rather than the pool shutting down.
+fn work(shared: &Shared, ind: usize) -> bool {
+    match &shared.queue {
+        Queue::Shared(receiver) => loop {
+            select! {
+                recv(receiver) -> msg => match msg {
+                    Ok(job) => {
+                        run(shared, job, ind);
+                        if shared.retire(false) {
+                            return true;
+                        }
+                    }
+                    Err(_) => {
+                        println!("Thread {ind} disconnected; shutting down.");
+                        return false;
+                    }
+                },
+                recv(shared.nudges.1) -> _ => {
+                    if shared.retire(false) {
+                        return true;
+                    }
+                }
+                default(shared.keep_alive) => {
+                    if shared.retire(true) {
+                        return true;
+                    }
+                }
+            }
+        },
+        Queue::Stealing(queues) => {
+            let retired = queues.work(
+                ind,
+                shared.keep_alive,
+                |job| run(shared, job, ind),
+                |idle| shared.retire(idle),
+            );
+            if !retired {
+                println!("Thread {ind} ran out of jobs; shutting down.");
+            }
+            retired
+        }
+    }
+}
+
+fn run(shared: &Shared, task: Task, ind: usize) {
+    // Busy first, so that the job is always counted as one or the other.
+    shared.busy.fetch_add(1, Ordering::SeqCst);
+    shared.queued.fetch_sub(1, Ordering::SeqCst);
+    // A panicking job shouldn't take the worker down with it. Whatever state the
+    // job shared with others is its own business; the worker keeps nothing of it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let job = match task {
+            Task::Job(job) => job,
+            Task::Ticket => shared.lanes.next_job(),
+        };
+        if !shared.cancel.is_cancelled() {
+            job();
+        }
+    }));
+    shared.busy.fetch_sub(1, Ordering::SeqCst);
+    if let Err(payload) = result {
+        match &shared.panic_handler {
+            Some(handler) => handler(payload),
+            None => println!("Thread {ind} caught a panicking job; carrying on."),
+        }
+    }
+}
+
+// Lives on a worker's stack. If the worker dies anyway, e.g. because the panic handler
+// panicked, it starts a replacement so that the pool keeps its size.
+struct Sentinel<'a> {
+    shared: &'a Arc<Shared>,
+    ind: usize,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        // The replacement is in place before this thread exits, so `ThreadPool::drop` finds it
+        // right after joining this one.
+        match spawn_worker(self.shared, self.ind) {
+            Ok(handle) => self.shared.workers.lock().unwrap()[self.ind] = Some(handle),
+            Err(err) => {
+                self.shared.threads.fetch_sub(1, Ordering::SeqCst);
+                eprintln!("Could not replace thread {}: {err}", self.ind);
+            }
+        }
+    }
+}
+
+impl ThreadPool {
+    // Panics if `size` is 0 or a thread can't be started; use `builder` to handle that instead.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::builder()
+            .workers(size)
+            .build()
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn builder() -> ThreadPoolBuilder {
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        ThreadPoolBuilder {
+            min_threads: workers,
+            max_threads: workers,
+            keep_alive: Duration::from_secs(60),
+            name_prefix: String::from("worker"),
+            stack_size: None,
+            queue_capacity: 1,
+            queues: vec![(DEFAULT_QUEUE.to_string(), 1)],
+            scheduler: Scheduler::SharedQueue,
+            panic_handler: None,
+        }
+    }
+
+    pub fn queue_depth(&self) -> QueueDepth {
+        QueueDepth(Arc::clone(&self.shared.queued))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let threads = self.shared.threads.load(Ordering::SeqCst);
+        // A worker that was just retired may still be finishing its last job.
+        let busy = self.shared.busy.load(Ordering::SeqCst).min(threads);
+        PoolStats {
+            threads,
+            idle: threads - busy,
+            busy,
+            queued: self.shared.queued.load(Ordering::SeqCst),
+        }
+    }
+
+    // Changes the bounds on the number of workers. Workers are started right away to make up
+    // `min_threads`. Surplus ones stop once they're done with the job at hand.
+    pub fn resize(&self, min_threads: usize, max_threads: usize) -> Result<(), PoolCreationError> {
+        check_size(min_threads, max_threads)?;
+        let shared = &self.shared;
+        shared.min_threads.store(min_threads, Ordering::SeqCst);
+        shared.max_threads.store(max_threads, Ordering::SeqCst);
+
+        while shared.threads.load(Ordering::SeqCst) < min_threads {
+            if !shared.add_worker().map_err(PoolCreationError::Spawn)? {
+                break;
+            }
+        }
+
+        let surplus = shared
+            .threads
+            .load(Ordering::SeqCst)
+            .saturating_sub(max_threads);
+        if surplus > 0 {
+            match &shared.queue {
+                Queue::Shared(_) => {
+                    for _ in 0..surplus {
+                        let _ = shared.nudges.0.send(());
+                    }
+                }
+                Queue::Stealing(queues) => queues.nudge(),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn execute<F>(&self, func: F)
+    where
+        // We need `Send` to transfer closure from one thread to another
+        // We need `'static` since we don't know how long the thread will take to execute.
+        F: FnOnce() + Send + 'static,
+    {
+        // Send func as fast as we can.
+        // The only way this fails is a pool that is shutting down, and then the job is dropped.
+        let _ = self.submit(0, Priority::Normal, Box::new(func), None);
+    }
+
+    // Like `execute`, but never waits: if no worker or queue slot is free right now, the job is
+    // turned away with `ExecuteError::Full`.
+    pub fn try_execute<F>(&self, func: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(0, Priority::Normal, Box::new(func), Some(Duration::ZERO))
+    }
+
+    // Like `execute`, but waits at most `timeout` for room in the queue.
+    pub fn execute_timeout<F>(&self, func: F, timeout: Duration) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(0, Priority::Normal, Box::new(func), Some(timeout))
+    }
+
+    // Like `execute`, but into one of the queues added with `ThreadPoolBuilder::queue`, and ahead
+    // of the jobs with a lower priority in there:
+    //
+    //     pool.execute_with("default", Priority::High, || health_check())?;
+    //
+    // Jobs submitted by a work-stealing worker are the exception: they go to that worker's own
+    // deque as usual, and run before anything else it has queued.
+    pub fn execute_with<F>(
+        &self,
+        queue: &str,
+        priority: Priority,
+        func: F,
+    ) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let queue = self
+            .shared
+            .lanes
+            .find(queue)
+            .ok_or(ExecuteError::UnknownQueue)?;
+        self.submit(queue, priority, Box::new(func), None)
+    }
+
+    fn submit(
+        &self,
+        queue: usize,
+        priority: Priority,
+        job: Job,
+        timeout: Option<Duration>,
+    ) -> Result<(), ExecuteError> {
+        if self.shared.shutting_down.load(Ordering::Relaxed) {
+            return Err(ExecuteError::ShuttingDown);
+        }
+        // A job that a work-stealing worker submits is usually part of what it's working on, so
+        // it goes straight to its deque.
+        if let Queue::Stealing(queues) = &self.shared.queue {
+            if queues.is_local() {
+                return self.enqueue(Task::Job(job), timeout);
+            }
+        }
+
+        // The ticket goes first, since it's the one that may have to wait for room.
+        self.enqueue(Task::Ticket, timeout)?;
+        self.shared.lanes.push(queue, priority, job);
+        Ok(())
+    }
+
+    // Like `submit` with no timeout, except that a worker of this pool doesn't wait for room in a
+    // full queue: the other workers might all be waiting too. It runs queued jobs until there's
+    // room instead. Jobs of a work-stealing worker always have room in its deque.
+    pub(super) fn submit_or_help(&self, job: Job) -> Result<(), ExecuteError> {
+        let shared = &self.shared;
+        if !shared.is_worker() || matches!(shared.queue, Queue::Stealing(_)) {
+            return self.submit(0, Priority::Normal, job, None);
+        }
+        if shared.shutting_down.load(Ordering::Relaxed) {
+            return Err(ExecuteError::ShuttingDown);
+        }
+
+        loop {
+            match self.enqueue(Task::Ticket, Some(Duration::ZERO)) {
+                Ok(()) => break,
+                Err(ExecuteError::Full) => {
+                    // Another thread may have taken the job that filled the queue.
+                    if !shared.help() {
+                        thread::yield_now();
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        shared.lanes.push(0, Priority::Normal, job);
+        Ok(())
+    }
+
+    // Waits for room in the queue for up to `timeout`, or for as long as it takes with `None`.
+    fn enqueue(&self, task: Task, timeout: Option<Duration>) -> Result<(), ExecuteError> {
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        self.shared.grow();
+        let result = match (&self.shared.queue, &self.sender) {
+            (Queue::Shared(_), Some(sender)) => match timeout {
+                None => sender.send(task).map_err(|_| ExecuteError::ShuttingDown),
+                Some(Duration::ZERO) => sender.try_send(task).map_err(|err| match err {
+                    TrySendError::Full(_) => ExecuteError::Full,
+                    TrySendError::Disconnected(_) => ExecuteError::ShuttingDown,
+                }),
+                Some(timeout) => sender.send_timeout(task, timeout).map_err(|err| match err {
+                    SendTimeoutError::Timeout(_) => ExecuteError::Full,
+                    SendTimeoutError::Disconnected(_) => ExecuteError::ShuttingDown,
+                }),
+            },
+            (Queue::Stealing(queues), _) => queues.push(task, timeout),
+            (Queue::Shared(_), None) => Err(ExecuteError::ShuttingDown),
+        };
+        if result.is_err() {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    // Stops taking jobs: from now on `execute` drops them, `spawn` hands back handles that
+    // report `JoinError::NotRun`, and the others return `ExecuteError::ShuttingDown`. Jobs that
+    // are already queued still run. Dropping the pool waits for them.
+    pub fn shutdown(&self) {
+        self.shared.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    // Like `shutdown`, but the jobs that are still queued are dropped without running, and the
+    // pool's `cancellation_token` is cancelled. Jobs that are running carry on unless they
+    // watch a token from it, so dropping the pool may still wait for them.
+    pub fn shutdown_now(&self) {
+        self.shutdown();
+        self.shared.cancel.cancel();
+    }
+
+    // Cancelled by `shutdown_now`. Its children make good tokens for `execute_cancellable`:
+    //
+    //     let token = pool.cancellation_token().child();
+    //     pool.execute_cancellable(token.clone(), |token| {
+    //         while !token.is_cancelled() {
+    //             // Some more of the work.
+    //         }
+    //     });
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shared.cancel.clone()
+    }
+
+    // Like `execute`, but the job is dropped without running if `token` is cancelled before a
+    // worker gets to it. The job gets the token as well, to check every so often while it runs.
+    pub fn execute_cancellable<F>(&self, token: CancellationToken, func: F)
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        self.execute(move || {
+            if !token.is_cancelled() {
+                func(&token);
+            }
+        });
+    }
+
+    // `spawn` meets `execute_cancellable`. The handle of a job that was dropped reports
+    // `JoinError::NotRun`.
+    pub fn spawn_cancellable<F, T>(&self, token: CancellationToken, func: F) -> JobHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let run_token = token.clone();
+        let (job, handle) = joinable(move || func(&run_token));
+        self.execute(move || {
+            if !token.is_cancelled() {
+                job();
+            }
+        });
+        handle
+    }
+
+    // Runs `func` with a scope for jobs that borrow from the caller, like `std::thread::scope`
+    // but on the pool's workers. Every job spawned in the scope is done by the time this
+    // returns. If `func` or any of the jobs panicked, this passes the panic on.
+    //
+    //     let mut sums = [0; 2];
+    //     let (left, right) = sums.split_at_mut(1);
+    //     pool.scope(|s| {
+    //         s.spawn(|| left[0] = numbers[..half].iter().sum());
+    //         s.spawn(|| right[0] = numbers[half..].iter().sum());
+    //     });
+    //
+    // When a job of the pool waits for a scope, its worker runs other jobs from the queue in the
+    // meantime, so scopes can be nested. It does the same while the queue is too full to spawn
+    // into, instead of waiting for room like `execute`. Jobs that a pool which is shutting down
+    // turns away or drops still run, on the calling thread, before this returns.
+    pub fn scope<'env, F, T>(&self, func: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope::new(self);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| func(&scope)));
+        // Even when `func` panicked, since the jobs may still be using what it lent them.
+        scope.wait();
+        match (result, scope.take_panic()) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(value), None) => value,
+        }
+    }
+
+    // Like `execute`, but hands back the job's return value through the handle:
+    //
+    //     let sum = pool.spawn(|| (1..=100).sum::<u32>());
+    //     assert_eq!(sum.join(), Ok(5050));
+    pub fn spawn<F, T>(&self, func: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = joinable(func);
+        let _ = self.submit(0, Priority::Normal, job, None);
+        handle
+    }
+
+    // `spawn` meets `execute_with`. A handle from a queue that doesn't exist reports
+    // `JoinError::NotRun`.
+    pub fn spawn_with<F, T>(&self, queue: &str, priority: Priority, func: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = joinable(func);
+        if let Some(queue) = self.shared.lanes.find(queue) {
+            let _ = self.submit(queue, priority, job, None);
+        }
+        handle
+    }
+}
+
+fn joinable<F, T>(func: F) -> (Job, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (completion, handle) = job_handle();
+    let job = Box::new(move || match panic::catch_unwind(AssertUnwindSafe(func)) {
+        Ok(value) => completion.complete(Ok(value)),
+        Err(payload) => {
+            completion.complete(Err(JoinError::Panicked(panic_message(&*payload))));
+            // Passed on, so that the worker reports it like any other panic.
+            panic::resume_unwind(payload);
+        }
+    });
+    (job, handle)
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Dropping the Sender explicitly to let the channel know it's done sending data.
+        drop(self.sender.take());
+        if let Queue::Stealing(queues) = &self.shared.queue {
+            queues.close();
+        }
+
+        println!("Shutting down workers");
+        let workers = self.shared.workers.lock().unwrap().len();
+        for ind in 0..workers {
+            // A worker that dies while we wait for it leaves a replacement in its slot, which
+            // has to be waited for in turn.
+            loop {
+                // Not in the loop condition, which would keep the lock while joining.
+                let handle = self.shared.workers.lock().unwrap()[ind].take();
+                let Some(handle) = handle else { break };
+                // Need to cancel the threads before joining.
+                // Otherwise, threads won't be able to be cancelled properly.
+                // A worker only fails to join if it died, and then there's nothing left to do.
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Instant};
+
+    #[test]
+    fn builder_names_the_workers() {
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .name_prefix("test-pool")
+            .stack_size(256 * 1024)
+            .build()
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..8 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                let name = thread::current().name().map(String::from);
+                tx.send(name).unwrap();
+            });
+        }
+        drop((tx, pool));
+
+        let names: Vec<String> = rx.iter().map(Option::unwrap).collect();
+        assert_eq!(names.len(), 8);
+        assert!(names
+            .iter()
+            .all(|name| name == "test-pool-0" || name == "test-pool-1"));
+    }
+
+    #[test]
+    fn jobs_queue_up_to_the_capacity() {
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .queue_capacity(3)
+            .build()
+            .unwrap();
+
+        // Keeps the only worker busy until we say so.
+        let (release, released) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        has_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // None of these block, even though nobody can take them yet.
+        for _ in 0..3 {
+            pool.execute(|| {});
+        }
+        assert_eq!(pool.queue_depth().get(), 3);
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn spawned_jobs_hand_back_their_results() {
+        let pool = ThreadPool::new(2);
+
+        let sum = pool.spawn(|| (1..=100).sum::<u32>());
+        assert_eq!(sum.join(), Ok(5050));
+
+        let (release, released) = mpsc::channel::<()>();
+        let mut slow = pool.spawn(move || {
+            released.recv().unwrap();
+            "done"
+        });
+        assert_eq!(slow.try_join(), None);
+        assert_eq!(slow.join_timeout(Duration::from_millis(20)), None);
+        assert!(!slow.is_finished());
+        release.send(()).unwrap();
+        assert_eq!(slow.join_timeout(Duration::from_secs(5)), Some(Ok("done")));
+
+        // Awaited from async code, without blocking the executor's thread.
+        let squares = (0..4)
+            .map(|n| pool.spawn(move || n * n))
+            .collect::<Vec<_>>();
+        let squares = smol::block_on(async {
+            let mut results = Vec::new();
+            for square in squares {
+                results.push(square.await.unwrap());
+            }
+            results
+        });
+        assert_eq!(squares, [0, 1, 4, 9]);
+    }
+
+    #[test]
+    fn panicking_jobs_are_reported_and_the_worker_lives_on() {
+        let (tx, rx) = mpsc::channel();
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .panic_handler(move |payload| tx.send(panic_message(&*payload)).unwrap())
+            .build()
+            .unwrap();
+
+        let job = pool.spawn(|| -> u32 { panic!("boom") });
+        assert_eq!(job.join(), Err(JoinError::Panicked(String::from("boom"))));
+        pool.execute(|| panic!("{} again", "boom"));
+
+        // Every panic reaches the handler, and the only worker is still there to run this.
+        assert_eq!(pool.spawn(|| 42).join(), Ok(42));
+        let reported: Vec<String> = rx.try_iter().collect();
+        assert_eq!(reported, ["boom", "boom again"]);
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        // A handler that panics itself kills the worker it runs on.
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .name_prefix("phoenix")
+            .panic_handler(|_| panic!("the handler gave up"))
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            pool.execute(|| panic!("boom"));
+        }
+        let mut name = pool.spawn(|| thread::current().name().map(String::from));
+        assert_eq!(
+            name.join_timeout(Duration::from_secs(5)),
+            Some(Ok(Some(String::from("phoenix-0"))))
+        );
+        assert_eq!(pool.shared.workers.lock().unwrap().len(), 1);
+        // Dropping the pool joins the replacements, rather than panicking over the dead.
+    }
+
+    #[test]
+    fn submitting_to_a_full_queue_can_give_up() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(1)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+
+            let (release, released) = mpsc::channel::<()>();
+            let (started, has_started) = mpsc::channel();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                released.recv().unwrap();
+            });
+            has_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            // The worker is busy, so there's room for exactly one more.
+            assert_eq!(pool.try_execute(|| {}), Ok(()), "{scheduler:?}");
+            assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::Full));
+            let start = Instant::now();
+            assert_eq!(
+                pool.execute_timeout(|| {}, Duration::from_millis(50)),
+                Err(ExecuteError::Full)
+            );
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            assert_eq!(pool.queue_depth().get(), 1);
+
+            // Room frees up while waiting.
+            let release_later = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                release.send(()).unwrap();
+            });
+            assert_eq!(pool.execute_timeout(|| {}, Duration::from_secs(5)), Ok(()));
+            release_later.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn jobs_submitted_by_jobs_stay_on_their_worker() {
+        let pool = Arc::new(
+            ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(2)
+                .scheduler(Scheduler::WorkStealing)
+                .build()
+                .unwrap(),
+        );
+        let (tx, rx) = mpsc::channel();
+        let (submitted, was_submitted) = mpsc::channel::<()>();
+
+        let (first, inner) = (tx.clone(), tx.clone());
+        let own_pool = Arc::clone(&pool);
+        pool.execute(move || {
+            // Only once the next job from outside is queued.
+            was_submitted.recv().unwrap();
+            first.send("outer").unwrap();
+            own_pool.execute(move || inner.send("inner").unwrap());
+        });
+        pool.execute(move || tx.send("next").unwrap());
+        submitted.send(()).unwrap();
+
+        // With a shared queue, "inner" would wait behind "next".
+        let order: Vec<&str> = rx.iter().take(3).collect();
+        assert_eq!(order, ["outer", "inner", "next"]);
+    }
+
+    // Runs `submit` while the only worker is held up, and collects what the jobs send.
+    fn in_order<T: Send + 'static>(
+        pool: &ThreadPool,
+        submit: impl FnOnce(&mpsc::Sender<T>),
+    ) -> Vec<T> {
+        let (release, gate) = mpsc::channel::<()>();
+        pool.execute(move || gate.recv().unwrap());
+        let (tx, rx) = mpsc::channel();
+        submit(&tx);
+        drop(tx);
+        release.send(()).unwrap();
+        rx.iter().collect()
+    }
+
+    #[test]
+    fn urgent_jobs_go_first_without_starving_the_rest() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(256)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+
+            let order = in_order(&pool, |tx| {
+                for (n, priority) in [Priority::Low, Priority::Normal, Priority::High]
+                    .into_iter()
+                    .enumerate()
+                {
+                    for _ in 0..5 {
+                        let tx = tx.clone();
+                        pool.execute_with("default", priority, move || tx.send(n).unwrap())
+                            .unwrap();
+                    }
+                }
+            });
+            assert_eq!(order, [2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0]);
+
+            // A steady stream of urgent jobs holds a low one up for a while, but not forever.
+            let order = in_order(&pool, |tx| {
+                let low = tx.clone();
+                pool.execute_with("default", Priority::Low, move || low.send(true).unwrap())
+                    .unwrap();
+                for _ in 0..200 {
+                    let tx = tx.clone();
+                    pool.spawn_with("default", Priority::High, move || tx.send(false).unwrap());
+                }
+            });
+            let low = order.iter().position(|&low| low).unwrap();
+            assert!((10..100).contains(&low), "{scheduler:?}: {low}");
+        }
+    }
+
+    #[test]
+    fn named_queues_share_the_workers_by_weight() {
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .queue_capacity(256)
+            .queue("bulk", 1)
+            .queue("pages", 3)
+            .build()
+            .unwrap();
+
+        let order = in_order(&pool, |tx| {
+            for queue in ["bulk", "pages"] {
+                for _ in 0..40 {
+                    let tx = tx.clone();
+                    pool.execute_with(queue, Priority::Normal, move || tx.send(queue).unwrap())
+                        .unwrap();
+                }
+            }
+        });
+        let pages = order[..20]
+            .iter()
+            .filter(|&&queue| queue == "pages")
+            .count();
+        assert_eq!(pages, 15);
+        // Once "pages" runs dry, "bulk" has the worker to itself.
+        assert!(order[60..].iter().all(|&queue| queue == "bulk"));
+
+        assert_eq!(
+            pool.execute_with("nope", Priority::High, || {}),
+            Err(ExecuteError::UnknownQueue)
+        );
+        let handle = pool.spawn_with("nope", Priority::High, || {});
+        assert_eq!(handle.join(), Err(JoinError::NotRun));
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_from_the_caller() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(3)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            let numbers: Vec<u64> = (1..=1000).collect();
+            let mut sums = vec![0; 10];
+            let jobs = AtomicUsize::new(0);
+
+            pool.scope(|s| {
+                for (chunk, sum) in numbers.chunks(100).zip(&mut sums) {
+                    let jobs = &jobs;
+                    s.spawn(move || {
+                        *sum = chunk.iter().sum();
+                        jobs.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+            assert_eq!(jobs.into_inner(), 10);
+            assert_eq!(sums.iter().sum::<u64>(), 500_500);
+            assert_eq!(sums[0], 5050);
+        }
+    }
+
+    #[test]
+    fn scopes_nest_on_a_single_worker() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(8)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            let count = AtomicUsize::new(0);
+
+            // The inner scope only finishes because the worker waiting for it runs its jobs.
+            pool.scope(|s| {
+                s.spawn(|| {
+                    pool.scope(|s| {
+                        for _ in 0..3 {
+                            s.spawn(|| {
+                                count.fetch_add(1, Ordering::SeqCst);
+                            });
+                        }
+                    });
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+            assert_eq!(count.into_inner(), 4, "{scheduler:?}");
+        }
+    }
+
+    #[test]
+    fn nested_scopes_help_instead_of_waiting_for_room() {
+        let (done, is_done) = mpsc::channel();
+        thread::spawn(move || {
+            let pool = ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(1)
+                .scheduler(Scheduler::SharedQueue)
+                .build()
+                .unwrap();
+            let count = AtomicUsize::new(0);
+
+            // The only worker would wait for room in the queue forever, since nobody else takes
+            // anything out of it.
+            pool.scope(|s| {
+                for _ in 0..2 {
+                    s.spawn(|| {
+                        pool.scope(|s| {
+                            for _ in 0..4 {
+                                s.spawn(|| {
+                                    count.fetch_add(1, Ordering::SeqCst);
+                                });
+                            }
+                        });
+                        let squares = (1..=100u64).par_map(&pool, |n| n * n);
+                        count.fetch_add(squares.len(), Ordering::SeqCst);
+                    });
+                }
+            });
+            done.send(count.into_inner()).unwrap();
+        });
+        assert_eq!(is_done.recv_timeout(Duration::from_secs(10)), Ok(208));
+    }
+
+    #[test]
+    fn scopes_still_run_every_job_after_shutting_down() {
+        for now in [false, true] {
+            let pool = ThreadPool::new(2);
+            let numbers: Vec<u64> = (0..10_000).collect();
+            let mut unsorted: Vec<u64> = (0..20_000).rev().collect();
+            match now {
+                false => pool.shutdown(),
+                true => pool.shutdown_now(),
+            }
+
+            let squares = numbers.iter().par_map(&pool, |n| n * n);
+            assert_eq!(squares.len(), 10_000, "now: {now}");
+            assert_eq!(squares[9_999], 9_999 * 9_999);
+            unsorted.par_sort(&pool);
+            assert!(unsorted.is_sorted(), "now: {now}");
+        }
+    }
+
+    #[test]
+    fn scopes_run_the_jobs_that_shutting_down_now_dropped() {
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .queue_capacity(8)
+            .build()
+            .unwrap();
+        let count = AtomicUsize::new(0);
+
+        pool.scope(|s| {
+            let (release, released) = mpsc::channel::<()>();
+            // Holds on to the only worker while the others are queued.
+            s.spawn(move || released.recv().unwrap());
+            for _ in 0..5 {
+                s.spawn(|| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            pool.shutdown_now();
+            release.send(()).unwrap();
+        });
+        assert_eq!(count.into_inner(), 5);
+    }
+
+    #[test]
+    fn scopes_pass_on_panics_once_every_job_is_done() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicBool::new(false);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("boom"));
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.store(true, Ordering::SeqCst);
+                });
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        assert!(finished.load(Ordering::SeqCst));
+
+        // The workers are none the worse for it.
+        assert_eq!(pool.scope(|_| 5), 5);
+        assert_eq!(pool.spawn(|| 6).join(), Ok(6));
+    }
+
+    #[test]
+    fn parallel_iterators_keep_the_order() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(3)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+
+            let numbers: Vec<u64> = (0..10_000).collect();
+            let doubled = numbers.iter().par_map(&pool, |n| n * 2);
+            assert_eq!(doubled, numbers.iter().map(|n| n * 2).collect::<Vec<_>>());
+
+            // Iterators of unknown length, with items that take a while.
+            let odd = (0..200).filter(|n| n % 2 == 1).par_map(&pool, |n| {
+                thread::sleep(Duration::from_micros(50));
+                n
+            });
+            assert_eq!(odd, (0..200).filter(|n| n % 2 == 1).collect::<Vec<_>>());
+
+            let sum = AtomicUsize::new(0);
+            (1..=1000).par_for_each(&pool, |n| {
+                sum.fetch_add(n, Ordering::SeqCst);
+            });
+            assert_eq!(sum.into_inner(), 500_500);
+
+            // Concatenating isn't commutative, so this only works out in order.
+            let joined = (0..500)
+                .map(|n| n.to_string())
+                .par_reduce(&pool, |a, b| a + &b);
+            assert_eq!(joined, Some((0..500).map(|n| n.to_string()).collect()));
+            assert_eq!(std::iter::empty::<u8>().par_reduce(&pool, |a, _| a), None);
+        }
+    }
+
+    #[test]
+    fn parallel_sorts_are_stable() {
+        let pool = ThreadPool::new(4);
+        // Plenty of duplicate keys, in no particular order.
+        let mut seed = 1u64;
+        let mut pairs: Vec<(u64, usize)> = (0..50_000)
+            .map(|ind| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (seed >> 54, ind)
+            })
+            .collect();
+
+        let mut expected = pairs.clone();
+        expected.sort_by_key(|&(key, _)| key);
+        pairs.par_sort_by(&pool, |a, b| a.0.cmp(&b.0));
+        assert_eq!(pairs, expected);
+
+        let mut numbers: Vec<u64> = pairs.iter().rev().map(|&(key, _)| key).collect();
+        numbers.par_sort(&pool);
+        assert!(numbers.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn cancelled_jobs_are_dropped_without_running() {
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .queue_capacity(8)
+            .build()
+            .unwrap();
+
+        let mut handle = None;
+        let order = in_order(&pool, |tx| {
+            let (cancelled, kept) = (CancellationToken::new(), CancellationToken::new());
+            for (name, token) in [("cancelled", &cancelled), ("kept", &kept)] {
+                let tx = tx.clone();
+                pool.execute_cancellable(token.clone(), move |_| tx.send(name).unwrap());
+            }
+            handle = Some(pool.spawn_cancellable(cancelled.child(), |_| 1));
+            cancelled.cancel();
+        });
+        assert_eq!(order, ["kept"]);
+        assert_eq!(handle.unwrap().join(), Err(JoinError::NotRun));
+    }
+
+    #[test]
+    fn running_jobs_can_watch_their_token() {
+        let pool = ThreadPool::new(2);
+        let token = CancellationToken::new();
+        let (started, has_started) = mpsc::channel();
+
+        let handle = pool.spawn_cancellable(token.clone(), move |token| {
+            started.send(()).unwrap();
+            let mut rounds = 0;
+            while !token.is_cancelled() {
+                rounds += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            rounds
+        });
+        has_started.recv().unwrap();
+        token.cancel();
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn shutting_down_now_drops_the_queued_jobs() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(1)
+                .queue_capacity(8)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            let ran = Arc::new(AtomicUsize::new(0));
+            let (started, has_started) = mpsc::channel();
+
+            let running = pool.spawn_cancellable(pool.cancellation_token().child(), move |token| {
+                started.send(()).unwrap();
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                "stopped"
+            });
+            has_started.recv().unwrap();
+            let queued: Vec<_> = (0..5)
+                .map(|_| {
+                    let ran = Arc::clone(&ran);
+                    pool.spawn(move || ran.fetch_add(1, Ordering::SeqCst))
+                })
+                .collect();
+
+            pool.shutdown_now();
+            assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::ShuttingDown));
+            assert_eq!(running.join(), Ok("stopped"));
+            for handle in queued {
+                assert_eq!(handle.join(), Err(JoinError::NotRun), "{scheduler:?}");
+            }
+            drop(pool);
+            assert_eq!(ran.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    // Every job splits into two more until `depth` runs out, and the last one to finish says so.
+    fn split(
+        pool: Arc<ThreadPool>,
+        depth: u32,
+        remaining: Arc<AtomicUsize>,
+        done: mpsc::Sender<()>,
+    ) {
+        if depth > 0 {
+            for _ in 0..2 {
+                remaining.fetch_add(1, Ordering::SeqCst);
+                let (job_pool, remaining, done) =
+                    (Arc::clone(&pool), Arc::clone(&remaining), done.clone());
+                pool.execute(move || split(job_pool, depth - 1, remaining, done));
+            }
+        }
+        // Before saying we're done, or the last reference to the pool could end up on a worker,
+        // which would have to wait for itself to finish.
+        drop(pool);
+        if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            done.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn work_stealing_runs_recursive_jobs() {
+        let pool = Arc::new(
+            ThreadPool::builder()
+                .workers(4)
+                .scheduler(Scheduler::WorkStealing)
+                .build()
+                .unwrap(),
+        );
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        let (done, is_done) = mpsc::channel();
+        let remaining = Arc::new(AtomicUsize::new(1));
+        {
+            let (job_pool, remaining) = (Arc::clone(&pool), Arc::clone(&remaining));
+            pool.execute(move || split(job_pool, 10, remaining, done));
+        }
+        is_done.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(pool.queue_depth().get(), 0);
+
+        // All the jobs are done, so workers go to sleep, and wake up for more.
+        for n in 0..100 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || ran.lock().unwrap().push(n));
+        }
+        drop(Arc::into_inner(pool).unwrap());
+        assert_eq!(ran.lock().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn shutting_down_turns_jobs_away() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let queued = tx.clone();
+        pool.execute(move || queued.send("queued").unwrap());
+
+        pool.shutdown();
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::ShuttingDown));
+        assert_eq!(
+            pool.execute_timeout(|| {}, Duration::from_secs(1)),
+            Err(ExecuteError::ShuttingDown)
+        );
+        assert_eq!(pool.spawn(|| 1).join(), Err(JoinError::NotRun));
+        pool.execute(move || tx.send("late").unwrap());
+
+        drop(pool);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), ["queued"]);
+    }
+
+    // Polls `stats` until `done` holds, or panics after a few seconds.
+    fn wait_for(pool: &ThreadPool, done: impl Fn(PoolStats) -> bool) -> PoolStats {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = pool.stats();
+            if done(stats) {
+                return stats;
+            }
+            assert!(Instant::now() < deadline, "gave up waiting: {stats:?}");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn workers_come_and_go_with_the_load() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .min_threads(1)
+                .max_threads(3)
+                .keep_alive(Duration::from_millis(50))
+                .queue_capacity(4)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            assert_eq!(pool.stats().threads, 1);
+
+            let (started_tx, started_rx) = mpsc::channel();
+            let (release_tx, release_rx) = crossbeam_channel::unbounded::<()>();
+            for _ in 0..4 {
+                let started_tx = started_tx.clone();
+                let release_rx = release_rx.clone();
+                pool.execute(move || {
+                    started_tx.send(()).unwrap();
+                    let _ = release_rx.recv();
+                });
+            }
+            // Three run at once, and the fourth waits for one of them.
+            for _ in 0..3 {
+                started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            let stats = wait_for(&pool, |stats| stats.busy == 3);
+            assert_eq!(
+                (stats.threads, stats.idle, stats.queued),
+                (3, 0, 1),
+                "{scheduler:?}"
+            );
+
+            drop(release_tx);
+            let stats = wait_for(&pool, |stats| stats.threads == 1);
+            assert_eq!(
+                (stats.idle, stats.busy, stats.queued),
+                (1, 0, 0),
+                "{scheduler:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn pools_can_be_resized() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder()
+                .workers(4)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            assert_eq!(pool.stats().threads, 4);
+
+            // Idle workers are surplus straight away, without waiting out the keep-alive.
+            pool.resize(1, 2).unwrap();
+            wait_for(&pool, |stats| stats.threads == 2);
+
+            pool.resize(3, 5).unwrap();
+            assert_eq!(pool.stats().threads, 3);
+
+            let err = pool.resize(2, 1).err().unwrap();
+            assert!(matches!(err, PoolCreationError::MinAboveMax { .. }));
+            assert_eq!(pool.stats().threads, 3);
+
+            // The new workers run jobs like any other.
+            let (tx, rx) = mpsc::channel();
+            for n in 0..10 {
+                let tx = tx.clone();
+                pool.execute(move || tx.send(n).unwrap());
+            }
+            drop(tx);
+            assert_eq!(rx.iter().sum::<i32>(), 45);
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_errors() {
+        let err = ThreadPool::builder().workers(0).build().err().unwrap();
+        assert!(matches!(err, PoolCreationError::NoWorkers));
+        let err = ThreadPool::builder()
+            .min_threads(3)
+            .max_threads(2)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            PoolCreationError::MinAboveMax { min: 3, max: 2 }
+        ));
+
+        // No OS can give a thread this much stack.
+        let err = ThreadPool::builder()
+            .workers(3)
+            .stack_size(usize::MAX)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, PoolCreationError::Spawn(_)), "{err}");
+    }
+}
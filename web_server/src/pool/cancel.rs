@@ -0,0 +1,56 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// Asks jobs to stop. A queued job whose token is cancelled is dropped without running, and a
+// running job can check its token every so often and stop early. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Node>);
+
+#[derive(Default)]
+struct Node {
+    cancelled: AtomicBool,
+    parent: Option<Arc<Node>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    // A token that is cancelled along with this one, but can also be cancelled by itself,
+    // e.g. one per request from the pool's token.
+    pub fn child(&self) -> CancellationToken {
+        CancellationToken(Arc::new(Node {
+            cancelled: AtomicBool::new(false),
+            parent: Some(Arc::clone(&self.0)),
+        }))
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let mut node = Some(&self.0);
+        while let Some(current) = node {
+            if current.cancelled.load(Ordering::SeqCst) {
+                return true;
+            }
+            node = current.parent.as_ref();
+        }
+        false
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
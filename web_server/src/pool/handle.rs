@@ -0,0 +1,174 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+// Why a job has no result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    // The job panicked, with this message.
+    Panicked(String),
+    // The job was dropped without running, e.g. because every worker had died.
+    NotRun,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "the job panicked: {message}"),
+            JoinError::NotRun => f.write_str("the job was dropped before it ran"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+// The result of a job from `ThreadPool::spawn`. It can be waited for from a plain thread with
+// `join`, or from async code by awaiting the handle itself. Dropping the handle doesn't stop the
+// job; its result is just thrown away.
+pub struct JobHandle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+// Where the worker leaves the result for the handle.
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+    finished: Condvar,
+}
+
+enum SlotState<T> {
+    // The waker of whoever last polled the handle, if it's being awaited.
+    Pending(Option<Waker>),
+    Finished(Result<T, JoinError>),
+    // Handed out by `try_join`, `join_timeout` or `poll`.
+    Taken,
+}
+
+impl<T> Slot<T> {
+    fn finish(&self, result: Result<T, JoinError>) {
+        let mut state = self.state.lock().unwrap();
+        let previous = std::mem::replace(&mut *state, SlotState::Finished(result));
+        if let SlotState::Pending(Some(waker)) = previous {
+            waker.wake();
+        }
+        self.finished.notify_all();
+    }
+}
+
+// Travels with the job to fill in the slot. If the job is dropped from the queue without
+// running, dropping this says so instead.
+pub(super) struct Completion<T>(Option<Arc<Slot<T>>>);
+
+impl<T> Completion<T> {
+    pub(super) fn complete(mut self, result: Result<T, JoinError>) {
+        if let Some(slot) = self.0.take() {
+            slot.finish(result);
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0.take() {
+            slot.finish(Err(JoinError::NotRun));
+        }
+    }
+}
+
+// Panics usually carry a `&str` or a `String`, from `panic!("literal")` and `panic!("{}", ..)`.
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
+
+pub(super) fn job_handle<T>() -> (Completion<T>, JobHandle<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(SlotState::Pending(None)),
+        finished: Condvar::new(),
+    });
+    (Completion(Some(Arc::clone(&slot))), JobHandle { slot })
+}
+
+impl<T> JobHandle<T> {
+    // Blocks until the job is done. Don't call this from async code, await the handle instead.
+    pub fn join(mut self) -> Result<T, JoinError> {
+        self.wait_until(None)
+            .expect("JobHandle::join called after the result was taken")
+    }
+
+    // The result if the job is done, without waiting.
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        self.join_timeout(Duration::ZERO)
+    }
+
+    // Waits at most `timeout` for the job. `None` means it's still running, and the handle can
+    // be waited on again.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JoinError>> {
+        // A timeout too long to add to the current time might as well be none.
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            *self.slot.state.lock().unwrap(),
+            SlotState::Finished(_) | SlotState::Taken
+        )
+    }
+
+    // Panics if the result was already taken, just like polling a finished future would.
+    fn wait_until(&mut self, deadline: Option<Instant>) -> Option<Result<T, JoinError>> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            match std::mem::replace(&mut *state, SlotState::Taken) {
+                SlotState::Finished(result) => return Some(result),
+                SlotState::Taken => panic!("the job's result was already taken"),
+                pending => *state = pending,
+            }
+            state = match deadline {
+                None => self.slot.finished.wait(state).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return None;
+                    }
+                    self.slot.finished.wait_timeout(state, timeout).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        match std::mem::replace(&mut *state, SlotState::Taken) {
+            SlotState::Finished(result) => Poll::Ready(result),
+            SlotState::Taken => panic!("JobHandle polled after it was finished"),
+            SlotState::Pending(_) => {
+                *state = SlotState::Pending(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
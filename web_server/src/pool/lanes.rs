@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
+
+use super::Job;
+
+// How many jobs that come after a job may go ahead of it, for each priority it's below them.
+const AGING: u64 = 32;
+// Queues take turns in steps of this over their weight, which leaves little to rounding.
+const STRIDE: u64 = 1 << 20;
+
+// How urgent a job is compared to the other jobs in its queue. Higher priorities go first, but
+// a job that has waited long enough moves up, so that the lower ones still get their turn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// The jobs submitted from outside the pool, waiting for a worker to get to one of their tickets.
+//
+// Between named queues this is stride scheduling: every queue has a pass that moves on by
+// `STRIDE / weight` each time it's picked, and the queue with the lowest pass goes next. So a
+// queue with weight 3 gets three turns for every turn of a queue with weight 1, as long as both
+// have jobs waiting.
+pub(super) struct Lanes {
+    state: Mutex<State>,
+    job_pushed: Condvar,
+}
+
+struct State {
+    queues: Vec<Lane>,
+    // The pass of the queue picked last. A queue that had nothing to do starts again from here,
+    // rather than catching up on all the turns it missed.
+    pass: u64,
+    // Tickets that got to a worker before their job was pushed.
+    waiting: usize,
+}
+
+struct Lane {
+    name: String,
+    stride: u64,
+    pass: u64,
+    // Jobs pushed to this queue so far.
+    pushed: u64,
+    // One per priority, holding each job along with how many jobs were pushed before it.
+    levels: [VecDeque<(u64, Job)>; 3],
+}
+
+impl Lanes {
+    // A weight of 0 acts like 1.
+    pub(super) fn new(queues: &[(String, u32)]) -> Lanes {
+        let queues = queues
+            .iter()
+            .map(|(name, weight)| Lane {
+                name: name.clone(),
+                stride: STRIDE / u64::from((*weight).max(1)),
+                pass: 0,
+                pushed: 0,
+                levels: Default::default(),
+            })
+            .collect();
+        Lanes {
+            state: Mutex::new(State {
+                queues,
+                pass: 0,
+                waiting: 0,
+            }),
+            job_pushed: Condvar::new(),
+        }
+    }
+
+    pub(super) fn find(&self, name: &str) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        state.queues.iter().position(|lane| lane.name == name)
+    }
+
+    // Only once its ticket is in the pool's queue, so that every job has one.
+    pub(super) fn push(&self, queue: usize, priority: Priority, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        let pass = state.pass;
+        let lane = &mut state.queues[queue];
+        if lane.levels.iter().all(VecDeque::is_empty) {
+            lane.pass = lane.pass.max(pass);
+        }
+        lane.levels[priority as usize].push_back((lane.pushed, job));
+        lane.pushed += 1;
+        if state.waiting > 0 {
+            self.job_pushed.notify_one();
+        }
+    }
+
+    // For a ticket. There's a job for every ticket, but it may not have been pushed yet.
+    pub(super) fn next_job(&self) -> Job {
+        let mut state = self.state.lock().unwrap();
+        let job = loop {
+            if let Some(job) = state.pop() {
+                break job;
+            }
+            state.waiting += 1;
+            state = self.job_pushed.wait(state).unwrap();
+            state.waiting -= 1;
+        };
+        job
+    }
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Job> {
+        // `min_by_key` picks the first of equals, so ties go to the queue that was added first.
+        let lane = self
+            .queues
+            .iter_mut()
+            .filter(|lane| lane.levels.iter().any(|level| !level.is_empty()))
+            .min_by_key(|lane| lane.pass)?;
+        self.pass = lane.pass;
+        lane.pass += lane.stride;
+        lane.pop()
+    }
+}
+
+impl Lane {
+    // The front of the highest priority, unless a lower one has been there for long enough:
+    // every job is handicapped by `AGING` for each priority it's below the highest.
+    fn pop(&mut self) -> Option<Job> {
+        let top = self.levels.len() as u64 - 1;
+        // `min_by_key` picks the first of equals, so the higher priority wins a tie.
+        let level = (0..self.levels.len())
+            .rev()
+            .filter_map(|level| {
+                let (pushed, _) = self.levels[level].front()?;
+                Some((level, pushed + (top - level as u64) * AGING))
+            })
+            .min_by_key(|&(_, handicap)| handicap)?
+            .0;
+        self.levels[level].pop_front().map(|(_, job)| job)
+    }
+}
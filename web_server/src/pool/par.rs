@@ -0,0 +1,194 @@
+use std::{
+    cmp::Ordering,
+    sync::{
+        atomic::{self, AtomicU64},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::ThreadPool;
+
+// What a job of a parallel operation should take: long enough that handing it to a worker costs
+// next to nothing in comparison, and short enough that the work spreads evenly.
+const TARGET: Duration = Duration::from_micros(100);
+// When the number of items is known, every worker gets at least this many jobs, as some of them
+// are bound to finish early.
+const JOBS_PER_WORKER: usize = 4;
+// Slices this short are sorted on the spot: handing them to the workers takes longer.
+const SORT_ALONE: usize = 4096;
+
+// Parallel versions of the usual iterator methods, for any iterator:
+//
+//     let squares = numbers.iter().par_map(&pool, |n| n * n);
+//
+// The items are pulled on the calling thread and handed to the workers in chunks, so the
+// iterator itself doesn't have to be `Send`. Chunks start out small and grow until a job takes
+// about as long as `TARGET`, going by how long the items took so far.
+pub trait ParallelIterator: Iterator + Sized {
+    // Like `map` and `collect`, keeping the order of the items.
+    fn par_map<F, R>(self, pool: &ThreadPool, func: F) -> Vec<R>
+    where
+        F: Fn(Self::Item) -> R + Sync,
+        R: Send;
+
+    // Like `for_each`, in no particular order.
+    fn par_for_each<F>(self, pool: &ThreadPool, func: F)
+    where
+        F: Fn(Self::Item) + Sync;
+
+    // Like `reduce`. `func` has to be associative, but not commutative: items are only ever
+    // combined with their neighbours, in order.
+    fn par_reduce<F>(self, pool: &ThreadPool, func: F) -> Option<Self::Item>
+    where
+        F: Fn(Self::Item, Self::Item) -> Self::Item + Sync;
+}
+
+impl<I> ParallelIterator for I
+where
+    I: Iterator,
+    I::Item: Send,
+{
+    fn par_map<F, R>(self, pool: &ThreadPool, func: F) -> Vec<R>
+    where
+        F: Fn(I::Item) -> R + Sync,
+        R: Send,
+    {
+        let chunks = chunked(pool, self, |chunk| {
+            chunk.into_iter().map(&func).collect::<Vec<R>>()
+        });
+        chunks.into_iter().flatten().collect()
+    }
+
+    fn par_for_each<F>(self, pool: &ThreadPool, func: F)
+    where
+        F: Fn(I::Item) + Sync,
+    {
+        chunked(pool, self, |chunk| chunk.into_iter().for_each(&func));
+    }
+
+    fn par_reduce<F>(self, pool: &ThreadPool, func: F) -> Option<I::Item>
+    where
+        F: Fn(I::Item, I::Item) -> I::Item + Sync,
+    {
+        let chunks = chunked(pool, self, |chunk| chunk.into_iter().reduce(&func));
+        chunks.into_iter().flatten().reduce(&func)
+    }
+}
+
+// Parallel sorting. Both sorts are stable.
+pub trait ParallelSlice<T> {
+    fn par_sort(&mut self, pool: &ThreadPool)
+    where
+        T: Ord;
+
+    fn par_sort_by<F>(&mut self, pool: &ThreadPool, compare: F)
+    where
+        F: Fn(&T, &T) -> Ordering + Sync;
+}
+
+impl<T: Send> ParallelSlice<T> for [T] {
+    fn par_sort(&mut self, pool: &ThreadPool)
+    where
+        T: Ord,
+    {
+        self.par_sort_by(pool, T::cmp);
+    }
+
+    // The workers sort a chunk each, and then merge them pairwise until there's one left. The
+    // standard sort spots runs that are sorted already, so sorting two of them merges them.
+    fn par_sort_by<F>(&mut self, pool: &ThreadPool, compare: F)
+    where
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        let workers = pool.shared.max_threads.load(atomic::Ordering::SeqCst);
+        if self.len() <= SORT_ALONE || workers == 1 {
+            self.sort_by(compare);
+            return;
+        }
+
+        let mut width = self.len().div_ceil(workers).max(SORT_ALONE);
+        loop {
+            pool.scope(|s| {
+                for chunk in self.chunks_mut(width) {
+                    let compare = &compare;
+                    s.spawn(move || chunk.sort_by(compare));
+                }
+            });
+            if width >= self.len() {
+                break;
+            }
+            width *= 2;
+        }
+    }
+}
+
+// Hands the items to the workers a chunk at a time, and returns what `run` made of each chunk,
+// in order.
+fn chunked<I, R>(pool: &ThreadPool, mut items: I, run: impl Fn(Vec<I::Item>) -> R + Sync) -> Vec<R>
+where
+    I: Iterator,
+    I::Item: Send,
+    R: Send,
+{
+    let chunker = Chunker {
+        workers: pool.shared.max_threads.load(atomic::Ordering::SeqCst),
+        nanos_per_item: AtomicU64::new(0),
+    };
+    let results = Mutex::new(Vec::new());
+
+    pool.scope(|s| {
+        let mut size = 0;
+        for ind in 0.. {
+            size = chunker.next_size(size, items.size_hint().0);
+            let chunk: Vec<I::Item> = items.by_ref().take(size).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            let (run, chunker, results) = (&run, &chunker, &results);
+            s.spawn(move || {
+                let (len, start) = (chunk.len(), Instant::now());
+                let result = run(chunk);
+                chunker.record(len, start.elapsed());
+                results.lock().unwrap().push((ind, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|&(ind, _)| ind);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// Picks chunk sizes, from how long the items have taken so far.
+struct Chunker {
+    workers: usize,
+    // A running average, or 0 until the first chunk is done.
+    nanos_per_item: AtomicU64,
+}
+
+impl Chunker {
+    // Until a chunk is done, every chunk is twice the size of the last one, starting with one
+    // item. `remaining` is how many items are left at least, as far as we know.
+    fn next_size(&self, last: usize, remaining: usize) -> usize {
+        let size = match self.nanos_per_item.load(atomic::Ordering::Relaxed) {
+            0 => (last * 2).max(1),
+            nanos => (TARGET.as_nanos() as u64 / nanos).max(1) as usize,
+        };
+        match remaining {
+            0 => size,
+            remaining => size.min(remaining.div_ceil(self.workers * JOBS_PER_WORKER)),
+        }
+    }
+
+    // Two jobs finishing at once may lose one of their times, which is fine for an estimate.
+    fn record(&self, items: usize, elapsed: Duration) {
+        let sample = (elapsed.as_nanos() / items as u128).max(1) as u64;
+        let average = match self.nanos_per_item.load(atomic::Ordering::Relaxed) {
+            0 => sample,
+            average => (average * 3 + sample) / 4,
+        };
+        self.nanos_per_item
+            .store(average, atomic::Ordering::Relaxed);
+    }
+}
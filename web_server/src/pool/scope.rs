@@ -0,0 +1,134 @@
+use std::{
+    any::Any,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use super::{Job, ThreadPool};
+
+// How often a worker waiting for a scope checks the queue for a job to help out with.
+const HELP_INTERVAL: Duration = Duration::from_millis(1);
+
+// Lets jobs borrow anything that outlives the scope, from `ThreadPool::scope`. Like
+// `std::thread::Scope`, it's invariant in both lifetimes so that nothing can sneak a shorter
+// borrow into a job.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<State>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct State {
+    // Jobs spawned but not finished or dropped yet.
+    running: Mutex<usize>,
+    all_done: Condvar,
+    // The first job to panic, for the scope to pass on.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+    // Jobs that the pool dropped without running, because it was shut down. The scope runs them
+    // itself, since the caller counts on every job having run once it returns.
+    leftovers: Mutex<Vec<Job>>,
+}
+
+// Lives in a job's closure and holds the scoped function until it runs, so that the scope hears
+// the job is done however the closure goes: run, or dropped without running. In the latter case
+// the function goes to the leftovers.
+struct Running {
+    state: Arc<State>,
+    func: Option<Job>,
+}
+
+impl Running {
+    fn run(mut self) {
+        if let Some(func) = self.func.take() {
+            func();
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(func) = self.func.take() {
+            self.state.leftovers.lock().unwrap().push(func);
+        }
+        let mut running = self.state.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.state.all_done.notify_all();
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(super) fn new(pool: &'scope ThreadPool) -> Scope<'scope, 'env> {
+        Scope {
+            pool,
+            state: Arc::new(State {
+                running: Mutex::new(0),
+                all_done: Condvar::new(),
+                panic: Mutex::new(None),
+                leftovers: Mutex::new(Vec::new()),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        }
+    }
+
+    // Runs `func` on the pool, like `ThreadPool::execute`. If it panics, the scope panics with
+    // the same payload once every other job is done.
+    pub fn spawn<F>(&'scope self, func: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.running.lock().unwrap() += 1;
+        let state = Arc::clone(&self.state);
+        let func: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(func)) {
+                state.panic.lock().unwrap().get_or_insert(payload);
+            }
+        });
+        // SAFETY: `ThreadPool::scope` doesn't return before every job is done, and `Running`
+        // says so only once the function has either run, or been handed over to the leftovers
+        // that `wait` runs before returning.
+        let func: Job = unsafe { mem::transmute(func) };
+        let running = Running {
+            state: Arc::clone(&self.state),
+            func: Some(func),
+        };
+        // Failing to submit drops the job, and with it `running`.
+        let _ = self.pool.submit_or_help(Box::new(move || running.run()));
+    }
+
+    // Waits for every job, and then runs the leftovers. A worker of the pool lends a hand in the
+    // meantime, since the jobs might otherwise wait in the queue for it.
+    pub(super) fn wait(&self) {
+        let shared = &self.pool.shared;
+        loop {
+            if *self.state.running.lock().unwrap() == 0 || !shared.is_worker() {
+                break;
+            }
+            if !shared.help() {
+                let running = self.state.running.lock().unwrap();
+                drop(self.state.all_done.wait_timeout(running, HELP_INTERVAL));
+            }
+        }
+        let running = self.state.running.lock().unwrap();
+        drop(
+            self.state
+                .all_done
+                .wait_while(running, |running| *running > 0),
+        );
+
+        let leftovers = mem::take(&mut *self.state.leftovers.lock().unwrap());
+        for func in leftovers {
+            func();
+        }
+    }
+
+    pub(super) fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.state.panic.lock().unwrap().take()
+    }
+}
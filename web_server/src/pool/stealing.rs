@@ -0,0 +1,338 @@
+use std::{
+    cell::RefCell,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+use super::{ExecuteError, Task};
+
+// Times an idle worker looks for a job again before it goes to sleep.
+const SPINS: u32 = 16;
+
+thread_local! {
+    // The deque of the work-stealing worker running on this thread, so that the jobs it submits
+    // go there instead of the injector.
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+struct Local {
+    // Which pool's worker this is, since a job may well submit to some other pool.
+    queues: *const Queues,
+    deque: Worker<Task>,
+}
+
+// The queues of a `Scheduler::WorkStealing` pool: an injector for jobs submitted from outside the
+// pool, and a deque per worker for the jobs that worker submits itself. Workers run their own jobs
+// newest first, then take the oldest job from the injector, and then steal the oldest jobs of
+// the others.
+//
+// Idle workers sleep until a job is pushed, the pool is resized, or their keep-alive runs out.
+// All the counters are `SeqCst`: a worker going to sleep
+// bumps `sleepers` and then checks `pending`, while a submitter bumps `pending` and then checks
+// `sleepers`, and at least one of them is bound to see the other's update.
+pub(super) struct Queues {
+    injector: Injector<Task>,
+    // One per worker slot, added as the pool grows. A worker takes its deque from here when it
+    // starts and puts it back when it stops, so that a replacement for a dead worker, or the next
+    // worker in a retired one's slot, carries on with its jobs.
+    deques: RwLock<Vec<Deque>>,
+    // Jobs in the injector, which submitters from outside the pool have to wait for.
+    capacity: usize,
+    injected: AtomicUsize,
+    // Jobs in any of the queues.
+    pending: AtomicUsize,
+    closed: AtomicBool,
+    // Guards the waits on both condition variables, and nothing else.
+    lock: Mutex<()>,
+    sleepers: AtomicUsize,
+    // Bumped to wake up every sleeper, so that they check whether they're still needed.
+    nudges: AtomicUsize,
+    work_available: Condvar,
+    blocked_submitters: AtomicUsize,
+    room_available: Condvar,
+}
+
+struct Deque {
+    worker: Mutex<Option<Worker<Task>>>,
+    stealer: Stealer<Task>,
+}
+
+// Why a worker woke up.
+enum Wake {
+    Work,
+    Nudged,
+    TimedOut,
+    Closed,
+}
+
+impl Queues {
+    // A capacity of 0 acts like 1: there is no handing a job straight to a worker here.
+    pub(super) fn new(capacity: usize) -> Queues {
+        Queues {
+            injector: Injector::new(),
+            deques: RwLock::new(Vec::new()),
+            capacity: capacity.max(1),
+            injected: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            sleepers: AtomicUsize::new(0),
+            nudges: AtomicUsize::new(0),
+            work_available: Condvar::new(),
+            blocked_submitters: AtomicUsize::new(0),
+            room_available: Condvar::new(),
+        }
+    }
+
+    // For a new worker slot.
+    pub(super) fn add_deque(&self) {
+        let worker = Worker::new_lifo();
+        self.deques.write().unwrap().push(Deque {
+            stealer: worker.stealer(),
+            worker: Mutex::new(Some(worker)),
+        });
+    }
+
+    // Whether this thread is one of our own workers.
+    pub(super) fn is_local(&self) -> bool {
+        LOCAL.with_borrow(|local| matches!(local, Some(local) if ptr::eq(local.queues, self)))
+    }
+
+    // Jobs from one of our own workers go to its deque right away. They never wait for room:
+    // the worker that would make room is busy running the job that submits them.
+    pub(super) fn push(&self, job: Task, timeout: Option<Duration>) -> Result<(), ExecuteError> {
+        let job = LOCAL.with_borrow(|local| match local {
+            Some(local) if ptr::eq(local.queues, self) => {
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                local.deque.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+
+        if let Some(job) = job {
+            if !self.reserve(timeout) {
+                return Err(ExecuteError::Full);
+            }
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            self.injector.push(job);
+        }
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.work_available.notify_one();
+        }
+        Ok(())
+    }
+
+    // Takes a place in the injector, waiting up to `timeout` (or forever with `None`) for one.
+    fn reserve(&self, timeout: Option<Duration>) -> bool {
+        let try_reserve = || {
+            self.injected
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < self.capacity).then_some(n + 1)
+                })
+                .is_ok()
+        };
+        if try_reserve() {
+            return true;
+        }
+        if timeout == Some(Duration::ZERO) {
+            return false;
+        }
+
+        // A timeout too long to add to the current time might as well be none.
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut lock = self.lock.lock().unwrap();
+        self.blocked_submitters.fetch_add(1, Ordering::SeqCst);
+        let reserved = loop {
+            if try_reserve() {
+                break true;
+            }
+            lock = match deadline {
+                None => self.room_available.wait(lock).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break false;
+                    }
+                    self.room_available.wait_timeout(lock, timeout).unwrap().0
+                }
+            };
+        };
+        self.blocked_submitters.fetch_sub(1, Ordering::SeqCst);
+        reserved
+    }
+
+    // Runs the worker in slot `ind`, handing every job it finds to `run`, until the queues are
+    // closed and every job is done, or until `retire` says the worker is no longer needed. That's
+    // asked with `false` after every job and before going to sleep, and with `true` after
+    // `keep_alive` without a job. Returns whether the worker retired.
+    pub(super) fn work(
+        &self,
+        ind: usize,
+        keep_alive: Duration,
+        mut run: impl FnMut(Task),
+        retire: impl Fn(bool) -> bool,
+    ) -> bool {
+        let deque = self.deques.read().unwrap()[ind]
+            .worker
+            .lock()
+            .unwrap()
+            .take();
+        let deque = deque.expect("two workers share a deque");
+        LOCAL.set(Some(Local {
+            queues: self,
+            deque,
+        }));
+        let _restore = Restore { queues: self, ind };
+
+        let mut idle = 0;
+        loop {
+            match self.find_job(ind) {
+                Some(job) => {
+                    idle = 0;
+                    run(job);
+                    if retire(false) {
+                        return true;
+                    }
+                }
+                // Going to sleep and being woken up again costs a lot more than a short job, so
+                // give the submitters a few chances to come up with one first.
+                None if idle < SPINS => {
+                    idle += 1;
+                    thread::yield_now();
+                }
+                None => {
+                    // Reading the nudges first, so that a nudge after the check wakes us up.
+                    let nudges = self.nudges.load(Ordering::SeqCst);
+                    if retire(false) {
+                        return true;
+                    }
+                    match self.wait_for_work(keep_alive, nudges) {
+                        Wake::Work => idle = 0,
+                        Wake::Nudged if retire(false) => return true,
+                        Wake::TimedOut if retire(true) => return true,
+                        Wake::Nudged | Wake::TimedOut => {}
+                        Wake::Closed => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    // Only on the worker in slot `ind`.
+    pub(super) fn find_job(&self, ind: usize) -> Option<Task> {
+        LOCAL.with_borrow(|local| {
+            let deque = &local.as_ref().unwrap().deque;
+            let deques = self.deques.read().unwrap();
+            let job = deque.pop().or_else(|| {
+                // `Retry` means we lost a race for a job, and there may be more to be had.
+                loop {
+                    let mut retry = false;
+                    match self.injector.steal() {
+                        Steal::Success(job) => {
+                            self.made_room();
+                            return Some(job);
+                        }
+                        Steal::Retry => retry = true,
+                        Steal::Empty => {}
+                    }
+                    // Starting after ourselves, so that thieves spread out over their victims.
+                    // Taking half of the victim's jobs saves coming back for the rest.
+                    let others = (ind + 1..deques.len()).chain(0..ind);
+                    for other in others {
+                        match deques[other].stealer.steal_batch_and_pop(deque) {
+                            Steal::Success(job) => return Some(job),
+                            Steal::Retry => retry = true,
+                            Steal::Empty => {}
+                        }
+                    }
+                    if !retry {
+                        return None;
+                    }
+                }
+            });
+            if job.is_some() {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            job
+        })
+    }
+
+    fn made_room(&self) {
+        self.injected.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked_submitters.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.room_available.notify_one();
+        }
+    }
+
+    // Sleeps while there's nothing to do, for up to `keep_alive`. A job that was counted but not
+    // pushed yet makes this return straight away, so the worker spins briefly until it shows up.
+    // Any nudge after the `nudges`th wakes it up as well.
+    fn wait_for_work(&self, keep_alive: Duration, nudges: usize) -> Wake {
+        let deadline = Instant::now().checked_add(keep_alive);
+        let mut lock = self.lock.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        let wake = loop {
+            if self.pending.load(Ordering::SeqCst) > 0 {
+                break Wake::Work;
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                break Wake::Closed;
+            }
+            if self.nudges.load(Ordering::SeqCst) != nudges {
+                break Wake::Nudged;
+            }
+            lock = match deadline {
+                None => self.work_available.wait(lock).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break Wake::TimedOut;
+                    }
+                    self.work_available.wait_timeout(lock, timeout).unwrap().0
+                }
+            };
+        };
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        wake
+    }
+
+    // Wakes up every sleeping worker to check whether it's still needed.
+    pub(super) fn nudge(&self) {
+        self.nudges.fetch_add(1, Ordering::SeqCst);
+        let _lock = self.lock.lock().unwrap();
+        self.work_available.notify_all();
+    }
+
+    // Lets the workers stop once they've run out of jobs.
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _lock = self.lock.lock().unwrap();
+        self.work_available.notify_all();
+    }
+}
+
+// Puts a worker's deque back into its slot when the worker stops, including by panicking.
+struct Restore<'a> {
+    queues: &'a Queues,
+    ind: usize,
+}
+
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        if let Some(local) = LOCAL.take() {
+            let deques = self.queues.deques.read().unwrap();
+            *deques[self.ind].worker.lock().unwrap() = Some(local.deque);
+        }
+    }
+}
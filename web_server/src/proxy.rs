@@ -0,0 +1,432 @@
+use std::{
+    io,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use smol::{
+    io::{AsyncWriteExt, BufReader},
+    Async,
+};
+
+use crate::{
+    http::{Method, Request, Response},
+    router::{timeout, BoxFuture, Handler},
+};
+
+// Headers that only apply to a single connection and must not be forwarded (RFC 9110 7.6.1).
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    // The backend with the fewest requests in flight; round-robin among equals.
+    LeastConnections,
+}
+
+struct Backend {
+    addr: String,
+    active: AtomicUsize,
+    // Consecutive failures since the last success.
+    failures: AtomicU32,
+    // Set once `max_fails` is reached. The backend is skipped until then, unless every
+    // backend is down.
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn is_up(&self, now: Instant) -> bool {
+        match *self.down_until.lock().unwrap() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        *self.down_until.lock().unwrap() = None;
+    }
+
+    fn record_failure(&self, max_fails: u32, fail_timeout: Duration) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= max_fails {
+            eprintln!(
+                "Backend {} failed {failures} times; marking it down",
+                self.addr
+            );
+            self.failures.store(0, Ordering::Relaxed);
+            *self.down_until.lock().unwrap() = Some(Instant::now() + fail_timeout);
+        }
+    }
+}
+
+struct Pool {
+    backends: Vec<Backend>,
+    balance: Balance,
+    next: AtomicUsize,
+    max_fails: u32,
+    fail_timeout: Duration,
+}
+
+impl Pool {
+    // Picks a backend that hasn't been `tried` yet for this request.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let untried: Vec<usize> = (0..self.backends.len())
+            .filter(|ind| !tried.contains(ind))
+            .collect();
+        let healthy: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|ind| self.backends[*ind].is_up(now))
+            .collect();
+        // With every backend down, trying one anyway is the only way to find out it recovered.
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let rotated =
+            (0..candidates.len()).map(|offset| candidates[(start + offset) % candidates.len()]);
+        match self.balance {
+            Balance::RoundRobin => rotated.into_iter().next(),
+            Balance::LeastConnections => {
+                rotated.min_by_key(|ind| self.backends[*ind].active.load(Ordering::Relaxed))
+            }
+        }
+    }
+}
+
+enum ForwardError {
+    // Nothing was sent, so the request can safely go to another backend.
+    Connect(io::Error),
+    // The backend took too long to respond.
+    Timeout,
+    Io(io::Error),
+}
+
+// Forwards requests to a set of `host:port` backends.
+//
+//     Router::new().route(Method::Get, "/api/*", Proxy::new(&["127.0.0.1:9000", "127.0.0.1:9001"]))
+pub struct Proxy {
+    pool: Arc<Pool>,
+    strip_prefix: Option<String>,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(backends: &[&str]) -> Proxy {
+        assert!(!backends.is_empty(), "a proxy needs at least one backend");
+
+        let backends = backends
+            .iter()
+            .map(|addr| Backend {
+                addr: addr.to_string(),
+                active: AtomicUsize::new(0),
+                failures: AtomicU32::new(0),
+                down_until: Mutex::new(None),
+            })
+            .collect();
+
+        Proxy {
+            pool: Arc::new(Pool {
+                backends,
+                balance: Balance::RoundRobin,
+                next: AtomicUsize::new(0),
+                max_fails: 3,
+                fail_timeout: Duration::from_secs(10),
+            }),
+            strip_prefix: None,
+            connect_timeout: Duration::from_secs(2),
+            response_timeout: Duration::from_secs(30),
+        }
+    }
+
+    // The pool is only shared once requests come in, so it can still be changed in place here.
+    fn pool_mut(&mut self) -> &mut Pool {
+        Arc::get_mut(&mut self.pool).expect("proxy is already serving requests")
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Proxy {
+        self.pool_mut().balance = balance;
+        self
+    }
+
+    // A backend that fails `max_fails` times in a row is taken out of rotation for `fail_timeout`.
+    pub fn health(mut self, max_fails: u32, fail_timeout: Duration) -> Proxy {
+        let pool = self.pool_mut();
+        pool.max_fails = max_fails.max(1);
+        pool.fail_timeout = fail_timeout;
+        self
+    }
+
+    // Removes `prefix` from the path before forwarding, e.g. `/api/users` => `/users`.
+    pub fn strip_prefix(mut self, prefix: &str) -> Proxy {
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn timeouts(mut self, connect: Duration, response: Duration) -> Proxy {
+        self.connect_timeout = connect;
+        self.response_timeout = response;
+        self
+    }
+
+    fn upstream_request(&self, request: &Request) -> Vec<u8> {
+        let mut path = request.path.as_str();
+        if let Some(rest) = self
+            .strip_prefix
+            .as_deref()
+            .and_then(|prefix| path.strip_prefix(prefix))
+            // Whole segments only: `/api` isn't a prefix of `/apiary`.
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        {
+            path = if rest.is_empty() { "/" } else { rest };
+        }
+        let query = match &request.query {
+            Some(query) => format!("?{query}"),
+            None => String::new(),
+        };
+
+        let mut headers = request.headers.clone();
+        // Headers listed in `Connection` are hop-by-hop too.
+        if let Some(connection) = request.header("Connection") {
+            for name in connection.split(',') {
+                headers.remove(name.trim());
+            }
+        }
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+
+        if let Some(peer_addr) = request.peer_addr {
+            let forwarded_for = match request.header("X-Forwarded-For") {
+                Some(existing) => format!("{existing}, {}", peer_addr.ip()),
+                None => peer_addr.ip().to_string(),
+            };
+            headers.insert("X-Forwarded-For", forwarded_for);
+        }
+        headers.insert(
+            "X-Forwarded-Proto",
+            if request.secure { "https" } else { "http" },
+        );
+        if let Some(host) = request.header("Host") {
+            headers.insert("X-Forwarded-Host", host);
+        }
+        headers.insert("Connection", "close");
+        headers.remove("Content-Length");
+        if !request.body.is_empty() {
+            headers.insert("Content-Length", request.body.len().to_string());
+        }
+
+        let mut head = format!("{} {path}{query} HTTP/1.1\r\n", request.method);
+        for (name, value) in headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&request.body);
+        bytes
+    }
+}
+
+impl Handler for Proxy {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        // Only a body we have in full can be passed on.
+        if request.body_reader.is_some() {
+            return Box::pin(async { Response::new(413).with_body("Payload Too Large") });
+        }
+        let upstream_request = self.upstream_request(&request);
+        let head = request.method == Method::Head;
+        let pool = Arc::clone(&self.pool);
+        let (connect_timeout, response_timeout) = (self.connect_timeout, self.response_timeout);
+
+        Box::pin(async move {
+            let mut tried = Vec::new();
+            while let Some(ind) = pool.pick(&tried) {
+                tried.push(ind);
+                let backend = &pool.backends[ind];
+
+                backend.active.fetch_add(1, Ordering::Relaxed);
+                let result = forward(
+                    &backend.addr,
+                    &upstream_request,
+                    head,
+                    connect_timeout,
+                    response_timeout,
+                )
+                .await;
+                backend.active.fetch_sub(1, Ordering::Relaxed);
+
+                match result {
+                    Ok(response) => {
+                        backend.record_success();
+                        return response;
+                    }
+                    Err(err) => {
+                        backend.record_failure(pool.max_fails, pool.fail_timeout);
+                        match err {
+                            ForwardError::Connect(err) => {
+                                eprintln!("Failed to connect to {}: {err}", backend.addr);
+                                continue;
+                            }
+                            ForwardError::Timeout => {
+                                eprintln!("Backend {} timed out", backend.addr);
+                                return Response::new(504).with_body("Gateway Timeout");
+                            }
+                            ForwardError::Io(err) => {
+                                eprintln!("Backend {} failed: {err}", backend.addr);
+                                return Response::new(502).with_body("Bad Gateway");
+                            }
+                        }
+                    }
+                }
+            }
+
+            Response::new(502).with_body("Bad Gateway")
+        })
+    }
+}
+
+async fn forward(
+    addr: &str,
+    request: &[u8],
+    head: bool,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+) -> Result<Response, ForwardError> {
+    let stream = timeout(connect_timeout, connect(addr))
+        .await
+        .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into()))
+        .map_err(ForwardError::Connect)?;
+
+    let exchange = async {
+        let mut reader = BufReader::new(stream);
+        reader.write_all(request).await?;
+        reader.flush().await?;
+        Response::read_from(&mut reader, head).await
+    };
+    let mut response = timeout(response_timeout, exchange)
+        .await
+        .ok_or(ForwardError::Timeout)?
+        .map_err(ForwardError::Io)?;
+
+    for name in HOP_BY_HOP {
+        response.headers.remove(name);
+    }
+    Ok(response)
+}
+
+async fn connect(addr: &str) -> io::Result<Async<TcpStream>> {
+    // Resolve on a blocking thread; `to_socket_addrs` may hit DNS.
+    let addr = addr.to_string();
+    let addrs = smol::unblock(move || std::net::ToSocketAddrs::to_socket_addrs(&addr)).await?;
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+    for addr in addrs {
+        match Async::<TcpStream>::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    #[test]
+    fn round_robin_skips_backends_that_are_down() {
+        let proxy = Proxy::new(&["a:1", "b:1", "c:1"]).health(1, Duration::from_secs(60));
+        let pool = &proxy.pool;
+
+        pool.backends[1].record_failure(pool.max_fails, pool.fail_timeout);
+        let picks: Vec<usize> = (0..4).map(|_| pool.pick(&[]).unwrap()).collect();
+        assert_eq!(picks, [0, 2, 0, 2]);
+
+        // Once nothing else is left, a down backend is still tried.
+        assert_eq!(pool.pick(&[0, 2]), Some(1));
+        assert_eq!(pool.pick(&[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_backends() {
+        let proxy = Proxy::new(&["a:1", "b:1"]).balance(Balance::LeastConnections);
+        proxy.pool.backends[0].active.store(3, Ordering::Relaxed);
+
+        for _ in 0..3 {
+            assert_eq!(proxy.pool.pick(&[]), Some(1));
+        }
+    }
+
+    #[test]
+    fn rewrites_request_for_upstream() {
+        let proxy = Proxy::new(&["a:1"]).strip_prefix("/api/");
+        let mut request = Request::new(Method::Post, "/api/users?page=2");
+        request.peer_addr = Some("10.0.0.2:5000".parse().unwrap());
+        request.headers.insert("Host", "example.com");
+        request
+            .headers
+            .insert("Connection", "keep-alive, X-Private");
+        request.headers.insert("X-Private", "secret");
+        request.headers.insert("X-Forwarded-For", "192.168.1.1");
+        request.body = b"{}".to_vec();
+
+        let upstream = String::from_utf8(proxy.upstream_request(&request)).unwrap();
+
+        assert!(
+            upstream.starts_with("POST /users?page=2 HTTP/1.1\r\n"),
+            "{upstream}"
+        );
+        assert!(
+            upstream.contains("X-Forwarded-For: 192.168.1.1, 10.0.0.2\r\n"),
+            "{upstream}"
+        );
+        assert!(
+            upstream.contains("X-Forwarded-Proto: http\r\n"),
+            "{upstream}"
+        );
+        assert!(
+            upstream.contains("X-Forwarded-Host: example.com\r\n"),
+            "{upstream}"
+        );
+        assert!(upstream.contains("Connection: close\r\n"), "{upstream}");
+        assert!(!upstream.contains("X-Private"), "{upstream}");
+        assert!(
+            upstream.ends_with("Content-Length: 2\r\n\r\n{}"),
+            "{upstream}"
+        );
+    }
+
+    #[test]
+    fn strips_whole_path_segments() {
+        let proxy = Proxy::new(&["a:1"]).strip_prefix("/api/");
+        let line = |path: &str| {
+            let upstream = proxy.upstream_request(&Request::new(Method::Get, path));
+            let upstream = String::from_utf8(upstream).unwrap();
+            upstream.lines().next().unwrap().to_string()
+        };
+
+        assert_eq!(line("/api"), "GET / HTTP/1.1");
+        assert_eq!(line("/api/x"), "GET /x HTTP/1.1");
+        assert_eq!(line("/apiary/x"), "GET /apiary/x HTTP/1.1");
+    }
+}
@@ -0,0 +1,200 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use smol::Timer;
+
+use crate::{
+    http::{Method, Request, Response},
+    middleware::{Middleware, Next, Stack},
+};
+
+// Handlers are async so that the same handler can run on both backends.
+// On the threaded backend the future is simply blocked on by the worker, while the async backend
+// can interleave many of them on a single thread.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+// `None` if `future` didn't finish within `duration`.
+pub(crate) async fn timeout<T>(duration: Duration, future: impl Future<Output = T>) -> Option<T> {
+    smol::future::or(async { Some(future.await) }, async {
+        Timer::after(duration).await;
+        None
+    })
+    .await
+}
+
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request) -> BoxFuture<Response>;
+}
+
+// Lets plain closures such as `|req| async move { ... }` be used as handlers.
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        Box::pin(self(request))
+    }
+}
+
+struct Route {
+    method: Method,
+    path: String,
+    handler: Arc<dyn Handler>,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Arc<dyn Handler>,
+    middleware: Stack,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: Arc::new(|_| async { Response::new(404).with_body("Not Found") }),
+            middleware: Stack::default(),
+        }
+    }
+
+    pub fn route(mut self, method: Method, path: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Post, path, handler)
+    }
+
+    // Handler for requests that no route matched.
+    pub fn fallback(mut self, handler: impl Handler) -> Router {
+        self.fallback = Arc::new(handler);
+        self
+    }
+
+    // Runs `middleware` around every request this router handles, whether or not a route matched.
+    // Middleware runs in the order it was added: the first one added sees the request first and
+    // the response last.
+    pub fn layer(mut self, middleware: impl Middleware) -> Router {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    // HEAD goes to the GET route for the path unless it has a route of its own. The server leaves
+    // out the body.
+    fn endpoint(&self, request: &Request) -> Arc<dyn Handler> {
+        let mut path_matched = false;
+        let mut get = None;
+        for route in &self.routes {
+            if !path_matches(&route.path, &request.path) {
+                continue;
+            }
+            if route.method == request.method {
+                return Arc::clone(&route.handler);
+            }
+            if route.method == Method::Get && get.is_none() {
+                get = Some(&route.handler);
+            }
+            path_matched = true;
+        }
+
+        if let (Method::Head, Some(handler)) = (&request.method, get) {
+            return Arc::clone(handler);
+        }
+        if path_matched {
+            return Arc::new(|_| async { Response::new(405).with_body("Method Not Allowed") });
+        }
+        Arc::clone(&self.fallback)
+    }
+}
+
+// Paths match exactly, except that a trailing `/*` matches the prefix and anything below it:
+// `/api/*` matches `/api`, `/api/` and `/api/users/1`, but not `/apis`.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        },
+        None => pattern == path,
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+// A router is itself a handler so that routers can be nested or wrapped.
+impl Handler for Router {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let endpoint = self.endpoint(&request);
+        Next::new(Arc::clone(&self.middleware), endpoint).run(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_of(router: &Router, method: Method, path: &str) -> u16 {
+        smol::block_on(router.call(Request::new(method, path))).status
+    }
+
+    #[test]
+    fn dispatches_on_method_and_path() {
+        let router = Router::new()
+            .get("/", |_| async { Response::new(200) })
+            .post("/", |_| async { Response::new(201) });
+
+        assert_eq!(status_of(&router, Method::Get, "/"), 200);
+        assert_eq!(status_of(&router, Method::Post, "/"), 201);
+        assert_eq!(status_of(&router, Method::Delete, "/"), 405);
+        assert_eq!(status_of(&router, Method::Get, "/missing"), 404);
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = Router::new()
+            .get("/", |_| async { Response::new(200) })
+            .post("/form", |_| async { Response::new(201) })
+            .route(Method::Head, "/own", |_| async { Response::new(204) })
+            .get("/own", |_| async { Response::new(200) });
+
+        assert_eq!(status_of(&router, Method::Head, "/"), 200);
+        assert_eq!(status_of(&router, Method::Head, "/form"), 405);
+        assert_eq!(status_of(&router, Method::Head, "/own"), 204);
+    }
+
+    #[test]
+    fn wildcard_routes_match_prefixes() {
+        assert!(path_matches("/api/*", "/api"));
+        assert!(path_matches("/api/*", "/api/users/1"));
+        assert!(!path_matches("/api/*", "/apis"));
+        assert!(path_matches("/*", "/anything"));
+        assert!(!path_matches("/api", "/api/users"));
+    }
+
+    #[test]
+    fn layers_wrap_matched_and_unmatched_requests() {
+        let router = Router::new()
+            .get("/", |_| async { Response::new(200) })
+            .layer(|request, next: Next| async move {
+                next.run(request).await.with_header("X-Layered", "yes")
+            });
+
+        for path in ["/", "/missing"] {
+            let response = smol::block_on(router.call(Request::new(Method::Get, path)));
+            assert_eq!(response.headers.get("X-Layered"), Some("yes"));
+        }
+    }
+}
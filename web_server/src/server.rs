@@ -0,0 +1,629 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_rustls::TlsAcceptor;
+use smol::{
+    future,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    stream::StreamExt,
+    Async, Executor, Timer,
+};
+
+use crate::{
+    access_log::{AccessLog, LogEntry},
+    http::{BodyReader, BodyStream, Method, Request, Response, MAX_BODY_SIZE},
+    http2,
+    limits::{ConnectionLimit, ConnectionPermit},
+    metrics::Metrics,
+    middleware::{Middleware, Next, Stack},
+    router::{timeout, BoxFuture, Handler},
+    ThreadPool,
+};
+
+// How long the accept loops pause when we're out of file descriptors.
+const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
+
+// The same handler can be served by either backend:
+// - `serve_threaded` hands each connection to a `ThreadPool` worker, which owns it until the
+//   response is written. A slow handler occupies that worker the whole time.
+// - `serve_async` multiplexes every connection over non-blocking sockets on a small executor, so a
+//   handler that is waiting (e.g. on a timer) doesn't hold on to a thread.
+//
+// Either way, clients can speak HTTP/1.1 or HTTP/2 to the same handler. HTTP/2 is picked with
+// ALPN during the TLS handshake, or by sending its preface straight away over plain TCP.
+//
+// Cloning is cheap; every connection gets its own clone.
+#[derive(Clone)]
+pub struct Server {
+    handler: Arc<dyn Handler>,
+    middleware: Stack,
+    tls: Option<TlsAcceptor>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    connection_limit: Option<Arc<ConnectionLimit>>,
+    queue_timeout: Option<Duration>,
+    max_body_size: usize,
+}
+
+impl Server {
+    pub fn new(handler: impl Handler) -> Server {
+        Server {
+            handler: Arc::new(handler),
+            middleware: Stack::default(),
+            tls: None,
+            access_log: None,
+            metrics: None,
+            read_timeout: None,
+            write_timeout: None,
+            connection_limit: None,
+            queue_timeout: None,
+            max_body_size: MAX_BODY_SIZE,
+        }
+    }
+
+    // Runs `middleware` around the handler for every request, outside of any router-level
+    // middleware. The first one added sees the request first and the response last.
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> Server {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    // Terminate TLS on every accepted connection before reading the request.
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Server {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Server {
+        self.access_log = Some(Arc::new(access_log));
+        self
+    }
+
+    // Records every request in `metrics`. Serve them with a `MetricsEndpoint` route.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Server {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Answers 408 to clients that take longer than `read` to send their request, and gives up on
+    // those that take longer than `write` to take the response.
+    pub fn with_timeouts(mut self, read: Duration, write: Duration) -> Server {
+        self.read_timeout = Some(read);
+        self.write_timeout = Some(write);
+        self
+    }
+
+    // Turns away connections over `limit` as soon as they are accepted.
+    pub fn with_connection_limit(mut self, limit: ConnectionLimit) -> Server {
+        self.connection_limit = Some(Arc::new(limit));
+        self
+    }
+
+    // How long the threaded backend's accept loop waits for a worker to take a new connection
+    // before answering it with 503 Service Unavailable itself. Without this, it waits for as long
+    // as it takes, and meanwhile accepts nothing else.
+    pub fn with_queue_timeout(mut self, timeout: Duration) -> Server {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    // Requests with a bigger body than `bytes` are answered with 413 Payload Too Large before any
+    // of it is read. Handlers get the whole body at once, so it's held in memory until they're
+    // done. Defaults to 1 MiB. Uploads (`multipart/form-data`) over the limit are still let
+    // through, and streamed to the handler (see `BodyReader`).
+    pub fn with_max_body_size(mut self, bytes: usize) -> Server {
+        self.max_body_size = bytes;
+        self
+    }
+
+    pub fn serve_threaded(&self, listener: TcpListener, pool: &ThreadPool) -> io::Result<()> {
+        serve_threaded(listener, pool, || self.clone())
+    }
+
+    // `threads` extra executor threads are spawned; the calling thread also runs tasks.
+    pub fn serve_async(&self, listener: TcpListener, threads: usize) -> io::Result<()> {
+        serve_async(listener, threads, || self.clone())
+    }
+
+    // Checked in the accept loop, so that a client over the limits can't occupy a worker even
+    // briefly. `Ok(None)` if there are no limits.
+    fn admit(&self, peer_addr: Option<SocketAddr>) -> Result<Option<ConnectionPermit>, Response> {
+        match (&self.connection_limit, peer_addr) {
+            (Some(limit), Some(peer_addr)) => limit.acquire(peer_addr.ip()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // Answers without waiting for the request. The response fits in the socket's send buffer, so
+    // this doesn't block the accept loop.
+    //
+    // A TLS client expects a handshake rather than a plain response, and a handshake would block
+    // the accept loop, so those connections are closed without a word.
+    fn reject(&self, mut stream: &TcpStream, mut response: Response) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_request(response.status, Duration::ZERO);
+        }
+        if self.tls.is_some() {
+            return;
+        }
+        response.headers.insert("Connection", "close");
+        let _ = stream
+            .write_all(&response.head_bytes())
+            .and_then(|_| stream.write_all(&response.body));
+
+        // Closing a socket with unread data makes the kernel reset the connection, which can
+        // discard the response before the client reads it. Throw away whatever has already
+        // arrived, without waiting for more.
+        let _ = stream.shutdown(Shutdown::Write);
+        if stream.set_nonblocking(true).is_ok() {
+            let mut buf = [0; 4096];
+            while matches!(stream.read(&mut buf), Ok(read) if read > 0) {}
+        }
+    }
+
+    async fn accept_connection(&self, stream: Async<TcpStream>, peer_addr: Option<SocketAddr>) {
+        let _connection = self
+            .metrics
+            .as_ref()
+            .map(|metrics| metrics.track_connection());
+
+        match &self.tls {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let connection = stream.get_ref().1;
+                    let server_name = connection.server_name().map(String::from);
+                    if connection.alpn_protocol() == Some(b"h2") {
+                        let reader = BufReader::new(stream);
+                        self.serve_http2(reader, peer_addr, true, server_name, http2::PREFACE)
+                            .await
+                    } else {
+                        self.serve_connection(stream, peer_addr, true, server_name)
+                            .await
+                    }
+                }
+                Err(err) => eprintln!("TLS handshake with {peer_addr:?} failed: {err}"),
+            },
+            None => self.serve_connection(stream, peer_addr, false, None).await,
+        }
+    }
+
+    // Reads one request, runs it through the handler and writes back the response. Hands over to
+    // HTTP/2 if the "request" turns out to be the start of its preface.
+    async fn serve_connection<S>(
+        &self,
+        stream: S,
+        peer_addr: Option<SocketAddr>,
+        secure: bool,
+        server_name: Option<String>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut reader = BufReader::new(stream);
+        let start = Instant::now();
+        let mut entry = new_entry(peer_addr);
+        // Responses to HEAD carry the headers of a GET, but no body.
+        let mut head = false;
+
+        let read = async {
+            let Some(mut request) = Request::read_head(&mut reader).await? else {
+                return Ok(None);
+            };
+            // Uploads too big to buffer are read while the handler runs instead.
+            let streamed = request.streamed_length(self.max_body_size);
+            if streamed.is_none() {
+                request.read_body(&mut reader, self.max_body_size).await?;
+            }
+            io::Result::Ok(Some((request, streamed)))
+        };
+        let read = match self.read_timeout {
+            Some(duration) => timeout(duration, read)
+                .await
+                .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into())),
+            None => read.await,
+        };
+
+        let mut response = match read {
+            Ok(Some((request, _))) if http2::is_preface(&request) => {
+                return self
+                    .serve_http2(reader, peer_addr, secure, server_name, b"SM\r\n\r\n")
+                    .await;
+            }
+            Ok(Some((mut request, streamed))) => {
+                request.peer_addr = peer_addr;
+                request.secure = secure;
+                request.server_name = server_name;
+                // The handler takes ownership of the request, so note what we log up front.
+                describe(&mut entry, &request);
+                head = request.method == Method::Head;
+
+                let next = Next::new(Arc::clone(&self.middleware), Arc::clone(&self.handler));
+                match streamed {
+                    Some(length) => {
+                        let (body, feed) = BodyReader::new(&mut reader, length, self.read_timeout);
+                        request.body_reader = Some(body);
+                        // Done as soon as the handler is, whether it read the whole body or not.
+                        let feed = async {
+                            feed.await;
+                            future::pending().await
+                        };
+                        future::or(next.run(request), feed).await
+                    }
+                    None => next.run(request).await,
+                }
+            }
+            Ok(None) => return,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                Response::new(408).with_body("Request Timeout")
+            }
+            Err(err) if err.kind() == io::ErrorKind::FileTooLarge => {
+                Response::too_large(self.max_body_size)
+            }
+            Err(err) => {
+                eprintln!("Bad request from {peer_addr:?}: {err}");
+                Response::new(400).with_body("Bad Request")
+            }
+        };
+        entry.status = response.status;
+        entry.bytes = if head { 0 } else { response.body.len() };
+        let upgrade = response.upgrade.take();
+        let mut streamed = response.stream.take();
+        if streamed.is_some() {
+            response.headers.insert("Transfer-Encoding", "chunked");
+            if head {
+                streamed = None;
+            }
+        }
+
+        // `BufReader` passes writes straight through to the stream.
+        let write = write_response(&mut reader, response, upgrade.is_some(), head);
+        let written = match self.write_timeout {
+            Some(duration) => timeout(duration, write)
+                .await
+                .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into())),
+            None => write.await,
+        };
+        if let Err(err) = written {
+            eprintln!("Failed to write response to {peer_addr:?}: {err}");
+            return;
+        }
+        if let Some(body) = streamed {
+            entry.bytes = self.write_stream(&mut reader, body).await;
+        }
+
+        entry.latency = start.elapsed();
+        self.record(&entry);
+
+        match upgrade {
+            // The reader is handed over as-is since it may already hold bytes the client sent
+            // right after the request. On the threaded backend the worker stays busy until the
+            // upgraded connection is done.
+            Some(upgrade) => upgrade.run(Box::new(reader)).await,
+            // Only hang up once the request is accounted for, so that a client which saw the
+            // connection close also sees its request in the logs and metrics.
+            // Closing (rather than just dropping) also sends the TLS close_notify alert.
+            None => {
+                let _ = reader.close().await;
+            }
+        }
+    }
+
+    // Checks the rest of the preface (`expected`), then serves requests on the connection until
+    // the client is done with it. Each request is logged once its handler is done, since there's
+    // no single point where a multiplexed connection has written "the" response.
+    async fn serve_http2<S>(
+        &self,
+        mut reader: BufReader<S>,
+        peer_addr: Option<SocketAddr>,
+        secure: bool,
+        server_name: Option<String>,
+        expected: &[u8],
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut preface = vec![0; expected.len()];
+        let read = reader.read_exact(&mut preface);
+        let read = match self.read_timeout {
+            Some(duration) => timeout(duration, read)
+                .await
+                .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into())),
+            None => read.await,
+        };
+        if read.is_err() || preface != expected {
+            eprintln!("Bad HTTP/2 preface from {peer_addr:?}");
+            return;
+        }
+
+        let handle = |mut request: Request| {
+            request.peer_addr = peer_addr;
+            request.secure = secure;
+            request.server_name = server_name.clone();
+            let server = self.clone();
+            let response: BoxFuture<Response> = Box::pin(async move {
+                let start = Instant::now();
+                let mut entry = new_entry(peer_addr);
+                describe(&mut entry, &request);
+
+                let response =
+                    Next::new(Arc::clone(&server.middleware), Arc::clone(&server.handler))
+                        .run(request)
+                        .await;
+                entry.status = response.status;
+                entry.bytes = response.body.len();
+                entry.latency = start.elapsed();
+                server.record(&entry);
+                response
+            });
+            response
+        };
+        let served = http2::serve(
+            reader,
+            self.read_timeout,
+            self.write_timeout,
+            self.max_body_size,
+            handle,
+        );
+        if let Err(err) = served.await {
+            eprintln!("Failed to write to {peer_addr:?}: {err}");
+        }
+    }
+
+    // Sends each chunk of `body` as soon as it's produced, until it ends or the client hangs up
+    // or stops taking it. Returns how many bytes of it were sent.
+    async fn write_stream<S>(&self, stream: &mut BufReader<S>, mut body: BodyStream) -> usize
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut sent = 0;
+        loop {
+            // The client has nothing more to send after its request, so anything it does send
+            // (EOF, most likely) means it's gone. That's noticed even while the body is idle.
+            let next = future::or(async { Some(body.next().await) }, async {
+                let mut buf = [0; 512];
+                while matches!(stream.read(&mut buf).await, Ok(read) if read > 0) {}
+                None
+            });
+            let chunk = match next.await {
+                Some(Some(chunk)) if chunk.is_empty() => continue,
+                Some(Some(chunk)) => chunk,
+                Some(None) => {
+                    // The last chunk is an empty one.
+                    let _ = stream.write_all(b"0\r\n\r\n").await;
+                    let _ = stream.flush().await;
+                    return sent;
+                }
+                None => return sent,
+            };
+
+            let write = async {
+                stream
+                    .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                    .await?;
+                stream.write_all(&chunk).await?;
+                stream.write_all(b"\r\n").await?;
+                stream.flush().await
+            };
+            let written = match self.write_timeout {
+                Some(duration) => timeout(duration, write).await.is_some_and(|r| r.is_ok()),
+                None => write.await.is_ok(),
+            };
+            if !written {
+                return sent;
+            }
+            sent += chunk.len();
+        }
+    }
+
+    fn record(&self, entry: &LogEntry) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_request(entry.status, entry.latency);
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.log(entry);
+        }
+    }
+}
+
+// Filled in as the request is served; `-` is what the log shows for anything we never learn.
+fn new_entry(peer_addr: Option<SocketAddr>) -> LogEntry {
+    LogEntry {
+        peer_addr,
+        time: SystemTime::now(),
+        method: String::from("-"),
+        target: String::from("-"),
+        version: String::from("-"),
+        status: 0,
+        bytes: 0,
+        referer: None,
+        user_agent: None,
+        latency: Default::default(),
+    }
+}
+
+// Notes what the access log needs to know about `request`.
+fn describe(entry: &mut LogEntry, request: &Request) {
+    entry.method = request.method.to_string();
+    entry.target = match &request.query {
+        Some(query) => format!("{}?{query}", request.path),
+        None => request.path.clone(),
+    };
+    entry.version = request.version.clone();
+    entry.referer = request.header("Referer").map(String::from);
+    entry.user_agent = request.header("User-Agent").map(String::from);
+}
+
+// A server that can be replaced while it is serving, e.g. when its configuration is reloaded.
+// Every connection is served start to finish by the server that was current when it was
+// accepted, so replacing it doesn't drop anything in flight; the listeners stay open throughout.
+#[derive(Clone)]
+pub struct Reloadable(Arc<RwLock<Server>>);
+
+impl Reloadable {
+    pub fn new(server: Server) -> Reloadable {
+        Reloadable(Arc::new(RwLock::new(server)))
+    }
+
+    // Serves connections accepted from now on with `server`.
+    pub fn replace(&self, server: Server) {
+        *self.0.write().unwrap() = server;
+    }
+
+    pub fn current(&self) -> Server {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn serve_threaded(&self, listener: TcpListener, pool: &ThreadPool) -> io::Result<()> {
+        serve_threaded(listener, pool, || self.current())
+    }
+
+    pub fn serve_async(&self, listener: TcpListener, threads: usize) -> io::Result<()> {
+        serve_async(listener, threads, || self.current())
+    }
+}
+
+// The accept loops ask `current` for the server to use for every new connection.
+fn serve_threaded(
+    listener: TcpListener,
+    pool: &ThreadPool,
+    current: impl Fn() -> Server,
+) -> io::Result<()> {
+    if let Some(metrics) = &current().metrics {
+        metrics.track_queue(pool.queue_depth());
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                if let Some(pause) = accept_failed(&err) {
+                    thread::sleep(pause);
+                }
+                continue;
+            }
+        };
+        let server = current();
+        let peer_addr = stream.peer_addr().ok();
+        let permit = match server.admit(peer_addr) {
+            Ok(permit) => permit,
+            Err(response) => {
+                server.reject(&stream, response);
+                continue;
+            }
+        };
+
+        // If the pool may turn the connection away, the job takes the stream with it. A second
+        // handle on the socket lets us answer the client anyway, or hang up on a TLS client.
+        let fallback = match server.queue_timeout {
+            Some(timeout) => stream
+                .try_clone()
+                .ok()
+                .map(|stream| (timeout, stream, server.clone())),
+            None => None,
+        };
+
+        let job = move || {
+            let _permit = permit;
+            // Wrapping the stream lets us share the connection code with the async backend.
+            // Blocking on it here means this worker is busy until the connection is done.
+            match Async::new(stream) {
+                Ok(stream) => smol::block_on(server.accept_connection(stream, peer_addr)),
+                Err(err) => eprintln!("Failed to register connection: {err}"),
+            }
+        };
+        match fallback {
+            Some((timeout, stream, server)) => {
+                if pool.execute_timeout(job, timeout).is_err() {
+                    let response = Response::new(503)
+                        .with_header("Retry-After", "1")
+                        .with_body("Service Unavailable");
+                    server.reject(&stream, response);
+                }
+            }
+            None => pool.execute(job),
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_async(
+    listener: TcpListener,
+    threads: usize,
+    current: impl Fn() -> Server,
+) -> io::Result<()> {
+    let executor = Arc::new(Executor::new());
+
+    for ind in 0..threads {
+        let executor = Arc::clone(&executor);
+        thread::Builder::new()
+            .name(format!("executor-{ind}"))
+            // The executor threads never have anything to wait for but the tasks themselves.
+            .spawn(move || smol::block_on(executor.run(smol::future::pending::<()>())))?;
+    }
+
+    let listener = Async::new(listener)?;
+    smol::block_on(executor.run(async {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    if let Some(pause) = accept_failed(&err) {
+                        Timer::after(pause).await;
+                    }
+                    continue;
+                }
+            };
+            let server = current();
+            let permit = match server.admit(Some(peer_addr)) {
+                Ok(permit) => permit,
+                Err(response) => {
+                    server.reject(stream.get_ref(), response);
+                    continue;
+                }
+            };
+            executor
+                .spawn(async move {
+                    let _permit = permit;
+                    server.accept_connection(stream, Some(peer_addr)).await
+                })
+                .detach();
+        }
+    }))
+}
+
+// Failing to accept a connection is no reason to stop accepting the rest: the client may have
+// given up already, or we're out of file descriptors until some connections close. Returns how
+// long to pause in the latter case, rather than spinning until then.
+fn accept_failed(err: &io::Error) -> Option<Duration> {
+    eprintln!("Failed to accept a connection: {err}");
+    // ENFILE and EMFILE.
+    matches!(err.raw_os_error(), Some(23 | 24)).then_some(ACCEPT_PAUSE)
+}
+
+async fn write_response<W>(
+    stream: &mut W,
+    mut response: Response,
+    upgrade: bool,
+    head: bool,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // We only ever serve one request per connection. Upgrades set their own `Connection`.
+    if !upgrade {
+        response.headers.insert("Connection", "close");
+    }
+
+    // `head_bytes` still counts the body in `Content-Length`, as the answer to a GET would.
+    stream.write_all(&response.head_bytes()).await?;
+    if !head {
+        stream.write_all(&response.body).await?;
+    }
+    stream.flush().await
+}
@@ -0,0 +1,261 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use smol::{
+    channel::{self, Receiver, Sender},
+    stream::Stream,
+    Timer,
+};
+
+use crate::{
+    http::{BodyStream, Response},
+    router::BoxFuture,
+};
+
+// Without anything going over it, a connection may be cut by proxies (or the client) that think
+// it's dead. A comment line every so often keeps it going without the client seeing an event.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// Events the handler has sent but the client hasn't taken yet. `send` waits while it's full.
+const BUFFERED_EVENTS: usize = 16;
+
+// One server-sent event. Only `data` is required.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    // The event type, which clients listen for with `addEventListener`. Defaults to "message".
+    pub fn with_event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    // Sent back by a reconnecting client as `Last-Event-ID`, so that it can pick up where it
+    // left off.
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    // How long the client should wait before reconnecting if the connection drops.
+    pub fn with_retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    // The wire format: a `field: value` line per field and a blank line to end the event.
+    // Every line of `data` gets its own `data:` field, since a newline would end the field early.
+    // Newlines in the other fields can't be sent at all and are dropped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            out.push_str(&format!(
+                "data: {}\n",
+                line.strip_suffix('\r').unwrap_or(line)
+            ));
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+// The client went away, so there's no point producing more events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the client disconnected")
+    }
+}
+
+impl Error for Disconnected {}
+
+// Sends events to one client. Clones can be handed to other tasks or threads, e.g. to broadcast
+// to every subscriber; once the client is gone, every send fails with `Disconnected`.
+#[derive(Debug, Clone)]
+pub struct EventSender(Sender<Vec<u8>>);
+
+impl EventSender {
+    pub async fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.0
+            .send(event.to_bytes())
+            .await
+            .map_err(|_| Disconnected)
+    }
+
+    // For threads that aren't running async code.
+    pub fn send_blocking(&self, event: Event) -> Result<(), Disconnected> {
+        self.0
+            .send_blocking(event.to_bytes())
+            .map_err(|_| Disconnected)
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+// Answers with an event stream, and runs `on_connect` to produce the events. The response ends
+// once `on_connect` is done and every sender is dropped. If the client goes away first,
+// `on_connect` is dropped (at whatever it was awaiting) and senders held elsewhere start failing.
+//
+//     router.get("/events", |_| async {
+//         sse::stream(|events| async move {
+//             while events.send(Event::new("tick")).await.is_ok() {
+//                 Timer::after(Duration::from_secs(1)).await;
+//             }
+//         })
+//     })
+pub fn stream<F, Fut>(on_connect: F) -> Response
+where
+    F: FnOnce(EventSender) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    stream_with_keep_alive(DEFAULT_KEEP_ALIVE, on_connect)
+}
+
+// Like `stream`, but with a keep-alive comment after every `interval` without events.
+pub fn stream_with_keep_alive<F, Fut>(interval: Duration, on_connect: F) -> Response
+where
+    F: FnOnce(EventSender) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (sender, receiver) = channel::bounded(BUFFERED_EVENTS);
+    let events = Events {
+        producer: Some(Box::pin(on_connect(EventSender(sender)))),
+        receiver: Box::pin(receiver),
+        keep_alive: Timer::after(interval),
+        interval,
+    };
+
+    Response::new(200)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        // Asks buffering proxies such as nginx to pass events on right away.
+        .with_header("X-Accel-Buffering", "no")
+        .with_stream(BodyStream::new(events))
+}
+
+// Drives the handler's producer along with the stream, so that it needs no task of its own: it
+// makes progress whenever the server waits for the next chunk, and is dropped with the stream.
+struct Events {
+    producer: Option<BoxFuture<()>>,
+    receiver: Pin<Box<Receiver<Vec<u8>>>>,
+    keep_alive: Timer,
+    interval: Duration,
+}
+
+impl Stream for Events {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        if let Some(producer) = &mut self.producer {
+            if producer.as_mut().poll(cx).is_ready() {
+                self.producer = None;
+            }
+        }
+
+        if let Poll::Ready(event) = self.receiver.as_mut().poll_next(cx) {
+            let interval = self.interval;
+            self.keep_alive.set_after(interval);
+            return Poll::Ready(event);
+        }
+
+        if Pin::new(&mut self.keep_alive).poll(cx).is_ready() {
+            let interval = self.interval;
+            self.keep_alive.set_after(interval);
+            return Poll::Ready(Some(b": keep-alive\n\n".to_vec()));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::stream::StreamExt;
+
+    #[test]
+    fn events_are_framed_line_by_line() {
+        let event = Event::new("first\nsecond\r\nthird")
+            .with_event("update")
+            .with_id("4\n2")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(
+            String::from_utf8(event.to_bytes()).unwrap(),
+            "event: update\nid: 42\nretry: 3000\ndata: first\ndata: second\ndata: third\n\n"
+        );
+        assert_eq!(Event::new("").to_bytes(), b"data: \n\n");
+    }
+
+    #[test]
+    fn stream_ends_with_the_producer_and_keeps_alive_meanwhile() {
+        let mut response = stream_with_keep_alive(Duration::from_millis(50), |events| async move {
+            events.send(Event::new("one")).await.unwrap();
+            Timer::after(Duration::from_millis(120)).await;
+            events.send(Event::new("two")).await.unwrap();
+        });
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/event-stream")
+        );
+
+        let body = response.stream.take().unwrap();
+        let chunks: Vec<Vec<u8>> = smol::block_on(body.collect());
+        assert_eq!(chunks.first().unwrap(), b"data: one\n\n");
+        assert_eq!(chunks.last().unwrap(), b"data: two\n\n");
+        let keep_alives = chunks
+            .iter()
+            .filter(|chunk| chunk.as_slice() == b": keep-alive\n\n")
+            .count();
+        // Every 50ms of the 120ms between the events; the timing is only roughly that.
+        assert!((1..=3).contains(&keep_alives), "{chunks:?}");
+    }
+
+    #[test]
+    fn senders_notice_the_client_leaving() {
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut response = stream(|events| async move {
+            sender.send(events).await.unwrap();
+        });
+        let mut body = response.stream.take().unwrap();
+
+        // Polled once, so that the producer hands its sender over.
+        smol::block_on(smol::future::poll_once(body.next()));
+        let events = receiver.try_recv().unwrap();
+        assert!(!events.is_disconnected());
+
+        drop(body);
+        assert!(events.is_disconnected());
+        assert_eq!(events.send_blocking(Event::new("late")), Err(Disconnected));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    compression::{self, Encoding},
+    http::{Method, Request, Response},
+    router::{BoxFuture, Handler},
+};
+
+// Serves files below `root`, mapping the request path onto the file system.
+// When `precompressed` is on and the client accepts it, `file.br` / `file.gz` siblings are sent
+// instead of `file`, so that nothing has to be compressed per request.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    precompressed: bool,
+    prefix: String,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index: String::from("index.html"),
+            precompressed: true,
+            prefix: String::new(),
+        }
+    }
+
+    // File served for requests that map to a directory.
+    pub fn index(mut self, name: &str) -> StaticFiles {
+        self.index = name.to_string();
+        self
+    }
+
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    // Removes `prefix` from request paths before mapping them onto the root, so that a
+    // `/assets/*` route can serve `public/app.js` for `/assets/app.js`.
+    pub fn strip_prefix(mut self, prefix: &str) -> StaticFiles {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    // Maps a request path onto a file below the root, refusing anything that would escape it.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let request_path = request_path
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or(request_path);
+        let relative = Path::new(request_path.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        let path = self.root.join(relative);
+        if path.is_dir() {
+            Some(path.join(&self.index))
+        } else {
+            Some(path)
+        }
+    }
+
+    fn serve(&self, request: &Request) -> Response {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return Response::new(405).with_header("Allow", "GET, HEAD");
+        }
+        let Some(path) = self.resolve(&request.path) else {
+            return Response::new(404).with_body("Not Found");
+        };
+        if !path.is_file() {
+            return Response::new(404).with_body("Not Found");
+        }
+
+        let content_type = content_type(&path);
+        let mut response = Response::new(200).with_header("Content-Type", content_type);
+
+        let encoding = if self.precompressed && compression::is_compressible(Some(content_type)) {
+            let available: Vec<Encoding> = [Encoding::Brotli, Encoding::Gzip]
+                .into_iter()
+                .filter(|encoding| sibling(&path, *encoding).is_file())
+                .collect();
+            // Even if we end up sending the plain file, caches must not serve it to everyone.
+            response.add_vary("Accept-Encoding");
+            compression::negotiate(request.header("Accept-Encoding"), &available)
+        } else {
+            Encoding::Identity
+        };
+
+        match fs::read(sibling(&path, encoding)) {
+            Ok(body) => {
+                if encoding != Encoding::Identity {
+                    response
+                        .headers
+                        .insert("Content-Encoding", encoding.token());
+                }
+                response.with_body(body)
+            }
+            Err(err) => {
+                eprintln!("Failed to read {}: {err}", path.display());
+                Response::new(500).with_body("Internal Server Error")
+            }
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let response = self.serve(&request);
+        Box::pin(async move { response })
+    }
+}
+
+// `index.html` + gzip => `index.html.gz`
+fn sibling(path: &Path, encoding: Encoding) -> PathBuf {
+    match encoding.extension() {
+        Some(extension) => {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".");
+            name.push(extension);
+            PathBuf::from(name)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let files = StaticFiles::new("/srv/www");
+        assert_eq!(files.resolve("/../etc/passwd"), None);
+        assert_eq!(files.resolve("/a/../../b"), None);
+        assert_eq!(
+            files.resolve("/css/site.css"),
+            Some(PathBuf::from("/srv/www/css/site.css"))
+        );
+    }
+
+    #[test]
+    fn strips_the_route_prefix() {
+        let files = StaticFiles::new("/srv/www").strip_prefix("/assets/");
+        assert_eq!(
+            files.resolve("/assets/css/site.css"),
+            Some(PathBuf::from("/srv/www/css/site.css"))
+        );
+    }
+
+    #[test]
+    fn siblings_get_encoding_extension() {
+        let path = Path::new("public/app.js");
+        assert_eq!(
+            sibling(path, Encoding::Gzip),
+            PathBuf::from("public/app.js.gz")
+        );
+        assert_eq!(
+            sibling(path, Encoding::Brotli),
+            PathBuf::from("public/app.js.br")
+        );
+        assert_eq!(
+            sibling(path, Encoding::Identity),
+            PathBuf::from("public/app.js")
+        );
+    }
+}
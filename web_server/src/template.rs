@@ -0,0 +1,697 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::http::{Request, Response};
+
+// Includes nested deeper than this are assumed to include each other in a loop.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// A small Jinja-like template language:
+//
+//     {{ user.name }}              a value, HTML-escaped
+//     {{ snippet | safe }}         a value as-is
+//     {% if user %}..{% else %}..{% endif %}, also `{% if not user %}`
+//     {% for item in items %}..{% endfor %}, with `loop.index`, `loop.first` and `loop.last`
+//     {% include "header.html" %}  another template, with the same values
+//     {# a comment #}
+//
+// Missing values render as nothing and are false in conditions.
+pub struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+// The values a template is rendered with.
+#[derive(Debug, Clone, Default)]
+pub struct Context(BTreeMap<String, Value>);
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(PathBuf, io::Error),
+    Syntax {
+        name: String,
+        line: usize,
+        message: String,
+    },
+    Render {
+        name: String,
+        message: String,
+    },
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value {
+        path: Vec<String>,
+        escape: bool,
+    },
+    If {
+        negate: bool,
+        path: Vec<String>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+    },
+    Include(String),
+}
+
+// `{{ .. }}` and `{% .. %}` come with the line they start on, for error messages.
+enum Token<'a> {
+    Text(&'a str),
+    Value(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+            TemplateError::Syntax {
+                name,
+                line,
+                message,
+            } => write!(f, "{name}:{line}: {message}"),
+            TemplateError::Render { name, message } => write!(f, "{name}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(value) => *value,
+            Value::Number(value) => *value != 0.0,
+            Value::Str(value) => !value.is_empty(),
+            Value::List(values) => !values.is_empty(),
+            Value::Map(values) => !values.is_empty(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Number(value) => write!(f, "{value}"),
+            Value::Str(value) => f.write_str(value),
+            Value::List(values) => {
+                for (ind, value) in values.iter().enumerate() {
+                    if ind > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+            Value::Map(_) => f.write_str("[map]"),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::Str(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value {
+        Value::Number(f64::from(value))
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context.0)
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    // `request.method`, `request.path` and `request.query`.
+    pub fn for_request(request: &Request) -> Context {
+        let details = Context::new()
+            .with("method", request.method.as_str())
+            .with("path", request.path.as_str())
+            .with("query", request.query.as_deref());
+        Context::new().with("request", details)
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.0.insert(name.to_string(), value.into());
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Context {
+        self.insert(name, value);
+        self
+    }
+}
+
+impl Template {
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let (nodes, _) = parse_nodes(name, &tokens, &mut 0, &[])?;
+        Ok(Template {
+            name: name.to_string(),
+            nodes,
+        })
+    }
+
+    // Renders a template on its own. Includes need `Templates::render`.
+    pub fn render(&self, context: &Context) -> Result<String, TemplateError> {
+        Renderer::new(None, context).render_template(self)
+    }
+}
+
+fn syntax_error(name: &str, line: usize, message: String) -> TemplateError {
+    TemplateError::Syntax {
+        name: name.to_string(),
+        line,
+        message,
+    }
+}
+
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<Token<'a>>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start..].get(..2) {
+            Some("{{") => "}}",
+            Some("{%") => "%}",
+            Some("{#") => "#}",
+            _ => {
+                // A lone brace is just text.
+                tokens.push(Token::Text(&rest[..=start]));
+                line += rest[..=start].matches('\n').count();
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+            line += rest[..start].matches('\n').count();
+        }
+        let inner = &rest[start + 2..];
+        let Some(length) = inner.find(close) else {
+            return Err(syntax_error(name, line, format!("missing `{close}`")));
+        };
+        match close {
+            "}}" => tokens.push(Token::Value(inner[..length].trim(), line)),
+            "%}" => tokens.push(Token::Tag(inner[..length].trim(), line)),
+            _ => {}
+        }
+        line += inner[..length].matches('\n').count();
+        rest = &inner[length + 2..];
+    }
+
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+// Parses nodes up to one of the `until` tags (e.g. `endif`), which is returned along with them.
+fn parse_nodes<'a>(
+    name: &str,
+    tokens: &[Token<'a>],
+    pos: &mut usize,
+    until: &[&str],
+) -> Result<(Vec<Node>, Option<&'a str>), TemplateError> {
+    let mut nodes = Vec::new();
+
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        match *token {
+            Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+            Token::Value(expr, line) => {
+                let (expr, escape) = match expr.split_once('|') {
+                    Some((expr, filter)) if filter.trim() == "safe" => (expr.trim(), false),
+                    Some((_, filter)) => {
+                        let message = format!("unknown filter `{}`", filter.trim());
+                        return Err(syntax_error(name, line, message));
+                    }
+                    None => (expr, true),
+                };
+                nodes.push(Node::Value {
+                    path: parse_path(name, line, expr)?,
+                    escape,
+                });
+            }
+            Token::Tag(tag, line) => {
+                let (keyword, args) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+                let args = args.trim();
+                if until.contains(&keyword) {
+                    return Ok((nodes, Some(keyword)));
+                }
+
+                match keyword {
+                    "if" => {
+                        let (negate, expr) = match args.strip_prefix("not ") {
+                            Some(expr) => (true, expr.trim()),
+                            None => (false, args),
+                        };
+                        let path = parse_path(name, line, expr)?;
+                        let (then, end) = parse_nodes(name, tokens, pos, &["else", "endif"])?;
+                        let otherwise = match end {
+                            Some("else") => parse_nodes(name, tokens, pos, &["endif"])?.0,
+                            _ => Vec::new(),
+                        };
+                        nodes.push(Node::If {
+                            negate,
+                            path,
+                            then,
+                            otherwise,
+                        });
+                    }
+                    "for" => {
+                        let words: Vec<&str> = args.split_whitespace().collect();
+                        let [var, "in", expr] = words[..] else {
+                            let message = String::from("expected `for <name> in <value>`");
+                            return Err(syntax_error(name, line, message));
+                        };
+                        let path = parse_path(name, line, expr)?;
+                        let (body, _) = parse_nodes(name, tokens, pos, &["endfor"])?;
+                        nodes.push(Node::For {
+                            var: var.to_string(),
+                            path,
+                            body,
+                        });
+                    }
+                    "include" => {
+                        let Some(target) = args
+                            .strip_prefix('"')
+                            .and_then(|args| args.strip_suffix('"'))
+                        else {
+                            let message = String::from("expected `include \"<template>\"`");
+                            return Err(syntax_error(name, line, message));
+                        };
+                        nodes.push(Node::Include(target.to_string()));
+                    }
+                    other => {
+                        let message = format!("unexpected `{{% {other} %}}`");
+                        return Err(syntax_error(name, line, message));
+                    }
+                }
+            }
+        }
+    }
+
+    match until.last() {
+        Some(end) => {
+            let message = format!("missing `{{% {end} %}}`");
+            Err(syntax_error(name, last_line(tokens), message))
+        }
+        None => Ok((nodes, None)),
+    }
+}
+
+// Where unclosed blocks are reported.
+fn last_line(tokens: &[Token]) -> usize {
+    let mut line = 1;
+    for token in tokens {
+        match token {
+            Token::Text(text) => line += text.matches('\n').count(),
+            Token::Value(_, start) | Token::Tag(_, start) => line = *start,
+        }
+    }
+    line
+}
+
+// `user.name` => ["user", "name"]
+fn parse_path(name: &str, line: usize, expr: &str) -> Result<Vec<String>, TemplateError> {
+    let valid = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if expr.split('.').all(valid) {
+        Ok(expr.split('.').map(str::to_string).collect())
+    } else {
+        Err(syntax_error(name, line, format!("bad value `{expr}`")))
+    }
+}
+
+struct Renderer<'a> {
+    templates: Option<&'a Templates>,
+    context: &'a Context,
+    // Loop variables, innermost last.
+    locals: Vec<(String, Value)>,
+    depth: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(templates: Option<&'a Templates>, context: &'a Context) -> Renderer<'a> {
+        Renderer {
+            templates,
+            context,
+            locals: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn render_template(&mut self, template: &Template) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        self.render(template, &template.nodes, &mut out)?;
+        Ok(out)
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.0.get(first))?;
+
+        for part in rest {
+            value = match value {
+                Value::Map(values) => values.get(part)?,
+                Value::List(values) => values.get(part.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    fn render(
+        &mut self,
+        template: &Template,
+        nodes: &[Node],
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        let error = |message: String| TemplateError::Render {
+            name: template.name.clone(),
+            message,
+        };
+
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Value { path, escape } => {
+                    if let Some(value) = self.lookup(path) {
+                        if *escape {
+                            escape_html(&value.to_string(), out);
+                        } else {
+                            out.push_str(&value.to_string());
+                        }
+                    }
+                }
+                Node::If {
+                    negate,
+                    path,
+                    then,
+                    otherwise,
+                } => {
+                    let truthy = self.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render(template, branch, out)?;
+                }
+                Node::For { var, path, body } => {
+                    let items = match self.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        None | Some(Value::Null) => Vec::new(),
+                        Some(_) => {
+                            return Err(error(format!("`{}` is not a list", path.join("."))))
+                        }
+                    };
+                    let count = items.len();
+                    for (ind, item) in items.into_iter().enumerate() {
+                        let details = Context::new()
+                            .with("index", ind + 1)
+                            .with("first", ind == 0)
+                            .with("last", ind + 1 == count);
+                        self.locals.push((String::from("loop"), details.into()));
+                        self.locals.push((var.clone(), item));
+                        let rendered = self.render(template, body, out);
+                        self.locals.truncate(self.locals.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include(name) => {
+                    let Some(templates) = self.templates else {
+                        return Err(error(format!("can't include {name:?} without `Templates`")));
+                    };
+                    if self.depth >= MAX_INCLUDE_DEPTH {
+                        return Err(error(format!("includes of {name:?} nest too deep")));
+                    }
+
+                    let included = templates.get(name)?;
+                    self.depth += 1;
+                    let rendered = self.render(&included, &included.nodes, out);
+                    self.depth -= 1;
+                    rendered?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape_html(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+// Loads templates by name from a directory and keeps them parsed.
+// In development mode every render checks whether the file changed and parses it again if so;
+// otherwise each template is only read once.
+pub struct Templates {
+    dir: PathBuf,
+    dev: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Templates {
+        Templates {
+            dir: dir.into(),
+            dev: false,
+            cache: Mutex::default(),
+        }
+    }
+
+    pub fn dev_mode(mut self, enabled: bool) -> Templates {
+        self.dev = enabled;
+        self
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let path = self.dir.join(name);
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+
+        if let Some(cached) = self.cache.lock().unwrap().get(name) {
+            if !self.dev || cached.modified == modified {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+
+        // `modified` was read first, so a change made while we read the file is picked up by the
+        // next render.
+        let source = fs::read_to_string(&path).map_err(|err| TemplateError::Io(path, err))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        self.cache.lock().unwrap().insert(
+            name.to_string(),
+            Cached {
+                template: Arc::clone(&template),
+                modified,
+            },
+        );
+        Ok(template)
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        Renderer::new(Some(self), context).render_template(&template)
+    }
+
+    // Renders an HTML page, or a plain 500 if the template is broken.
+    pub fn response(&self, status: u16, name: &str, context: &Context) -> Response {
+        match self.render(name, context) {
+            Ok(html) => Response::html(status, html),
+            Err(err) => {
+                eprintln!("Failed to render {name}: {err}");
+                Response::new(500).with_body("Internal Server Error")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use std::time::Duration;
+
+    fn render(source: &str, context: &Context) -> String {
+        Template::parse("test.html", source)
+            .unwrap()
+            .render(context)
+            .unwrap()
+    }
+
+    #[test]
+    fn values_are_escaped_unless_safe() {
+        let context = Context::new()
+            .with("name", "<b>Tom & \"Jerry\"</b>")
+            .with("user", Context::new().with("age", 7));
+
+        assert_eq!(
+            render("Hi {{ name }}, {{ user.age }}{{ missing }}", &context),
+            "Hi &lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;, 7"
+        );
+        assert_eq!(
+            render("{{ name | safe }}", &context),
+            "<b>Tom & \"Jerry\"</b>"
+        );
+    }
+
+    #[test]
+    fn conditionals_and_loops() {
+        let context = Context::new()
+            .with("items", vec!["a", "b", "c"])
+            .with("empty", Vec::<String>::new());
+        let source = "{% for item in items %}{{ loop.index }}={{ item }}\
+                      {% if not loop.last %}, {% endif %}{% endfor %}\
+                      {% if empty %}!{% else %}.{% endif %}{# not rendered #}";
+
+        assert_eq!(render(source, &context), "1=a, 2=b, 3=c.");
+    }
+
+    #[test]
+    fn request_details_are_available() {
+        let request = Request::new(Method::Get, "/missing?x=1");
+        assert_eq!(
+            render(
+                "{{ request.method }} {{ request.path }}?{{ request.query }}",
+                &Context::for_request(&request)
+            ),
+            "GET /missing?x=1"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        let err = Template::parse("page.html", "<p>\n{% if x %}\n{{ a b }}\n{% endif %}")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "page.html:3: bad value `a b`");
+
+        let err = Template::parse("page.html", "{% for x in xs %}\n\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "page.html:3: missing `{% endfor %}`");
+    }
+
+    #[test]
+    fn includes_are_cached_or_reloaded_in_dev_mode() {
+        let dir = std::env::temp_dir().join(format!("web_server_templates_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("page.html"), "[{% include \"part.html\" %}]").unwrap();
+        fs::write(dir.join("part.html"), "{{ name }}").unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+        let context = Context::new().with("name", "<x>");
+
+        let cached = Templates::new(&dir);
+        let dev = Templates::new(&dir).dev_mode(true);
+        assert_eq!(cached.render("page.html", &context).unwrap(), "[&lt;x&gt;]");
+        assert_eq!(dev.render("page.html", &context).unwrap(), "[&lt;x&gt;]");
+        assert!(cached.render("loop.html", &context).is_err());
+
+        fs::write(dir.join("part.html"), "{{ name | safe }}").unwrap();
+        // Make sure the change shows even on file systems with coarse timestamps.
+        fs::File::options()
+            .write(true)
+            .open(dir.join("part.html"))
+            .and_then(|part| part.set_modified(SystemTime::now() + Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(cached.render("page.html", &context).unwrap(), "[&lt;x&gt;]");
+        assert_eq!(dev.render("page.html", &context).unwrap(), "[<x>]");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
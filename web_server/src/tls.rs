@@ -0,0 +1,187 @@
+use std::{io, path::Path, sync::Arc};
+
+use futures_rustls::{
+    rustls::{
+        crypto::ring,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use crate::{
+    http::{Request, Response},
+    router::{BoxFuture, Handler},
+    vhost,
+};
+
+// Builds an acceptor from a PEM certificate chain and a PEM private key (PKCS#1, PKCS#8 or SEC1).
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    Certificates::load(cert_path, key_path)?.acceptor()
+}
+
+pub fn acceptor_from_der(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<TlsAcceptor> {
+    Certificates::from_der(certs, key)?.acceptor()
+}
+
+// Picks the certificate for the name the client asks for during the handshake (SNI), so that
+// every virtual host can have its own. Clients asking for a name without a certificate of its
+// own, or for no name at all, get the default one.
+#[derive(Debug)]
+pub struct Certificates {
+    default: Arc<CertifiedKey>,
+    // Names are lowercase and may be `*.` wildcards, as for `VirtualHosts`.
+    hosts: Vec<(Vec<String>, Arc<CertifiedKey>)>,
+}
+
+impl Certificates {
+    // The default certificate, in the same format as for `load_acceptor`.
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Certificates> {
+        let (certs, key) = read_pem(cert_path, key_path)?;
+        Certificates::from_der(certs, key)
+    }
+
+    pub fn from_der(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Certificates> {
+        Ok(Certificates {
+            default: certified_key(certs, key)?,
+            hosts: Vec::new(),
+        })
+    }
+
+    // Uses this certificate for clients asking for any of `names`.
+    pub fn with_host(
+        self,
+        names: &[&str],
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> io::Result<Certificates> {
+        let (certs, key) = read_pem(cert_path, key_path)?;
+        self.with_host_der(names, certs, key)
+    }
+
+    pub fn with_host_der(
+        mut self,
+        names: &[&str],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Certificates> {
+        let names = names.iter().map(|name| name.to_ascii_lowercase()).collect();
+        self.hosts.push((names, certified_key(certs, key)?));
+        Ok(self)
+    }
+
+    pub fn acceptor(self) -> io::Result<TlsAcceptor> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid_input(err.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self));
+        // Clients that support HTTP/2 pick it during the handshake (ALPN); the rest get HTTP/1.1.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello
+            .server_name()
+            .map(|name| name.to_ascii_lowercase())
+            .and_then(|name| vhost::lookup(&self.hosts, &name));
+        Some(match host {
+            Some(ind) => Arc::clone(&self.hosts[ind].1),
+            None => Arc::clone(&self.default),
+        })
+    }
+}
+
+fn read_pem(
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid_input(format!("{}: {err}", cert_path.display())))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| invalid_input(format!("{}: {err}", key_path.display())))?;
+    Ok((certs, key))
+}
+
+// Also checks that the key belongs to the certificate.
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Arc<CertifiedKey>> {
+    if certs.is_empty() {
+        return Err(invalid_input(String::from("no certificates found")));
+    }
+    CertifiedKey::from_der(certs, key, &ring::default_provider())
+        .map(Arc::new)
+        .map_err(|err| invalid_input(err.to_string()))
+}
+
+// Handler for the plain HTTP listener that sends every request over to the HTTPS listener.
+pub struct HttpsRedirect {
+    https_port: u16,
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16) -> HttpsRedirect {
+        HttpsRedirect { https_port }
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        // Keep the host the client asked for, but swap in the HTTPS port.
+        let host = request.header("Host").unwrap_or("localhost");
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => name,
+            _ => host,
+        };
+        let port = match self.https_port {
+            443 => String::new(),
+            port => format!(":{port}"),
+        };
+        let query = match &request.query {
+            Some(query) => format!("?{query}"),
+            None => String::new(),
+        };
+
+        let location = format!("https://{host}{port}{}{query}", request.path);
+        Box::pin(async move { Response::new(301).with_header("Location", location) })
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    #[test]
+    fn redirect_keeps_host_path_and_query() {
+        let mut request = Request::new(Method::Get, "/a/b?c=d");
+        request.headers.insert("Host", "example.com:7878");
+
+        let response = smol::block_on(HttpsRedirect::new(7879).call(request));
+
+        assert_eq!(response.status, 301);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some("https://example.com:7879/a/b?c=d")
+        );
+    }
+}
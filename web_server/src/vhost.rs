@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use crate::{
+    http::{Request, Response},
+    router::{BoxFuture, Handler},
+};
+
+// Lets one listener serve several sites, picking the site by the `Host` header (`:authority` for
+// HTTP/2). Over TLS, the name the client asked for in the handshake (SNI) is used when there's no
+// `Host`, and a request for a different site than the handshake's is answered with 421
+// Misdirected Request. The client picked the certificate and connection for the handshake's
+// name, so it should open a new connection for the other site.
+//
+// Requests for names that no site lists go to the default host if there is one, and get a 421
+// otherwise.
+pub struct VirtualHosts {
+    // Names are lowercase.
+    hosts: Vec<(Vec<String>, Arc<dyn Handler>)>,
+    default: Option<Arc<dyn Handler>>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts {
+            hosts: Vec::new(),
+            default: None,
+        }
+    }
+
+    // Serves requests for any of `names` with `handler`. A name starting with `*.` matches every
+    // subdomain below it, but names listed in full take precedence.
+    pub fn host(mut self, names: &[&str], handler: impl Handler) -> VirtualHosts {
+        let names = names.iter().map(|name| name.to_ascii_lowercase()).collect();
+        self.hosts.push((names, Arc::new(handler)));
+        self
+    }
+
+    // Serves requests for every name no host lists.
+    pub fn default_host(mut self, handler: impl Handler) -> VirtualHosts {
+        self.default = Some(Arc::new(handler));
+        self
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> VirtualHosts {
+        VirtualHosts::new()
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn call(&self, request: Request) -> BoxFuture<Response> {
+        let host = request.header("Host").map(host_name);
+        let server_name = request.server_name.as_deref().map(host_name);
+
+        let host = match (host, server_name) {
+            (Some(host), Some(server_name)) => {
+                let site = lookup(&self.hosts, &host);
+                if site != lookup(&self.hosts, &server_name) {
+                    return misdirected();
+                }
+                site
+            }
+            (Some(name), None) | (None, Some(name)) => lookup(&self.hosts, &name),
+            (None, None) => None,
+        };
+
+        match host.map(|ind| &self.hosts[ind].1).or(self.default.as_ref()) {
+            Some(handler) => handler.call(request),
+            None => misdirected(),
+        }
+    }
+}
+
+// The index of the entry listing `name`, or `None` if none does. Names listed in full are
+// preferred over `*.` wildcards. Also picks the certificate for a name, see `tls::Certificates`.
+pub(crate) fn lookup<T>(hosts: &[(Vec<String>, T)], name: &str) -> Option<usize> {
+    let listed = |matches: &dyn Fn(&str) -> bool| {
+        hosts
+            .iter()
+            .position(|(names, _)| names.iter().any(|pattern| matches(pattern)))
+    };
+    listed(&|pattern| pattern == name).or_else(|| {
+        listed(&|pattern| match pattern.strip_prefix('*') {
+            Some(suffix) => name.len() > suffix.len() && name.ends_with(suffix),
+            None => false,
+        })
+    })
+}
+
+fn misdirected() -> BoxFuture<Response> {
+    Box::pin(async { Response::new(421).with_body("Misdirected Request") })
+}
+
+// `Host` may carry a port, and names are case-insensitive and may end in a dot:
+// `Example.COM.:8080` is `example.com`. IPv6 addresses keep their brackets.
+fn host_name(host: &str) -> String {
+    let name = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn site(name: &'static str) -> impl Handler {
+        move |_| async move { Response::new(200).with_body(name) }
+    }
+
+    fn request(host: Option<&str>, server_name: Option<&str>) -> Request {
+        let mut request = Request::new(Method::Get, "/");
+        if let Some(host) = host {
+            request.headers.insert("Host", host);
+        }
+        request.server_name = server_name.map(String::from);
+        request
+    }
+
+    fn serve(hosts: &VirtualHosts, host: Option<&str>, server_name: Option<&str>) -> String {
+        let response = smol::block_on(hosts.call(request(host, server_name)));
+        format!(
+            "{} {}",
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        )
+    }
+
+    #[test]
+    fn dispatches_on_host_and_server_name() {
+        let hosts = VirtualHosts::new()
+            .host(&["example.com", "www.example.com"], site("example"))
+            .host(&["*.example.org", "special.example.com"], site("wild"))
+            .host(&["admin.example.org"], site("admin"));
+
+        assert_eq!(serve(&hosts, Some("example.com"), None), "200 example");
+        assert_eq!(
+            serve(&hosts, Some("WWW.Example.com.:8080"), None),
+            "200 example"
+        );
+        assert_eq!(serve(&hosts, Some("a.b.example.org"), None), "200 wild");
+        assert_eq!(serve(&hosts, Some("admin.example.org"), None), "200 admin");
+        assert_eq!(serve(&hosts, None, Some("special.example.com")), "200 wild");
+
+        // Unknown names, or no name at all, without a default host.
+        assert_eq!(
+            serve(&hosts, Some("example.org"), None),
+            "421 Misdirected Request"
+        );
+        assert_eq!(serve(&hosts, None, None), "421 Misdirected Request");
+
+        let hosts = hosts.default_host(site("default"));
+        assert_eq!(serve(&hosts, Some("127.0.0.1:7878"), None), "200 default");
+        assert_eq!(serve(&hosts, Some("[::1]:7878"), None), "200 default");
+        assert_eq!(serve(&hosts, None, None), "200 default");
+    }
+
+    #[test]
+    fn host_must_agree_with_the_handshake() {
+        let hosts = VirtualHosts::new()
+            .host(&["example.com", "www.example.com"], site("example"))
+            .host(&["other.com"], site("other"))
+            .default_host(site("default"));
+
+        assert_eq!(
+            serve(&hosts, Some("www.example.com"), Some("example.com")),
+            "200 example"
+        );
+        assert_eq!(
+            serve(&hosts, Some("other.com"), Some("example.com")),
+            "421 Misdirected Request"
+        );
+        assert_eq!(
+            serve(&hosts, Some("unknown.com"), Some("example.com")),
+            "421 Misdirected Request"
+        );
+        assert_eq!(serve(&hosts, Some("a.net"), Some("b.net")), "200 default");
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha1::{Digest, Sha1};
+use smol::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    lock::Mutex,
+};
+
+use crate::http::{Io, Method, Request, Response, Upgrade};
+
+// Appended to the client's key to prove that we understood the handshake (RFC 6455 section 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Messages bigger than this, fragmented or not, get the connection closed with 1009.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Close codes we send ourselves.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // Pings are answered automatically; they are still passed on in case the handler cares.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+// Answers a WebSocket handshake, or explains what is wrong with it.
+// Once the `101` is written, `on_connect` owns the connection until its future completes.
+//
+//     router.get("/ws", |request| async move {
+//         websocket::upgrade(&request, |mut socket| async move {
+//             while let Ok(Some(message)) = socket.recv().await { ... }
+//         })
+//     })
+pub fn upgrade<F, Fut>(request: &Request, on_connect: F) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let key = match check_handshake(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(Upgrade::new(move |io| {
+            Box::pin(async move {
+                let socket = WebSocket::new(io);
+                let sender = socket.sender();
+                on_connect(socket).await;
+                // Whatever state the handler left things in, the TCP connection ends here.
+                let _ = sender.writer.lock().await.close().await;
+            })
+        }))
+}
+
+fn check_handshake(request: &Request) -> Result<&str, Response> {
+    let has_token = |header: &str, token: &str| {
+        request.header(header).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if request.method != Method::Get
+        || !has_token("Upgrade", "websocket")
+        || !has_token("Connection", "upgrade")
+    {
+        return Err(Response::new(400).with_body("Expected a WebSocket upgrade"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(426).with_header("Sec-WebSocket-Version", "13"));
+    }
+
+    match request.header("Sec-WebSocket-Key") {
+        // The key is a base64-encoded 16-byte nonce.
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(Response::new(400).with_body("Missing or invalid Sec-WebSocket-Key")),
+    }
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+enum FrameError {
+    Io(io::Error),
+    // Close code and a short explanation for the close frame.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> FrameError {
+        FrameError::Io(err)
+    }
+}
+
+// Reads one client frame and unmasks its payload.
+async fn read_frame<R>(reader: &mut R, max_size: usize) -> Result<Frame, FrameError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await?;
+
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    // We don't negotiate any extensions, so the reserved bits must be clear.
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "reserved bits set",
+        ));
+    }
+    // Clients must mask every frame they send.
+    if head[1] & 0x80 == 0 {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "unmasked client frame",
+        ));
+    }
+
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).await?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length).await?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+
+    if opcode >= OPCODE_CLOSE && (!fin || length > 125) {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "bad control frame",
+        ));
+    }
+    if length > max_size as u64 {
+        return Err(FrameError::Protocol(CLOSE_TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).await?;
+    for (ind, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[ind % 4];
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// Server frames are never masked.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// The receiving end of a WebSocket connection. Use `sender()` to push messages from other tasks,
+// e.g. to stream dashboard updates while this end keeps reading.
+pub struct WebSocket {
+    reader: ReadHalf<Box<dyn Io>>,
+    sender: WebSocketSender,
+    // Opcode and payload so far of a fragmented message.
+    fragments: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    peer_closed: bool,
+}
+
+impl WebSocket {
+    fn new(io: Box<dyn Io>) -> WebSocket {
+        let (reader, writer) = smol::io::split(io);
+        WebSocket {
+            reader,
+            sender: WebSocketSender {
+                writer: Arc::new(Mutex::new(writer)),
+                closed: Arc::new(AtomicBool::new(false)),
+            },
+            fragments: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            peer_closed: false,
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    pub fn sender(&self) -> WebSocketSender {
+        self.sender.clone()
+    }
+
+    pub async fn send(&self, message: Message) -> io::Result<()> {
+        self.sender.send(message).await
+    }
+
+    // Starts the closing handshake; keep calling `recv` to wait for the client's reply.
+    pub async fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason).await
+    }
+
+    // Waits for the next complete message.
+    // Returns `Ok(None)` once the connection is closed, after handing out the client's
+    // `Message::Close` if it sent one.
+    pub async fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.peer_closed {
+            return Ok(None);
+        }
+
+        loop {
+            let frame = match read_frame(&mut self.reader, self.max_message_size).await {
+                Ok(frame) => frame,
+                Err(FrameError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(FrameError::Io(err)) => return Err(err),
+                Err(FrameError::Protocol(code, reason)) => return self.fail(code, reason).await,
+            };
+
+            match frame.opcode {
+                OPCODE_CLOSE => return self.on_close(frame.payload).await,
+                OPCODE_PING => {
+                    self.sender.send_frame(OPCODE_PONG, &frame.payload).await?;
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                OPCODE_PONG => return Ok(Some(Message::Pong(frame.payload))),
+                OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_none() => {
+                    if frame.fin {
+                        return self.complete(frame.opcode, frame.payload).await;
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION if self.fragments.is_some() => {
+                    let (opcode, mut payload) = self.fragments.take().unwrap();
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return self.fail(CLOSE_TOO_BIG, "message too big").await;
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.complete(opcode, payload).await;
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+                _ => return self.fail(CLOSE_PROTOCOL_ERROR, "unexpected opcode").await,
+            }
+        }
+    }
+
+    async fn complete(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<Option<Message>> {
+        if opcode == OPCODE_BINARY {
+            return Ok(Some(Message::Binary(payload)));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Some(Message::Text(text))),
+            Err(_) => self.fail(CLOSE_INVALID_DATA, "text is not UTF-8").await,
+        }
+    }
+
+    async fn on_close(&mut self, payload: Vec<u8>) -> io::Result<Option<Message>> {
+        self.peer_closed = true;
+
+        let frame = match payload.len() {
+            0 => None,
+            1 => {
+                return self
+                    .fail(CLOSE_PROTOCOL_ERROR, "truncated close code")
+                    .await
+            }
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let Ok(reason) = String::from_utf8(payload[2..].to_vec()) else {
+                    return self.fail(CLOSE_INVALID_DATA, "reason is not UTF-8").await;
+                };
+                Some(CloseFrame { code, reason })
+            }
+        };
+
+        // Echo the close unless this is the reply to one we sent.
+        if !self.sender.closed.load(Ordering::SeqCst) {
+            let code = frame.as_ref().map_or(CLOSE_NORMAL, |frame| frame.code);
+            self.sender.close(code, "").await?;
+        }
+        Ok(Some(Message::Close(frame)))
+    }
+
+    // Closes the connection because the client broke the protocol.
+    async fn fail(&mut self, code: u16, reason: &str) -> io::Result<Option<Message>> {
+        self.peer_closed = true;
+        let _ = self.sender.close(code, reason).await;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            reason.to_string(),
+        ))
+    }
+}
+
+// Cloneable sending end of a `WebSocket`. Frames from different clones never interleave.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    writer: Arc<Mutex<WriteHalf<Box<dyn Io>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl WebSocketSender {
+    pub async fn send(&self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_frame(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.send_frame(OPCODE_BINARY, &data).await,
+            Message::Ping(data) => self.send_frame(OPCODE_PING, &data).await,
+            Message::Pong(data) => self.send_frame(OPCODE_PONG, &data).await,
+            Message::Close(Some(frame)) => self.close(frame.code, &frame.reason).await,
+            Message::Close(None) => self.close(CLOSE_NORMAL, "").await,
+        }
+    }
+
+    pub async fn text(&self, text: impl Into<String>) -> io::Result<()> {
+        self.send(Message::Text(text.into())).await
+    }
+
+    pub async fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frames are limited to 125 bytes, two of which are the code.
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        self.send_frame(OPCODE_CLOSE, &payload).await?;
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    async fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        // Nothing may follow our close frame.
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&encode_frame(opcode, payload)).await?;
+        writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(ind, byte)| byte ^ mask[ind % 4]),
+        );
+        frame
+    }
+
+    fn read(bytes: &[u8]) -> Result<Frame, FrameError> {
+        smol::block_on(read_frame(&mut &bytes[..], DEFAULT_MAX_MESSAGE_SIZE))
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn unmasks_client_frames() {
+        let payload = "x".repeat(300);
+        let frame = read(&client_frame(true, OPCODE_TEXT, payload.as_bytes()))
+            .ok()
+            .unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                fin: true,
+                opcode: OPCODE_TEXT,
+                payload: payload.into_bytes()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unmasked_and_fragmented_control_frames() {
+        let unmasked = encode_frame(OPCODE_TEXT, b"hi");
+        assert!(matches!(
+            read(&unmasked),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+
+        let fragmented_ping = client_frame(false, OPCODE_PING, b"");
+        assert!(matches!(
+            read(&fragmented_ping),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+    }
+
+    #[test]
+    fn encodes_extended_lengths() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"hi"), [0x81, 2, b'h', b'i']);
+        assert_eq!(
+            &encode_frame(OPCODE_BINARY, &[0; 200])[..4],
+            [0x82, 126, 0, 200]
+        );
+        assert_eq!(&encode_frame(OPCODE_BINARY, &[0; 70_000])[..2], [0x82, 127]);
+    }
+
+    #[test]
+    fn handshake_requires_websocket_headers() {
+        let mut request = Request::new(Method::Get, "/ws");
+        request.headers.insert("Upgrade", "websocket");
+        request.headers.insert("Connection", "keep-alive, Upgrade");
+        request.headers.insert("Sec-WebSocket-Version", "13");
+        request
+            .headers
+            .insert("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        let response = upgrade(&request, |_| async {});
+        assert_eq!(response.status, 101);
+        assert!(response.upgrade.is_some());
+
+        request.headers.insert("Sec-WebSocket-Version", "8");
+        assert_eq!(upgrade(&request, |_| async {}).status, 426);
+
+        let plain = Request::new(Method::Get, "/ws");
+        assert_eq!(upgrade(&plain, |_| async {}).status, 400);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use smol::Timer;
+use web_server::{Response, Router, Server};
+
+mod common;
+
+fn router() -> Router {
+    Router::new()
+        .get("/", |_| async { Response::html(200, "<h1>Hello!</h1>") })
+        .get("/sleep", |_| async {
+            Timer::after(Duration::from_millis(500)).await;
+            Response::html(200, "<h1>Slept</h1>")
+        })
+}
+
+#[test]
+fn threaded_backend_serves_routes() {
+    let addr = common::spawn_threaded(Server::new(router()), 2);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("<h1>Hello!</h1>"), "{response}");
+
+    let response = common::get(addr, "/missing");
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+}
+
+#[test]
+fn async_backend_serves_routes() {
+    let addr = common::spawn_async(Server::new(router()), 1);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("<h1>Hello!</h1>"), "{response}");
+}
+
+#[test]
+fn head_responses_have_no_body() {
+    // Served by the GET route.
+    let addr = common::spawn_threaded(Server::new(router()), 1);
+
+    let response = common::send(addr, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Content-Length: 15\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\n"), "{response}");
+}
+
+#[test]
+fn malformed_request_gets_400() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+
+    let response = common::send(addr, "NONSENSE\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+}
+
+#[test]
+fn slow_clients_get_408() {
+    let server =
+        Server::new(router()).with_timeouts(Duration::from_millis(200), Duration::from_secs(1));
+    let addr = common::spawn_threaded(server, 1);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{response}"
+    );
+}
+
+// With no extra executor threads every request shares the accept loop's thread, yet the sleeps
+// still overlap instead of running back to back.
+#[test]
+fn async_backend_does_not_block_on_slow_handlers() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..8)
+        .map(|_| thread::spawn(move || common::get(addr, "/sleep")))
+        .collect();
+    for client in clients {
+        let response = client.join().unwrap();
+        assert!(response.ends_with("<h1>Slept</h1>"), "{response}");
+    }
+
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "took {:?}",
+        start.elapsed()
+    );
+}
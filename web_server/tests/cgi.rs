@@ -0,0 +1,143 @@
+#![cfg(unix)]
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use web_server::{cgi::Cgi, Router, Server, ThreadPool};
+
+mod common;
+
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("web_server_{}_{name}", std::process::id()));
+    fs::write(&path, source).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn runs_the_script_with_the_request() {
+    let program = script(
+        "echo.sh",
+        "#!/bin/sh\n\
+         echo 'Status: 201 Created'\n\
+         echo 'X-Method: '$REQUEST_METHOD\n\
+         echo\n\
+         echo \"$PATH_INFO?$QUERY_STRING $HTTP_X_TOKEN\"\n\
+         cat\n",
+    );
+    let pool = Arc::new(ThreadPool::new(2));
+    let router = Router::new().post("/echo/*", Cgi::new(&program, pool).script_name("/echo"));
+    let addr = common::spawn_async(Server::new(router), 1);
+
+    let response = common::send(
+        addr,
+        "POST /echo/a/b?x=1 HTTP/1.1\r\nHost: localhost\r\nX-Token: secret\r\n\
+         Content-Length: 5\r\n\r\nhello",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("X-Method: POST\r\n"), "{response}");
+    assert!(
+        response.ends_with("\r\n\r\n/a/b?x=1 secret\nhello"),
+        "{response}"
+    );
+
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn slow_scripts_are_killed() {
+    let program = script("slow.sh", "#!/bin/sh\nexec sleep 5\n");
+    let pool = Arc::new(ThreadPool::new(1));
+    let cgi = Cgi::new(&program, pool).timeout(Duration::from_millis(200));
+    let addr = common::spawn_threaded(Server::new(Router::new().get("/slow", cgi)), 1);
+
+    let start = Instant::now();
+    let response = common::get(addr, "/slow");
+    assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn lingering_scripts_dont_hold_on_to_the_worker() {
+    // Closes stdout, but keeps running.
+    let program = script(
+        "linger.sh",
+        "#!/bin/sh\necho Content-Type: text/plain\necho\necho done\nexec >&-\nsleep 5\n",
+    );
+    let pool = Arc::new(
+        ThreadPool::builder()
+            .workers(1)
+            .queue_capacity(0)
+            .build()
+            .unwrap(),
+    );
+    let addr = common::spawn_async(
+        Server::new(Router::new().get("/", Cgi::new(&program, pool))),
+        1,
+    );
+
+    let start = Instant::now();
+    for _ in 0..2 {
+        let response = common::get(addr, "/");
+        assert!(response.ends_with("\r\n\r\ndone\n"), "{response}");
+        // The response can get here a moment before the worker is free again.
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn busy_pool_turns_requests_away() {
+    let program = script(
+        "busy.sh",
+        "#!/bin/sh\nsleep 1\necho Content-Type: text/plain\necho\necho done\n",
+    );
+    let pool = ThreadPool::builder()
+        .workers(1)
+        .queue_capacity(0)
+        .build()
+        .unwrap();
+    let cgi = Cgi::new(&program, Arc::new(pool));
+    let addr = common::spawn_async(Server::new(Router::new().get("/", cgi)), 1);
+
+    let first = thread::spawn(move || common::get(addr, "/"));
+    thread::sleep(Duration::from_millis(200));
+    // Answered right away, while the first script is still running.
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+
+    let response = first.join().unwrap();
+    assert!(response.ends_with("\r\n\r\ndone\n"), "{response}");
+
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn bad_output_is_a_bad_gateway() {
+    let program = script("bad.sh", "#!/bin/sh\necho 'no headers here'\n");
+    let cgi = Cgi::new(&program, Arc::new(ThreadPool::new(1)));
+    let addr = common::spawn_threaded(Server::new(Router::new().get("/", cgi)), 1);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+
+    let cgi = Cgi::new("/no/such/program", Arc::new(ThreadPool::new(1)));
+    let addr = common::spawn_threaded(Server::new(Router::new().get("/", cgi)), 1);
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+
+    fs::remove_file(program).unwrap();
+}
@@ -0,0 +1,140 @@
+// Each test crate compiles its own copy of this module and not all of them use every helper.
+#![allow(dead_code)]
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+};
+
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use web_server::{hpack, Server, ThreadPool};
+
+// Binds an ephemeral port and serves `server` from a background thread.
+// The server lives until the test binary exits.
+pub fn spawn_threaded(server: Server, workers: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let pool = ThreadPool::new(workers);
+        server.serve_threaded(listener, &pool).unwrap();
+    });
+    addr
+}
+
+pub fn spawn_async(server: Server, threads: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve_async(listener, threads).unwrap());
+    addr
+}
+
+// Sends a raw request and returns everything the server wrote before closing the connection.
+pub fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+pub fn send_bytes(addr: SocketAddr, request: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+pub fn get(addr: SocketAddr, path: &str) -> String {
+    send(
+        addr,
+        &format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+    )
+}
+
+pub struct H2Response {
+    pub stream: u32,
+    pub status: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// A bare-bones HTTP/2 client: sends the preface and every request (method, path, body) at
+// once on streams 1, 3, 5..., and returns the responses in the order they were completed.
+// It grants the server a 1 MiB window up front instead of keeping track of flow control.
+pub async fn h2_exchange<S>(stream: &mut S, requests: &[(&str, &str, &[u8])]) -> Vec<H2Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut out = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    h2_frame(&mut out, 0x4, 0, 0, &[0, 4, 0, 0x10, 0, 0]);
+    h2_frame(&mut out, 0x8, 0, 0, &(1u32 << 20).to_be_bytes());
+    for (ind, &(method, path, body)) in requests.iter().enumerate() {
+        let id = ind as u32 * 2 + 1;
+        let block = hpack::encode([
+            (":method", method),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ]);
+        let end_stream = if body.is_empty() { 0x1 } else { 0 };
+        h2_frame(&mut out, 0x1, 0x4 | end_stream, id, &block);
+        if !body.is_empty() {
+            h2_frame(&mut out, 0x0, 0x1, id, body);
+        }
+    }
+    stream.write_all(&out).await.unwrap();
+
+    let mut decoder = hpack::Decoder::new(hpack::DEFAULT_TABLE_SIZE);
+    let mut partial: Vec<H2Response> = Vec::new();
+    let mut done = Vec::new();
+    while done.len() < requests.len() {
+        let mut head = [0; 9];
+        stream.read_exact(&mut head).await.unwrap();
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let (kind, flags) = (head[3], head[4]);
+        let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        match kind {
+            // SETTINGS, which have to be acknowledged.
+            0x4 if flags & 0x1 == 0 => {
+                let mut ack = Vec::new();
+                h2_frame(&mut ack, 0x4, 0x1, 0, &[]);
+                stream.write_all(&ack).await.unwrap();
+            }
+            0x1 => {
+                let headers = decoder.decode(&payload, usize::MAX).unwrap();
+                let status = headers[0].1.clone();
+                partial.push(H2Response {
+                    stream: id,
+                    status,
+                    headers: headers[1..].to_vec(),
+                    body: Vec::new(),
+                });
+            }
+            0x0 => {
+                let response = partial.iter_mut().find(|r| r.stream == id).unwrap();
+                response.body.extend_from_slice(&payload);
+            }
+            0x3 | 0x7 => panic!("stream {id} reset or connection closed: {payload:?}"),
+            _ => {}
+        }
+        if (kind == 0x0 || kind == 0x1) && flags & 0x1 != 0 {
+            let ind = partial.iter().position(|r| r.stream == id).unwrap();
+            done.push(partial.remove(ind));
+        }
+    }
+    done
+}
+
+fn h2_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[kind, flags]);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
@@ -0,0 +1,53 @@
+use std::{env, fs, io::Write, path::PathBuf, process};
+
+use flate2::{write::GzEncoder, Compression};
+use web_server::{compression::Compress, Response, Router, Server, StaticFiles};
+
+mod common;
+
+fn site(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("web_server-static-{}-{name}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), "<h1>plain</h1>").unwrap();
+
+    // Deliberately different from the plain file so we can tell which one was served.
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(b"<h1>precompressed</h1>").unwrap();
+    fs::write(root.join("index.html.gz"), gz.finish().unwrap()).unwrap();
+    root
+}
+
+fn get(addr: std::net::SocketAddr, accept_encoding: &str) -> String {
+    let request =
+        format!("GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+    // Compressed bodies aren't valid UTF-8, but the head always is.
+    String::from_utf8_lossy(&common::send_bytes(addr, &request)).into_owned()
+}
+
+#[test]
+fn static_files_serve_precompressed_siblings() {
+    let root = site("precompressed");
+    let addr = common::spawn_async(Server::new(StaticFiles::new(&root)), 0);
+
+    let response = get(addr, "gzip");
+    assert!(response.contains("Content-Encoding: gzip"), "{response}");
+    assert!(response.contains("Vary: Accept-Encoding"), "{response}");
+
+    let response = get(addr, "identity");
+    assert!(!response.contains("Content-Encoding"), "{response}");
+    assert!(response.contains("Vary: Accept-Encoding"), "{response}");
+    assert!(response.ends_with("<h1>plain</h1>"), "{response}");
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn large_responses_are_compressed_on_the_fly() {
+    let router = Router::new().get("/", |_| async {
+        Response::html(200, "<p>hi</p>".repeat(500))
+    });
+    let addr = common::spawn_threaded(Server::new(Compress::new(router)), 1);
+
+    let response = get(addr, "br;q=0.9, gzip;q=1.0");
+    assert!(response.contains("Content-Encoding: gzip"), "{response}");
+}
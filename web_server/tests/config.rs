@@ -0,0 +1,124 @@
+use std::{
+    fs,
+    net::TcpListener,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use smol::Timer;
+use web_server::{config::Config, server::Reloadable, Response, ThreadPool};
+
+mod common;
+
+fn load(text: &str) -> Config {
+    text.parse().unwrap_or_else(|err| panic!("{err}"))
+}
+
+#[test]
+fn shipped_config_is_valid() {
+    Config::load(Path::new("server.toml")).unwrap();
+}
+
+#[test]
+fn serves_configured_routes() {
+    let dir = std::env::temp_dir().join(format!("web_server_config_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("site.css"), "body {}").unwrap();
+
+    let config = load(&format!(
+        r#"
+        not_found = "404.html"
+
+        [log]
+        access = false
+
+        [[route]]
+        path = "/"
+        file = "hello.html"
+
+        [[route]]
+        path = "/assets/*"
+        dir = {dir:?}
+
+        [[route]]
+        path = "/old"
+        redirect = "/"
+        "#
+    ));
+    let addr = common::spawn_threaded(config.server(config.router().unwrap()).unwrap(), 2);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("<title>Hello!</title>"), "{response}");
+
+    let response = common::get(addr, "/assets/site.css");
+    assert!(response.ends_with("\r\n\r\nbody {}"), "{response}");
+
+    let response = common::get(addr, "/old");
+    assert!(response.starts_with("HTTP/1.1 301"), "{response}");
+    assert!(response.contains("Location: /\r\n"), "{response}");
+
+    let response = common::get(addr, "/missing");
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn not_found_template_shows_the_path() {
+    let config = load(
+        r#"
+        [log]
+        access = false
+
+        [templates]
+        not_found = "404.html"
+        "#,
+    );
+    let addr = common::spawn_async(config.server(config.router().unwrap()).unwrap(), 1);
+
+    let response = common::get(addr, "/nothing/<here>");
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    assert!(response.contains("<title>Hello!</title>"), "{response}");
+    assert!(
+        response.contains("<code>/nothing/&lt;here&gt;</code>"),
+        "{response}"
+    );
+}
+
+#[test]
+fn reloading_keeps_connections_in_flight() {
+    let build = |page: &str| {
+        let config = load(&format!(
+            "[log]\naccess = false\n\n[[route]]\npath = \"/\"\nfile = \"{page}\"\n"
+        ));
+        let router = config.router().unwrap().get("/slow", |_| async {
+            Timer::after(Duration::from_millis(300)).await;
+            Response::new(200).with_body("slow")
+        });
+        config.server(router).unwrap()
+    };
+
+    let server = Reloadable::new(build("hello.html"));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let serving = server.clone();
+    thread::spawn(move || {
+        let pool = ThreadPool::new(2);
+        serving.serve_threaded(listener, &pool).unwrap();
+    });
+
+    let slow = thread::spawn(move || common::get(addr, "/slow"));
+    thread::sleep(Duration::from_millis(100));
+    server.replace(build("404.html"));
+
+    // Accepted before the reload, so still served by the old server.
+    let start = Instant::now();
+    let response = slow.join().unwrap();
+    assert!(response.ends_with("slow"), "{response}");
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let response = common::get(addr, "/");
+    assert!(response.contains("<h1>Oops!</h1>"), "{response}");
+}
@@ -0,0 +1,131 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use web_server::{
+    form::{Form, FormError, FormLimits},
+    Request, Response, Router, Server,
+};
+
+mod common;
+
+fn upload(request: Request) -> Response {
+    upload_response(request.form())
+}
+
+fn upload_response(form: Result<Form, FormError>) -> Response {
+    let form = match form {
+        Ok(form) => form,
+        Err(err) => return err.response(),
+    };
+    let Some(file) = form.file("doc") else {
+        return Response::new(400).with_body("no file");
+    };
+
+    let mut contents = String::new();
+    file.open().unwrap().read_to_string(&mut contents).unwrap();
+    Response::new(200).with_body(format!(
+        "{} sent {} ({} bytes): {contents}",
+        form.get("user").unwrap_or("nobody"),
+        file.file_name().unwrap_or("?"),
+        file.size()
+    ))
+}
+
+#[test]
+fn handlers_read_uploaded_files() {
+    let router = Router::new().post("/upload", |request| async { upload(request) });
+    let addr = common::spawn_threaded(Server::new(router), 2);
+
+    let body = "--sep\r\n\
+         Content-Disposition: form-data; name=\"user\"\r\n\r\n\
+         ferris\r\n\
+         --sep\r\n\
+         Content-Disposition: form-data; name=\"doc\"; filename=\"notes.txt\"\r\n\
+         Content-Type: text/plain\r\n\r\n\
+         hello, world\r\n\
+         --sep--\r\n";
+    let response = common::send(
+        addr,
+        &format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Type: multipart/form-data; boundary=sep\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        ),
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.ends_with("ferris sent notes.txt (12 bytes): hello, world"),
+        "{response}"
+    );
+
+    let response = common::send(
+        addr,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+         Content-Length: 2\r\n\r\nhi",
+    );
+    assert!(response.starts_with("HTTP/1.1 415"), "{response}");
+}
+
+#[test]
+fn big_uploads_are_streamed_to_disk() {
+    let router = || {
+        Router::new()
+            .post("/upload", |request| async { upload(request) })
+            .post("/stream", |mut request: Request| async move {
+                // Nothing of it is buffered.
+                assert!(request.body.is_empty());
+                let form = request.read_form(&FormLimits::default()).await;
+                upload_response(form)
+            })
+    };
+    let head = |path: &str, content_type: &str, length: usize| {
+        format!(
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Type: {content_type}\r\n\
+             Content-Length: {length}\r\n\r\n"
+        )
+    };
+    let contents = "x".repeat(2 * 1024 * 1024);
+    let body = format!(
+        "--sep\r\n\
+         Content-Disposition: form-data; name=\"doc\"; filename=\"big.txt\"\r\n\r\n\
+         {contents}\r\n\
+         --sep--\r\n"
+    );
+    let multipart = "multipart/form-data; boundary=sep";
+
+    for addr in [
+        common::spawn_threaded(Server::new(router()), 1),
+        common::spawn_async(Server::new(router()), 1),
+    ] {
+        // Any other body that big is turned away as soon as the head is in, without waiting for
+        // the body, and so is an upload to a handler that wants it buffered.
+        for (path, content_type) in [
+            ("/stream", "application/octet-stream"),
+            ("/upload", multipart),
+        ] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let request = head(path, content_type, body.len());
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        }
+
+        let response = common::send(
+            addr,
+            &format!("{}{body}", head("/stream", multipart, body.len())),
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "{response:.200}"
+        );
+        assert!(
+            response.contains("nobody sent big.txt (2097152 bytes)"),
+            "{response:.200}"
+        );
+    }
+}
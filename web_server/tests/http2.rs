@@ -0,0 +1,206 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use smol::{stream, Async, Timer};
+use web_server::{
+    http::{BodyStream, Upgrade},
+    Request, Response, Router, Server,
+};
+
+mod common;
+
+fn router() -> Router {
+    Router::new()
+        .get("/slow", |_| async {
+            Timer::after(Duration::from_millis(200)).await;
+            Response::new(200).with_body("slow")
+        })
+        .get("/fast", |request: Request| async move {
+            Response::new(200).with_body(format!(
+                "fast over {} to {}",
+                request.version,
+                request.header("Host").unwrap_or("?")
+            ))
+        })
+        .get("/big", |_| async {
+            Response::new(200).with_body(vec![b'x'; 200_000])
+        })
+        .post("/echo", |request: Request| async move {
+            Response::new(201).with_body(request.body)
+        })
+        .get("/upgrade", |_| async {
+            Response::new(101).with_upgrade(Upgrade::new(|_| Box::pin(async {})))
+        })
+}
+
+fn exchange(
+    server: Server,
+    threaded: bool,
+    requests: &[(&str, &str, &[u8])],
+) -> Vec<common::H2Response> {
+    let addr = if threaded {
+        common::spawn_threaded(server, 1)
+    } else {
+        common::spawn_async(server, 1)
+    };
+    smol::block_on(async {
+        let mut stream = Async::<TcpStream>::connect(addr).await.unwrap();
+        common::h2_exchange(&mut stream, requests).await
+    })
+}
+
+#[test]
+fn slow_requests_dont_hold_up_the_rest() {
+    // A single worker, and still both requests run at once.
+    let responses = exchange(
+        Server::new(router()),
+        true,
+        &[("GET", "/slow", b""), ("GET", "/fast", b"")],
+    );
+
+    assert_eq!(responses[0].stream, 3);
+    assert_eq!(responses[0].status, "200");
+    assert_eq!(responses[0].body, b"fast over HTTP/2.0 to localhost");
+    assert_eq!(responses[1].stream, 1);
+    assert_eq!(responses[1].body, b"slow");
+}
+
+#[test]
+fn big_bodies_go_both_ways() {
+    let responses = exchange(
+        Server::new(router()),
+        false,
+        &[("GET", "/big", b""), ("POST", "/echo", b"hello over h2")],
+    );
+
+    let big = responses.iter().find(|r| r.stream == 1).unwrap();
+    assert_eq!(big.body.len(), 200_000);
+    assert!(big
+        .headers
+        .contains(&("content-length".to_string(), "200000".to_string())));
+
+    let echo = responses.iter().find(|r| r.stream == 3).unwrap();
+    assert_eq!(echo.status, "201");
+    assert_eq!(echo.body, b"hello over h2");
+}
+
+#[test]
+fn unknown_routes_get_the_same_answer_as_http1() {
+    let responses = exchange(Server::new(router()), false, &[("GET", "/nope", b"")]);
+    assert_eq!(responses[0].status, "404");
+
+    let addr = common::spawn_async(Server::new(router()), 1);
+    let response = common::get(addr, "/nope");
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+}
+
+#[test]
+fn upgrades_need_http1() {
+    let responses = exchange(Server::new(router()), false, &[("GET", "/upgrade", b"")]);
+    assert_eq!(responses[0].status, "505");
+}
+
+#[test]
+fn header_list_bombs_are_refused() {
+    let addr = common::spawn_threaded(Server::new(router()), 1);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // A 4000-byte header that goes into the table, then 20 references to it: about 80 KB of
+    // headers from a block of about 4 KB.
+    let mut block = vec![0x40, 0x01, b'x', 0x7f, 0xa1, 0x1e];
+    block.extend_from_slice(&[b'y'; 4000]);
+    block.extend_from_slice(&[0xbe; 20]);
+    let mut out = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    out.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+    out.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[0x1, 0x5, 0, 0, 0, 1]);
+    out.extend_from_slice(&block);
+    stream.write_all(&out).unwrap();
+
+    let mut settings = None;
+    loop {
+        let mut head = [0; 9];
+        stream.read_exact(&mut head).unwrap();
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        match head[3] {
+            0x4 if settings.is_none() => settings = Some(payload),
+            0x7 => {
+                // COMPRESSION_ERROR.
+                assert_eq!(payload[4..8], [0, 0, 0, 0x9]);
+                break;
+            }
+            0x1 => panic!("the request was answered"),
+            _ => {}
+        }
+    }
+
+    // The limit was in our settings: SETTINGS_MAX_HEADER_LIST_SIZE (0x6) of 64 KiB.
+    let settings = settings.unwrap();
+    assert!(settings
+        .chunks(6)
+        .any(|setting| setting == [0, 0x6, 0, 1, 0, 0]));
+}
+
+#[test]
+fn streamed_bodies_wait_for_the_peer() {
+    // An endless body, a chunk every millisecond.
+    let produced = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&produced);
+    let router = Router::new().get("/forever", move |_| {
+        let counter = Arc::clone(&counter);
+        async move {
+            let chunks = stream::unfold(counter, |counter| async move {
+                Timer::after(Duration::from_millis(1)).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                Some((vec![b'x'; 1000], counter))
+            });
+            Response::new(200).with_stream(BodyStream::new(chunks))
+        }
+    });
+    let addr = common::spawn_threaded(Server::new(router), 1);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // SETTINGS_INITIAL_WINDOW_SIZE of 0: the server may send no DATA at all.
+    let block = web_server::hpack::encode([
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/forever"),
+        (":authority", "localhost"),
+    ]);
+    let mut out = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    out.extend_from_slice(&[0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 0x4, 0, 0, 0, 0]);
+    out.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[0x1, 0x5, 0, 0, 0, 1]);
+    out.extend_from_slice(&block);
+    stream.write_all(&out).unwrap();
+
+    loop {
+        let mut head = [0; 9];
+        stream.read_exact(&mut head).unwrap();
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        stream.read_exact(&mut vec![0; len]).unwrap();
+        if head[3] == 0x1 {
+            break;
+        }
+    }
+
+    // Without room to send it, the body shouldn't be produced any further than a chunk ahead.
+    thread::sleep(Duration::from_millis(300));
+    assert!(produced.load(Ordering::SeqCst) <= 2);
+}
@@ -0,0 +1,90 @@
+use std::{net::TcpStream, thread, time::Duration};
+
+use web_server::{
+    limits::{ConnectionLimit, RateLimit},
+    Response, Router, Server,
+};
+
+mod common;
+
+fn router() -> Router {
+    Router::new().get("/", |_| async { Response::new(200).with_body("ok") })
+}
+
+#[test]
+fn clients_over_the_rate_get_429() {
+    let server = Server::new(router()).with_middleware(RateLimit::new(1.0, 2));
+    let addr = common::spawn_threaded(server, 2);
+
+    for _ in 0..2 {
+        let response = common::get(addr, "/");
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+
+    let response = common::get(addr, "/");
+    assert!(
+        response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{response}");
+}
+
+#[test]
+fn connections_per_ip_are_capped() {
+    let server = Server::new(router()).with_connection_limit(ConnectionLimit::new(10, 1));
+    let addr = common::spawn_threaded(server, 2);
+
+    // Holds on to its permit while the server waits for a request that never comes.
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 429"), "{response}");
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[test]
+fn total_connections_are_capped() {
+    let server = Server::new(router()).with_connection_limit(ConnectionLimit::new(1, 10));
+    let addr = common::spawn_async(server, 1);
+
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let response = common::get(addr, "/");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[test]
+fn busy_workers_shed_load() {
+    let server = Server::new(router()).with_queue_timeout(Duration::from_millis(100));
+    // One worker, with room for one more connection in the queue.
+    let addr = common::spawn_threaded(server, 1);
+
+    let idle = [(); 2].map(|_| {
+        let stream = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream
+    });
+    let response = common::get(addr, "/");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{response}");
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
@@ -0,0 +1,79 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use web_server::{
+    access_log::{AccessLog, LogFormat},
+    metrics::{Metrics, MetricsEndpoint},
+    Response, Router, Server,
+};
+
+mod common;
+
+// Collects access log lines so the test can look at them.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn requests_are_logged_and_counted() {
+    let log = SharedBuffer::default();
+    let metrics = Arc::new(Metrics::new());
+    let router = Router::new()
+        .get("/", |_| async { Response::html(200, "hi") })
+        .get("/metrics", MetricsEndpoint::new(Arc::clone(&metrics)));
+    let server = Server::new(router)
+        .with_access_log(AccessLog::new(LogFormat::Json, log.clone()))
+        .with_metrics(Arc::clone(&metrics));
+    let addr = common::spawn_threaded(server, 2);
+
+    common::get(addr, "/");
+    common::get(addr, "/missing?q=1");
+    // A client sees the connection close just before the worker lets go of it.
+    thread::sleep(Duration::from_millis(50));
+    let response = common::get(addr, "/metrics");
+
+    assert!(
+        response.contains("http_requests_total{status=\"200\"} 1\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("http_requests_total{status=\"404\"} 1\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("http_request_duration_seconds_count 2\n"),
+        "{response}"
+    );
+    // The scrape itself is still in flight.
+    assert!(
+        response.contains("http_active_connections 1\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("thread_pool_queue_depth 0\n"),
+        "{response}"
+    );
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 3, "{log}");
+    assert!(
+        lines[0].contains("\"target\":\"/\",\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2")
+    );
+    assert!(lines[1].contains("\"target\":\"/missing?q=1\""));
+    assert!(lines[1].contains("\"status\":404"));
+}
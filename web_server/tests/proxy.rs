@@ -0,0 +1,186 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use smol::Timer;
+use web_server::{
+    proxy::{Balance, Proxy},
+    Handler, Method, Response, Router, Server,
+};
+
+mod common;
+
+// A stand-in backend that says who it is and what the proxy told it.
+fn backend(name: &'static str, delay: Duration) -> String {
+    let handler = move |request: web_server::Request| async move {
+        Timer::after(delay).await;
+        let forwarded_for = request.header("X-Forwarded-For").unwrap_or("-").to_string();
+        let proto = request
+            .header("X-Forwarded-Proto")
+            .unwrap_or("-")
+            .to_string();
+        Response::new(200)
+            .with_header("X-Backend", name)
+            .with_body(format!("{name} {} {forwarded_for} {proto}", request.path))
+    };
+    let router = Router::new()
+        .route(Method::Get, "/*", handler)
+        .route(Method::Head, "/*", handler);
+    common::spawn_async(Server::new(router), 0).to_string()
+}
+
+// An address that refuses connections.
+fn dead_backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn body(response: &str) -> &str {
+    response.split("\r\n\r\n").nth(1).unwrap()
+}
+
+#[test]
+fn forwards_with_round_robin_and_forwarded_headers() {
+    let a = backend("a", Duration::ZERO);
+    let b = backend("b", Duration::ZERO);
+    let router = Router::new().route(
+        Method::Get,
+        "/api/*",
+        Proxy::new(&[&a, &b]).strip_prefix("/api"),
+    );
+    let addr = common::spawn_async(Server::new(router), 1);
+
+    let bodies: Vec<String> = (0..4)
+        .map(|_| body(&common::get(addr, "/api/users")).to_string())
+        .collect();
+
+    assert_eq!(
+        bodies,
+        [
+            "a /users 127.0.0.1 http",
+            "b /users 127.0.0.1 http",
+            "a /users 127.0.0.1 http",
+            "b /users 127.0.0.1 http"
+        ]
+    );
+}
+
+#[test]
+fn fails_over_and_stops_trying_dead_backends() {
+    let dead = dead_backend();
+    let live = backend("live", Duration::ZERO);
+    let proxy = Proxy::new(&[&dead, &live]).health(1, Duration::from_secs(60));
+    let addr = common::spawn_async(Server::new(Router::new().get("/", proxy)), 0);
+
+    for _ in 0..4 {
+        let response = common::get(addr, "/");
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(body(&response).starts_with("live "), "{response}");
+    }
+}
+
+#[test]
+fn least_connections_avoids_busy_backends() {
+    let slow = backend("slow", Duration::from_millis(500));
+    let fast = backend("fast", Duration::ZERO);
+    let proxy = Proxy::new(&[&slow, &fast]).balance(Balance::LeastConnections);
+    let addr = common::spawn_async(Server::new(Router::new().get("/", proxy)), 1);
+
+    // Occupy the slow backend, then check that everything else goes to the fast one.
+    let first = std::thread::spawn(move || common::get(addr, "/"));
+    std::thread::sleep(Duration::from_millis(100));
+    for _ in 0..3 {
+        assert!(body(&common::get(addr, "/")).starts_with("fast "));
+    }
+    assert!(body(&first.join().unwrap()).starts_with("slow "));
+}
+
+#[test]
+fn head_requests_keep_the_backend_up() {
+    let live = backend("live", Duration::ZERO);
+    let proxy = Arc::new(Proxy::new(&[&live]).health(1, Duration::from_secs(60)));
+    let handler = move |request| proxy.call(request);
+    let router = Router::new()
+        .route(Method::Get, "/", handler.clone())
+        .route(Method::Head, "/", handler);
+    let addr = common::spawn_async(Server::new(router), 0);
+
+    let response = common::send(addr, "HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    // The length of "live / 127.0.0.1 http", which the backend didn't send.
+    assert!(response.contains("Content-Length: 21\r\n"), "{response}");
+    assert_eq!(body(&response), "");
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+}
+
+#[test]
+fn all_backends_down_is_a_bad_gateway() {
+    let dead = dead_backend();
+    let addr = common::spawn_async(Server::new(Router::new().get("/", Proxy::new(&[&dead]))), 0);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+}
+
+// An address that accepts no more connections but doesn't refuse them either, so connecting
+// times out: its backlog is full and nothing takes connections out of it.
+fn unresponsive_backend() -> (String, TcpListener, Vec<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    for _ in 0..10_000 {
+        match TcpStream::connect_timeout(&addr, Duration::from_millis(50)) {
+            Ok(stream) => queued.push(stream),
+            Err(_) => return (addr.to_string(), listener, queued),
+        }
+    }
+    panic!("the backlog never filled up");
+}
+
+#[test]
+fn connect_timeouts_fail_over() {
+    let (stuck, _listener, _queued) = unresponsive_backend();
+    let live = backend("live", Duration::ZERO);
+    let proxy =
+        Proxy::new(&[&stuck, &live]).timeouts(Duration::from_millis(100), Duration::from_secs(5));
+    let addr = common::spawn_async(Server::new(Router::new().get("/", proxy)), 0);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(body(&response).starts_with("live "), "{response}");
+}
+
+#[test]
+fn slow_backends_time_out() {
+    let slow = backend("slow", Duration::from_secs(5));
+    let proxy = Proxy::new(&[&slow]).timeouts(Duration::from_secs(1), Duration::from_millis(100));
+    let addr = common::spawn_async(Server::new(Router::new().get("/", proxy)), 0);
+
+    let response = common::get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+}
+
+#[test]
+fn uploads_too_big_to_buffer_are_refused() {
+    let a = backend("a", Duration::ZERO);
+    let router = Router::new().route(Method::Post, "/*", Proxy::new(&[&a]));
+    let addr = common::spawn_async(Server::new(router), 0);
+
+    // Only the head: the body is never read.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\n\
+              Content-Type: multipart/form-data; boundary=sep\r\n\
+              Content-Length: 2000000\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+}
@@ -0,0 +1,137 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use smol::{Async, Timer};
+use web_server::{
+    sse::{self, Event, EventSender},
+    Router, Server,
+};
+
+mod common;
+
+fn router(senders: mpsc::Sender<EventSender>) -> Router {
+    Router::new()
+        .get("/countdown", |_| async {
+            sse::stream(|events| async move {
+                for n in (1..=3).rev() {
+                    events.send(Event::new(n.to_string())).await.unwrap();
+                    Timer::after(Duration::from_millis(100)).await;
+                }
+                let _ = events.send(Event::new("liftoff").with_event("done")).await;
+            })
+        })
+        .get("/forever", move |_| {
+            let senders = senders.clone();
+            async move {
+                sse::stream_with_keep_alive(Duration::from_millis(50), move |events| async move {
+                    // Someone else produces the events, as a broadcaster would.
+                    senders.send(events).unwrap();
+                    smol::future::pending::<()>().await
+                })
+            }
+        })
+}
+
+// Reads the response head, then one line at a time as the events arrive.
+fn open(addr: std::net::SocketAddr, path: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        reader.read_line(&mut head).unwrap();
+    }
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert!(
+        head.contains("Content-Type: text/event-stream\r\n"),
+        "{head}"
+    );
+    assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+    assert!(!head.contains("Content-Length"), "{head}");
+    reader
+}
+
+#[test]
+fn events_are_sent_as_they_are_produced() {
+    let (senders, _) = mpsc::channel();
+    let addr = common::spawn_threaded(Server::new(router(senders)), 1);
+    let mut reader = open(addr, "/countdown");
+
+    // The first event arrives well before the last one is produced.
+    let start = Instant::now();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "9\r\n");
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(
+        rest,
+        "data: 3\n\n\r\n9\r\ndata: 2\n\n\r\n9\r\ndata: 1\n\n\r\n\
+         1b\r\nevent: done\ndata: liftoff\n\n\r\n0\r\n\r\n"
+    );
+}
+
+#[test]
+fn disconnects_are_noticed() {
+    let (senders, events) = mpsc::channel();
+    // One worker, which the stream occupies until the client leaves.
+    let addr = common::spawn_threaded(Server::new(router(senders)), 1);
+    let mut reader = open(addr, "/forever");
+
+    let sender = events.recv_timeout(Duration::from_secs(5)).unwrap();
+    sender.send_blocking(Event::new("hello")).unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "d\r\ndata: hello\n");
+
+    // Nothing to send for a while, so the server keeps the connection alive on its own.
+    // What is left of the event, then the chunk holding the comment.
+    let mut keep_alive = vec![0; 3];
+    reader.read_exact(&mut keep_alive).unwrap();
+    let mut keep_alive = vec![0; 3 + 14 + 2];
+    reader.read_exact(&mut keep_alive).unwrap();
+    assert!(keep_alive == b"e\r\n: keep-alive\n\n\r\n", "{keep_alive:?}");
+
+    drop(reader);
+    let start = Instant::now();
+    while !sender.is_disconnected() {
+        assert!(start.elapsed() < Duration::from_secs(5), "still connected");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(sender.send_blocking(Event::new("anyone?")).is_err());
+
+    // The worker is free again.
+    let response = common::get(addr, "/countdown");
+    assert!(response.ends_with("0\r\n\r\n"), "{response}");
+}
+
+#[test]
+fn http2_streams_events_too() {
+    let (senders, _) = mpsc::channel();
+    let addr = common::spawn_async(Server::new(router(senders)), 1);
+
+    let responses = smol::block_on(async {
+        let mut stream = Async::<TcpStream>::connect(addr).await.unwrap();
+        common::h2_exchange(&mut stream, &[("GET", "/countdown", b"")]).await
+    });
+    assert_eq!(responses[0].status, "200");
+    assert!(!responses[0]
+        .headers
+        .iter()
+        .any(|(name, _)| name == "content-length"));
+    assert_eq!(
+        responses[0].body,
+        b"data: 3\n\ndata: 2\n\ndata: 1\n\nevent: done\ndata: liftoff\n\n"
+    );
+}
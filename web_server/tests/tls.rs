@@ -0,0 +1,267 @@
+use std::{
+    env, fs, io,
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use futures_rustls::{
+    rustls::{crypto::ring, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use rustls_pki_types::{CertificateDer, ServerName};
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    Async,
+};
+use web_server::{limits::ConnectionLimit, tls, Response, Router, Server, VirtualHosts};
+
+mod common;
+
+// A fresh self-signed certificate, for "localhost" unless noted, written out as PEM files so that the server
+// loads it the same way it would load a real one.
+struct SelfSigned {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    cert_der: CertificateDer<'static>,
+}
+
+impl SelfSigned {
+    fn generate(name: &str) -> SelfSigned {
+        SelfSigned::for_host(name, "localhost")
+    }
+
+    fn for_host(name: &str, host: &str) -> SelfSigned {
+        let certified = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+
+        let dir = env::temp_dir().join(format!("web_server-tls-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+        SelfSigned {
+            cert_path,
+            key_path,
+            cert_der: certified.cert.der().clone(),
+        }
+    }
+}
+
+impl Drop for SelfSigned {
+    fn drop(&mut self) {
+        if let Some(dir) = self.cert_path.parent() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn connector(cert: &CertificateDer<'static>, alpn: &[&[u8]]) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    TlsConnector::from(Arc::new(config))
+}
+
+fn https_get(addr: SocketAddr, cert: &CertificateDer<'static>, path: &str) -> String {
+    // Offering HTTP/1.1 alone, as clients without HTTP/2 support would.
+    let connector = connector(cert, &[b"http/1.1"]);
+
+    smol::block_on(async {
+        let stream = Async::<TcpStream>::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    })
+}
+
+// Just the handshake, for connections the server might turn away.
+fn handshake(addr: SocketAddr, cert: &CertificateDer<'static>) -> io::Result<()> {
+    let connector = connector(cert, &[b"http/1.1"]);
+    smol::block_on(async {
+        let stream = Async::<TcpStream>::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        connector.connect(server_name, stream).await.map(drop)
+    })
+}
+
+fn router() -> Router {
+    Router::new().get("/", |_| async { Response::html(200, "<h1>Secure</h1>") })
+}
+
+#[test]
+fn threaded_backend_serves_https() {
+    let cert = SelfSigned::generate("threaded");
+    let acceptor = tls::load_acceptor(&cert.cert_path, &cert.key_path).unwrap();
+    let addr = common::spawn_threaded(Server::new(router()).with_tls(acceptor), 2);
+
+    let response = https_get(addr, &cert.cert_der, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("<h1>Secure</h1>"), "{response}");
+}
+
+#[test]
+fn async_backend_serves_https() {
+    let cert = SelfSigned::generate("async");
+    let acceptor = tls::load_acceptor(&cert.cert_path, &cert.key_path).unwrap();
+    let addr = common::spawn_async(Server::new(router()).with_tls(acceptor), 1);
+
+    let response = https_get(addr, &cert.cert_der, "/");
+    assert!(response.ends_with("<h1>Secure</h1>"), "{response}");
+}
+
+#[test]
+fn alpn_negotiates_http2() {
+    let cert = SelfSigned::generate("h2");
+    let acceptor = tls::load_acceptor(&cert.cert_path, &cert.key_path).unwrap();
+    let addr = common::spawn_async(Server::new(router()).with_tls(acceptor), 1);
+    let connector = connector(&cert.cert_der, &[b"h2", b"http/1.1"]);
+
+    let responses = smol::block_on(async {
+        let stream = Async::<TcpStream>::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        common::h2_exchange(&mut stream, &[("GET", "/", b"")]).await
+    });
+    assert_eq!(responses[0].status, "200");
+    assert_eq!(responses[0].body, b"<h1>Secure</h1>");
+}
+
+#[test]
+fn virtual_hosts_get_their_own_certificates() {
+    let default = SelfSigned::generate("sni-default");
+    let example = SelfSigned::for_host("sni-example", "example.test");
+    let acceptor = tls::Certificates::load(&default.cert_path, &default.key_path)
+        .and_then(|certs| certs.with_host(&["example.test"], &example.cert_path, &example.key_path))
+        .and_then(|certs| certs.acceptor())
+        .unwrap();
+    let hosts = VirtualHosts::new()
+        .host(&["example.test"], |_| async {
+            Response::new(200).with_body("example")
+        })
+        .default_host(router());
+    let addr = common::spawn_async(Server::new(hosts).with_tls(acceptor), 1);
+
+    // Only trusting the example.test certificate, so the handshake fails unless SNI picked it.
+    let connector = connector(&example.cert_der, &[b"http/1.1"]);
+    let get = |host: &str| {
+        smol::block_on(async {
+            let stream = Async::<TcpStream>::connect(addr).await.unwrap();
+            let server_name = ServerName::try_from("example.test").unwrap();
+            let mut stream = connector.connect(server_name, stream).await.unwrap();
+
+            let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        })
+    };
+    let response = get("example.test");
+    assert!(response.ends_with("\r\n\r\nexample"), "{response}");
+    let response = get("localhost");
+    assert!(
+        response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+        "{response}"
+    );
+
+    // Clients asking for other names get the default certificate and site.
+    let response = https_get(addr, &default.cert_der, "/");
+    assert!(response.ends_with("<h1>Secure</h1>"), "{response}");
+}
+
+#[test]
+fn turned_away_clients_are_hung_up_on() {
+    let cert = SelfSigned::generate("limited");
+    let acceptor = tls::load_acceptor(&cert.cert_path, &cert.key_path).unwrap();
+    let server = Server::new(router())
+        .with_tls(acceptor)
+        .with_connection_limit(ConnectionLimit::new(10, 1));
+    let addr = common::spawn_threaded(server, 2);
+
+    // Holds on to its permit while the server waits for a handshake that never comes.
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let err = handshake(addr, &cert.cert_der).unwrap_err();
+    // A plain "HTTP/1.1 429" would have been a corrupt TLS record instead.
+    assert!(
+        matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+        ),
+        "{err:?}"
+    );
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let response = https_get(addr, &cert.cert_der, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn busy_workers_hang_up_on_tls_clients() {
+    let cert = SelfSigned::generate("busy");
+    let acceptor = tls::load_acceptor(&cert.cert_path, &cert.key_path).unwrap();
+    let server = Server::new(router())
+        .with_tls(acceptor)
+        .with_queue_timeout(Duration::from_millis(100));
+    // One worker, with room for one more connection in the queue.
+    let addr = common::spawn_threaded(server, 1);
+
+    let idle = [(); 2].map(|_| {
+        let stream = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream
+    });
+    let err = handshake(addr, &cert.cert_der).unwrap_err();
+    assert!(
+        matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+        ),
+        "{err:?}"
+    );
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let response = https_get(addr, &cert.cert_der, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn plain_http_is_redirected() {
+    let addr = common::spawn_async(Server::new(tls::HttpsRedirect::new(8443)), 0);
+
+    let response = common::send(
+        addr,
+        "GET /page?x=1 HTTP/1.1\r\nHost: localhost:7878\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 301"), "{response}");
+    assert!(
+        response.contains("Location: https://localhost:8443/page?x=1\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn missing_certificate_is_an_error() {
+    let missing = env::temp_dir().join("web_server-tls-does-not-exist.pem");
+    assert!(tls::load_acceptor(&missing, &missing).is_err());
+}
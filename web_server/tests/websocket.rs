@@ -0,0 +1,211 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use smol::Timer;
+use web_server::{
+    websocket::{self, Message},
+    Response, Router, Server,
+};
+
+mod common;
+
+fn router() -> Router {
+    Router::new()
+        .get("/echo", |request| async move {
+            websocket::upgrade(&request, |mut socket| async move {
+                while let Ok(Some(message)) = socket.recv().await {
+                    if let Message::Text(_) | Message::Binary(_) = message {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        // Pushes updates from a separate task without waiting for the client to say anything.
+        .get("/ticks", |request| async move {
+            websocket::upgrade(&request, |mut socket| async move {
+                let sender = socket.sender();
+                let ticker = smol::spawn(async move {
+                    for tick in 0..3 {
+                        Timer::after(Duration::from_millis(10)).await;
+                        sender.text(format!("tick {tick}")).await.unwrap();
+                    }
+                });
+                while let Ok(Some(_)) = socket.recv().await {}
+                ticker.cancel().await;
+            })
+        })
+        .get("/", |_| async { Response::html(200, "not a websocket") })
+}
+
+// A bare-bones client: just enough of RFC 6455 to drive the server.
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr, path: &str) -> Client {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+
+        // Read the head byte by byte so that no frame data is swallowed.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{head}"
+        );
+
+        Client { stream }
+    }
+
+    fn send(&mut self, fin: bool, opcode: u8, payload: &[u8]) {
+        let mask = [0xA1, 0xB2, 0xC3, 0xD4];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(ind, byte)| byte ^ mask[ind % 4]),
+        );
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    // Returns (opcode, payload) of the next server frame.
+    fn recv(&mut self) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "server frames are never fragmented");
+        assert_eq!(head[1] & 0x80, 0, "server frames must not be masked");
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        self.stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn assert_closed(&mut self) {
+        let mut rest = Vec::new();
+        self.stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty(), "{rest:?}");
+    }
+}
+
+#[test]
+fn echoes_text_and_binary_on_both_backends() {
+    for addr in [
+        common::spawn_threaded(Server::new(router()), 1),
+        common::spawn_async(Server::new(router()), 0),
+    ] {
+        let mut client = Client::connect(addr, "/echo");
+
+        client.send(true, 0x1, b"hello");
+        assert_eq!(client.recv(), (0x1, b"hello".to_vec()));
+
+        let big = vec![7; 1000];
+        client.send(true, 0x2, &big);
+        assert_eq!(client.recv(), (0x2, big));
+    }
+}
+
+#[test]
+fn reassembles_fragmented_messages() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+    let mut client = Client::connect(addr, "/echo");
+
+    client.send(false, 0x1, b"frag");
+    // Control frames may arrive in the middle of a fragmented message.
+    client.send(true, 0x9, b"still there?");
+    client.send(false, 0x0, b"men");
+    client.send(true, 0x0, b"ted");
+
+    assert_eq!(client.recv(), (0xA, b"still there?".to_vec()));
+    assert_eq!(client.recv(), (0x1, b"fragmented".to_vec()));
+}
+
+#[test]
+fn completes_the_close_handshake() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+    let mut client = Client::connect(addr, "/echo");
+
+    let mut payload = 1000u16.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"bye");
+    client.send(true, 0x8, &payload);
+
+    let (opcode, payload) = client.recv();
+    assert_eq!(opcode, 0x8);
+    assert_eq!(payload[..2], 1000u16.to_be_bytes());
+    client.assert_closed();
+}
+
+#[test]
+fn closes_on_protocol_errors() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+    let mut client = Client::connect(addr, "/echo");
+
+    // Continuation without a message to continue.
+    client.send(true, 0x0, b"oops");
+
+    let (opcode, payload) = client.recv();
+    assert_eq!(opcode, 0x8);
+    assert_eq!(payload[..2], 1002u16.to_be_bytes());
+    client.assert_closed();
+}
+
+#[test]
+fn handlers_can_push_updates() {
+    let addr = common::spawn_async(Server::new(router()), 1);
+    let mut client = Client::connect(addr, "/ticks");
+
+    for tick in 0..3 {
+        assert_eq!(client.recv(), (0x1, format!("tick {tick}").into_bytes()));
+    }
+
+    client.send(true, 0x8, &1000u16.to_be_bytes());
+    assert_eq!(client.recv().0, 0x8);
+}
+
+#[test]
+fn plain_requests_to_a_websocket_route_are_rejected() {
+    let addr = common::spawn_async(Server::new(router()), 0);
+
+    let response = common::get(addr, "/echo");
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+}